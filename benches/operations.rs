@@ -0,0 +1,199 @@
+//! Benchmarks of basic database operations driven through the public API.
+//!
+//! Run with `cargo bench`. Arguments filter benchmarks by name, e.g.
+//! `cargo bench -- point_get`. Save a baseline with `cargo bench -- --save-baseline before`
+//! and compare a change against it with `cargo bench -- --baseline before`.
+
+use std::hint::black_box;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use bonsai_db::{Database, Options};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, SamplingMode, Throughput};
+
+const KEYS: u64 = 20_000;
+const PAGE_SIZES: &[u32] = &[4 << 10, 16 << 10];
+const VALUE_SIZES: &[usize] = &[16, 256];
+
+struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> Self {
+        let file_name = format!("bonsai-db-bench-{}-{name}.db", std::process::id());
+        let path = std::env::temp_dir().join(file_name);
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+struct Params {
+    page_size: u32,
+    value_size: usize,
+}
+
+impl Params {
+    fn create_database(&self, path: &TempPath) -> Database {
+        let options = Options {
+            page_size: self.page_size,
+            ..Options::default()
+        };
+        Database::create(&path.0, options).unwrap()
+    }
+
+    fn populated_database(&self, path: &TempPath) -> Database {
+        let db = self.create_database(path);
+        let mut tx = db.begin_write();
+        for i in 0..KEYS {
+            tx.put(&key(i), &self.value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        db
+    }
+
+    fn value(&self, i: u64) -> Vec<u8> {
+        let mut value = i.to_be_bytes().repeat(self.value_size.div_ceil(8));
+        value.truncate(self.value_size);
+        value
+    }
+}
+
+fn key(i: u64) -> Vec<u8> {
+    format!("key{i:010}").into_bytes()
+}
+
+/// Returns pseudo-random permutation of `0..KEYS`.
+fn shuffled_keys() -> Vec<u64> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut keys = (0..KEYS).collect::<Vec<_>>();
+    for i in (1..keys.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        keys.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+    keys
+}
+
+fn sequential_insert(params: &Params) -> Duration {
+    let path = TempPath::new("sequential-insert");
+    let db = params.create_database(&path);
+    let start = Instant::now();
+    let mut tx = db.begin_write();
+    for i in 0..KEYS {
+        tx.put(&key(i), &params.value(i)).unwrap();
+    }
+    tx.commit().unwrap();
+    start.elapsed()
+}
+
+fn random_insert(params: &Params) -> Duration {
+    let path = TempPath::new("random-insert");
+    let db = params.create_database(&path);
+    let keys = shuffled_keys();
+    let start = Instant::now();
+    let mut tx = db.begin_write();
+    for &i in &keys {
+        tx.put(&key(i), &params.value(i)).unwrap();
+    }
+    tx.commit().unwrap();
+    start.elapsed()
+}
+
+fn point_get(params: &Params) -> Duration {
+    let path = TempPath::new("point-get");
+    let db = params.populated_database(&path);
+    let keys = shuffled_keys();
+    let start = Instant::now();
+    let tx = db.begin_read();
+    for &i in &keys {
+        black_box(tx.get(&key(i)).unwrap());
+    }
+    start.elapsed()
+}
+
+fn full_scan(params: &Params) -> Duration {
+    let path = TempPath::new("full-scan");
+    let db = params.populated_database(&path);
+    let start = Instant::now();
+    let tx = db.begin_read();
+    let mut cursor = tx.cursor().unwrap();
+    let mut entries = 0;
+    while cursor.is_valid() {
+        black_box(cursor.key_value());
+        entries += 1;
+        cursor.next_entry().unwrap();
+    }
+    assert_eq!(entries, KEYS);
+    start.elapsed()
+}
+
+fn range_scan(params: &Params) -> Duration {
+    let path = TempPath::new("range-scan");
+    let db = params.populated_database(&path);
+    let start = Instant::now();
+    let tx = db.begin_read();
+    let mut cursor = tx.cursor().unwrap();
+    for lower in (0..KEYS).step_by(10) {
+        cursor.seek_bounded(&key(lower), &key(lower + 9)).unwrap();
+        while cursor.is_valid() {
+            black_box(cursor.key_value());
+            cursor.next_entry().unwrap();
+        }
+    }
+    start.elapsed()
+}
+
+fn delete(params: &Params) -> Duration {
+    let path = TempPath::new("delete");
+    let db = params.populated_database(&path);
+    let keys = shuffled_keys();
+    let start = Instant::now();
+    let mut tx = db.begin_write();
+    for &i in &keys {
+        tx.remove(&key(i)).unwrap();
+    }
+    tx.commit().unwrap();
+    start.elapsed()
+}
+
+type Benchmark = fn(&Params) -> Duration;
+
+const BENCHMARKS: &[(&str, Benchmark)] = &[
+    ("sequential_insert", sequential_insert),
+    ("random_insert", random_insert),
+    ("point_get", point_get),
+    ("full_scan", full_scan),
+    ("range_scan", range_scan),
+    ("delete", delete),
+];
+
+/// Runs every benchmark for each combination of page and value size. Each run sets
+/// up its own database and times only the operations, measuring `KEYS` of them.
+fn operations(c: &mut Criterion) {
+    let mut group = c.benchmark_group("operations");
+    group.throughput(Throughput::Elements(KEYS));
+    // Every iteration takes milliseconds, so they are not scaled up per sample
+    group.sampling_mode(SamplingMode::Flat);
+    group.sample_size(10);
+    for (name, benchmark) in BENCHMARKS {
+        for &page_size in PAGE_SIZES {
+            for &value_size in VALUE_SIZES {
+                let id = BenchmarkId::new(*name, format!("page_size={page_size}/value_size={value_size}"));
+                let params = Params { page_size, value_size };
+                group.bench_with_input(id, &params, |b, params| {
+                    b.iter_custom(|iters| (0..iters).map(|_| benchmark(params)).sum())
+                });
+            }
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, operations);
+criterion_main!(benches);
@@ -0,0 +1,223 @@
+//! Async façade running blocking database calls on a dedicated thread pool, so that
+//! reads and commits don't stall threads of async runtime. Returned futures don't
+//! depend on any runtime and can be awaited from any executor.
+//!
+//! Transactions can't be held across `.await` points, so writes take a closure
+//! which runs the whole transaction on a pool thread.
+
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use anyhow::{anyhow, Result};
+
+use crate::tx::{ReadTransaction, WriteTransaction};
+use crate::Database;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Clone)]
+pub struct AsyncDatabase {
+    database: Database,
+    pool: Arc<ThreadPool>,
+}
+
+impl AsyncDatabase {
+    /// Wraps database running its blocking calls on `threads` dedicated threads.
+    /// Values below 1 are raised to 1.
+    pub fn new(database: Database, threads: usize) -> Self {
+        Self {
+            database,
+            pool: Arc::new(ThreadPool::new(threads.max(1))),
+        }
+    }
+
+    /// Returns wrapped database for calls which don't block, such as `metrics`.
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = key.to_vec();
+        self.read(move |tx| tx.get(&key)).await
+    }
+
+    /// Runs `f` in a read transaction of latest committed state.
+    pub async fn read<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&ReadTransaction) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.spawn(move |database| f(&database.begin_read())).await
+    }
+
+    /// Runs `f` in a write transaction which is committed if `f` succeeds,
+    /// same as `Database::write`.
+    pub async fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut WriteTransaction) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.spawn(move |database| database.write(f)).await
+    }
+
+    /// Runs any blocking call on the pool. Panic of `f` is returned as an error.
+    pub fn spawn<T, F>(&self, f: F) -> BlockingTask<T>
+    where
+        F: FnOnce(&Database) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(TaskState {
+            result: None,
+            waker: None,
+        }));
+        let database = self.database.clone();
+        let task_state = state.clone();
+        self.pool.execute(Box::new(move || {
+            let result = catch_unwind(AssertUnwindSafe(|| f(&database)))
+                .unwrap_or_else(|_| Err(anyhow!("blocking database call panicked")));
+            let mut state = task_state.lock().expect("task state lock");
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }));
+        BlockingTask { state }
+    }
+}
+
+struct TaskState<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// Future resolved once blocking call running on the pool returns.
+pub struct BlockingTask<T> {
+    state: Arc<Mutex<TaskState<T>>>,
+}
+
+impl<T> Future for BlockingTask<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().expect("task state lock");
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Pool of detached threads. Dropping it closes job channel without waiting,
+/// so that last `AsyncDatabase` clone can be dropped on executor thread. Threads
+/// finish queued jobs and exit on their own.
+struct ThreadPool {
+    sender: mpsc::Sender<Job>,
+}
+
+impl ThreadPool {
+    fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            std::thread::spawn(move || loop {
+                // Lock is released before job runs, so other threads keep taking jobs
+                let job = receiver.lock().expect("job receiver lock").recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => return,
+                }
+            });
+        }
+        Self { sender }
+    }
+
+    fn execute(&self, job: Job) {
+        self.sender.send(job).expect("pool threads are running");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{collect, key, open_database, value, TempPath};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn runs_concurrent_gets_and_write() {
+        let path = TempPath::new();
+        let db = AsyncDatabase::new(open_database(&path), 4);
+        db.write(|tx| {
+            for i in 0..100 {
+                tx.put(&key(i), &value(i))?;
+            }
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        let readers = (0..8)
+            .map(|reader| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    // Key 0 is removed concurrently
+                    for i in (1 + reader..100).step_by(8) {
+                        assert_eq!(db.get(&key(i)).await.unwrap(), Some(value(i)));
+                    }
+                    // Written concurrently, so either state may be seen
+                    let written = db.get(&key(100)).await.unwrap();
+                    assert!(written.is_none() || written == Some(value(100)));
+                })
+            })
+            .collect::<Vec<_>>();
+        let removed = db
+            .write(|tx| {
+                tx.put(&key(100), &value(100))?;
+                tx.remove_returning(&key(0))
+            })
+            .await
+            .unwrap();
+        assert_eq!(removed, Some(value(0)));
+        for reader in readers {
+            reader.await.unwrap();
+        }
+
+        assert_eq!(db.get(&key(0)).await.unwrap(), None);
+        assert_eq!(db.get(&key(100)).await.unwrap(), Some(value(100)));
+        let keys = db.read(|tx| Ok(collect(&mut tx.cursor()?).len())).await.unwrap();
+        assert_eq!(keys, 100);
+    }
+
+    #[tokio::test]
+    async fn returns_panic_of_blocking_call_as_error() {
+        let path = TempPath::new();
+        let db = AsyncDatabase::new(open_database(&path), 1);
+        let err = db
+            .write(|tx| -> Result<()> {
+                tx.put(&key(1), &value(1))?;
+                panic!("failed in transaction")
+            })
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("panicked"), "{err:#}");
+        // Transaction was rolled back and pool thread keeps serving calls
+        assert_eq!(db.get(&key(1)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn drops_without_waiting_for_running_calls() {
+        let path = TempPath::new();
+        let db = AsyncDatabase::new(open_database(&path), 1);
+        let (release, released) = mpsc::channel::<()>();
+        let task = db.spawn(move |_| Ok(released.recv()?));
+        // Would block forever if drop joined pool threads
+        drop(db);
+        release.send(()).unwrap();
+        task.await.unwrap();
+    }
+}
@@ -1,11 +1,86 @@
-use anyhow::Result;
+use std::fmt;
 
-use crate::node::{InternalNodes, Node, NodeId, NodeReader};
+use anyhow::{anyhow, Result};
+
+use crate::node::{BranchInternalNode, InternalNodes, Node, NodeId, NodeReader};
+use crate::tx::WriteTransaction;
+
+/// Side of the target key `Cursor::seek_biased` lands on when key does not match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekBias {
+    /// First entry with key not less than target, same as `Cursor::seek`.
+    GreaterOrEqual,
+    /// Last entry with key not greater than target.
+    LessOrEqual,
+    /// First entry with key greater than target.
+    Greater,
+    /// Last entry with key less than target.
+    Less,
+}
 
 pub struct Cursor<'a> {
     pub root_node_id: NodeId,
     pub stack: Vec<CursorNodeRef<'a>>,
-    pub node_reader: &'a dyn NodeReader,
+    source: CursorSource<'a>,
+    /// Entries expiring at or before this unix time in milliseconds are skipped.
+    now: u64,
+    /// Inclusive bound set by `seek_bounded`, past which cursor is invalid.
+    upper_bound: Option<Vec<u8>>,
+}
+
+enum CursorSource<'a> {
+    Reader(&'a dyn NodeReader),
+    Writer(&'a mut WriteTransaction),
+}
+
+impl<'a> CursorSource<'a> {
+    fn read_node(&self, node_id: NodeId) -> Result<Node<'a>> {
+        match self {
+            Self::Reader(node_reader) => {
+                let node_reader: &'a dyn NodeReader = *node_reader;
+                node_reader.read_node(node_id)
+            }
+            // Writer cursor mutates the transaction, so it cannot borrow
+            // dirty nodes and keeps detached copies instead.
+            Self::Writer(tx) => Ok(Node::ReadOnly(tx.read_node_detached(node_id)?)),
+        }
+    }
+
+    fn node_reader(&self) -> &dyn NodeReader {
+        match self {
+            Self::Reader(node_reader) => *node_reader,
+            Self::Writer(tx) => &**tx,
+        }
+    }
+
+    /// Returns number of entries in subtree of `child`. Counts of dirty children
+    /// are stale until they are written, so their subtrees are counted instead.
+    fn subtree_count(&self, child: &BranchInternalNode) -> Result<u64> {
+        if let NodeId::Address(_) = child.node_id {
+            return Ok(child.count);
+        }
+        let node = self.read_node(child.node_id)?;
+        match node.as_ref() {
+            InternalNodes::Branch(nodes) => nodes.iter().map(|node| self.subtree_count(node)).sum(),
+            InternalNodes::Leaf(nodes) => Ok(nodes.len() as u64),
+        }
+    }
+}
+
+impl fmt::Debug for Cursor<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stack = self
+            .stack
+            .iter()
+            .map(|element| (element.node_id, element.index))
+            .collect::<Vec<_>>();
+        let key = self.is_valid().then(|| String::from_utf8_lossy(self.key()));
+        f.debug_struct("Cursor")
+            .field("depth", &self.stack.len())
+            .field("stack", &stack)
+            .field("key", &key)
+            .finish()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -17,21 +92,41 @@ pub struct CursorNodeRef<'a> {
 
 impl<'a> Cursor<'a> {
     pub fn new(root_node_id: NodeId, node_reader: &'a dyn NodeReader) -> Result<Self> {
-        let root = node_reader.read_node(root_node_id)?;
+        Self::with_source(root_node_id, CursorSource::Reader(node_reader))
+    }
+
+    pub fn new_writer(tx: &'a mut WriteTransaction) -> Result<Self> {
+        Self::with_source(tx.root_node_id(), CursorSource::Writer(tx))
+    }
+
+    fn with_source(root_node_id: NodeId, source: CursorSource<'a>) -> Result<Self> {
+        let root = source.read_node(root_node_id)?;
+        let now = source.node_reader().now();
         let mut cursor = Self {
             root_node_id,
-            node_reader,
+            source,
             stack: vec![CursorNodeRef {
                 node: root,
                 index: 0,
                 node_id: root_node_id,
             }],
+            now,
+            upper_bound: None,
         };
-        cursor.move_to_first_leaf()?;
+        cursor.first()?;
         Ok(cursor)
     }
 
     pub fn is_valid(&self) -> bool {
+        self.is_positioned()
+            && self
+                .upper_bound
+                .as_deref()
+                .is_none_or(|upper_bound| self.key() <= upper_bound)
+    }
+
+    /// Returns whether cursor points to an entry regardless of upper bound.
+    fn is_positioned(&self) -> bool {
         let Some(element) = self.stack.last() else {
             return false;
         };
@@ -39,7 +134,7 @@ impl<'a> Cursor<'a> {
     }
 
     pub fn key(&self) -> &[u8] {
-        assert!(self.is_valid(), "cursor must be valid");
+        assert!(self.is_positioned(), "cursor must be valid");
         let element = self.stack.last().expect("cursor stack top");
         let InternalNodes::Leaf(ref nodes) = element.node.as_ref() else {
             panic!("cursor must point to a leaf node");
@@ -47,8 +142,26 @@ impl<'a> Cursor<'a> {
         nodes[element.index].key.as_ref()
     }
 
+    /// Returns unix time in milliseconds when current entry expires.
+    pub fn expires_at(&self) -> Option<u64> {
+        assert!(self.is_positioned(), "cursor must be valid");
+        let element = self.stack.last().expect("cursor stack top");
+        let InternalNodes::Leaf(ref nodes) = element.node.as_ref() else {
+            panic!("cursor must point to a leaf node");
+        };
+        nodes[element.index].expires_at
+    }
+
+    pub(crate) fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expires_at().is_some_and(|expires_at| expires_at <= self.now)
+    }
+
     pub fn value(&self) -> &[u8] {
-        assert!(self.is_valid(), "cursor must be valid");
+        assert!(self.is_positioned(), "cursor must be valid");
         let element = self.stack.last().expect("cursor stack top");
         let InternalNodes::Leaf(ref nodes) = element.node.as_ref() else {
             panic!("cursor must point to a leaf node");
@@ -56,18 +169,37 @@ impl<'a> Cursor<'a> {
         nodes[element.index].value.as_ref()
     }
 
+    /// Returns key and value of current entry.
+    pub fn key_value(&self) -> (&[u8], &[u8]) {
+        assert!(self.is_positioned(), "cursor must be valid");
+        let element = self.stack.last().expect("cursor stack top");
+        let InternalNodes::Leaf(ref nodes) = element.node.as_ref() else {
+            panic!("cursor must point to a leaf node");
+        };
+        let node = &nodes[element.index];
+        (node.key.as_ref(), node.value.as_ref())
+    }
+
+    /// Consumes cursor returning its current entry if cursor is valid.
+    pub fn into_key_value(self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.is_valid()
+            .then(|| (self.key().to_vec(), self.value().to_vec()))
+    }
+
     pub fn first(&mut self) -> Result<()> {
+        self.upper_bound = None;
         self.stack.drain(1..);
         let element = self.stack.last_mut().expect("cursor stack top");
         element.index = 0;
         self.move_to_first_leaf()?;
         if self.stack.last().expect("cursor stack top").node.is_empty() {
-            self.next_entry()?;
+            self.step_next()?;
         }
-        Ok(())
+        self.skip_expired_forward()
     }
 
     pub fn last(&mut self) -> Result<()> {
+        self.upper_bound = None;
         self.stack.drain(1..);
         let element = self.stack.last_mut().expect("cursor stack top");
         element.index = if element.node.is_empty() {
@@ -77,12 +209,84 @@ impl<'a> Cursor<'a> {
         };
         self.move_to_last_leaf()?;
         if self.stack.last().expect("cursor stack top").node.is_empty() {
-            self.next_entry()?;
+            self.step_prev()?;
         }
-        Ok(())
+        self.skip_expired_backward()
     }
 
+    /// Moves to previous entry. Returns `false` and invalidates cursor
+    /// if cursor was at the first entry.
     pub fn prev_entry(&mut self) -> Result<bool> {
+        loop {
+            if !self.step_prev()? {
+                return Ok(false);
+            }
+            if !self.is_expired() {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Moves cursor to the entry at position `n` in key order, counting from zero,
+    /// descending by subtree counts of branch nodes. Expired entries which were not
+    /// evicted yet are counted same as by `ReadTransaction::select`, and if entry at `n`
+    /// is expired, cursor moves to the next live one. Returns `false` and leaves cursor
+    /// invalid if there is no such entry.
+    pub fn seek_to_nth(&mut self, mut n: u64) -> Result<bool> {
+        self.upper_bound = None;
+        self.stack.drain(1..);
+        loop {
+            let element = self.stack.last_mut().expect("cursor stack top");
+            let InternalNodes::Branch(nodes) = element.node.as_ref() else {
+                // Index past the last leaf entry leaves cursor invalid
+                element.index = n.min(element.node.len() as u64) as usize;
+                break;
+            };
+            // Position past all entries descends to the last leaf
+            let mut index = nodes.len().saturating_sub(1);
+            for (i, node) in nodes.iter().enumerate() {
+                let count = self.source.subtree_count(node)?;
+                if n < count {
+                    index = i;
+                    break;
+                }
+                if i < index {
+                    n -= count;
+                }
+            }
+            element.index = index;
+            let node_id = element.node.child(index)?.node_id;
+            let node = self.source.read_node(node_id)?;
+            self.stack.push(CursorNodeRef {
+                node,
+                index: 0,
+                node_id,
+            });
+        }
+        self.skip_expired_forward()?;
+        Ok(self.is_valid())
+    }
+
+    pub fn next_entry(&mut self) -> Result<()> {
+        self.step_next()?;
+        self.skip_expired_forward()
+    }
+
+    fn skip_expired_forward(&mut self) -> Result<()> {
+        while self.is_valid() && self.is_expired() {
+            self.step_next()?;
+        }
+        Ok(())
+    }
+
+    fn skip_expired_backward(&mut self) -> Result<()> {
+        if self.is_valid() && self.is_expired() {
+            self.prev_entry()?;
+        }
+        Ok(())
+    }
+
+    fn step_prev(&mut self) -> Result<bool> {
         let element = self.stack.last_mut().expect("cursor stack top");
         if element.index > 0 {
             assert!(element.node.is_leaf(), "cursor must point to a leaf node");
@@ -102,6 +306,10 @@ impl<'a> Cursor<'a> {
             }
 
             let Some(last_index) = last_index else {
+                // Moving before the first entry invalidates cursor
+                // the same way as moving past the last one.
+                let node_ref = self.stack.last_mut().expect("cursor stack top");
+                node_ref.index = node_ref.node.len();
                 return Ok(false);
             };
 
@@ -115,7 +323,7 @@ impl<'a> Cursor<'a> {
         Ok(true)
     }
 
-    pub fn next_entry(&mut self) -> Result<()> {
+    fn step_next(&mut self) -> Result<()> {
         let element = self.stack.last_mut().expect("cursor stack top");
         if !element.node.is_empty() && element.index < element.node.len() - 1 {
             assert!(element.node.is_leaf(), "cursor must point to a leaf node");
@@ -153,19 +361,63 @@ impl<'a> Cursor<'a> {
     }
 
     pub fn seek(&mut self, key: &[u8]) -> Result<()> {
+        self.upper_bound = None;
+        self.seek_entry(key)
+    }
+
+    /// Moves cursor to the entry closest to `key` on the side chosen by `bias`.
+    /// Cursor is invalid if there is no such entry.
+    pub fn seek_biased(&mut self, key: &[u8], bias: SeekBias) -> Result<()> {
+        self.seek(key)?;
+        let matches = self.is_valid() && self.key() == key;
+        match bias {
+            SeekBias::GreaterOrEqual => {}
+            SeekBias::Greater => {
+                if matches {
+                    self.next_entry()?;
+                }
+            }
+            SeekBias::LessOrEqual | SeekBias::Less => {
+                if matches && bias == SeekBias::LessOrEqual {
+                    return Ok(());
+                }
+                if self.is_valid() {
+                    self.prev_entry()?;
+                } else {
+                    // All keys are less than target
+                    self.last()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves cursor to the first entry within `[lower, upper]`. Cursor stays valid
+    /// only while it points to an entry not greater than `upper`, so current entry
+    /// can be read right away without checking it against the bound.
+    /// Bound is cleared by any other seek.
+    pub fn seek_bounded(&mut self, lower: &[u8], upper: &[u8]) -> Result<()> {
+        if lower > upper {
+            return Err(anyhow!("lower bound is greater than upper bound"));
+        }
+        self.upper_bound = Some(upper.to_vec());
+        self.seek_entry(lower)
+    }
+
+    fn seek_entry(&mut self, key: &[u8]) -> Result<()> {
         self.seek_internal(key)?;
         let element = self.stack.last_mut().expect("cursor stack top");
 
         // TODO: Emit database corrupted?
         assert!(element.node.is_leaf(), "seek must stop at leaf node");
         if element.node.is_empty() {
-            self.next_entry()?;
+            self.step_next()?;
         } else if element.index > element.node.len() - 1 {
             element.index = element.node.len() - 1;
-            self.next_entry()?;
+            self.step_next()?;
         }
 
-        Ok(())
+        self.skip_expired_forward()
     }
 
     pub fn seek_internal(&mut self, key: &[u8]) -> Result<()> {
@@ -184,7 +436,7 @@ impl<'a> Cursor<'a> {
 
                     element.index = index;
                     let node_id = nodes[element.index].node_id;
-                    let node = self.node_reader.read_node(node_id)?;
+                    let node = self.source.read_node(node_id)?;
                     self.stack.push(CursorNodeRef {
                         node,
                         index: 0,
@@ -209,6 +461,70 @@ impl<'a> Cursor<'a> {
         Ok(())
     }
 
+    /// Removes current entry and moves cursor to the next entry.
+    /// Only cursors created by `WriteTransaction::cursor_mut` can delete.
+    pub fn delete(&mut self) -> Result<()> {
+        let key = self.writable_key()?;
+        self.writer().remove(&key)?;
+        // Removal may have replaced nodes on the path with dirty copies,
+        // so restart from the new root and land on the next entry.
+        self.reload(&key)
+    }
+
+    /// Overwrites value of the current entry keeping cursor on it and its expiry.
+    /// Any split required by the new value size is deferred to commit.
+    pub fn put(&mut self, value: &[u8]) -> Result<()> {
+        let key = self.writable_key()?;
+        self.writer().upsert_with(&key, |_| Some(value.to_vec()))?;
+        self.reload(&key)
+    }
+
+    fn writable_key(&self) -> Result<Vec<u8>> {
+        if !matches!(self.source, CursorSource::Writer(_)) {
+            return Err(anyhow!("cannot modify entry using read-only cursor"));
+        }
+        if !self.is_valid() {
+            return Err(anyhow!("cannot modify entry using invalid cursor"));
+        }
+        Ok(self.key().to_vec())
+    }
+
+    fn writer(&mut self) -> &mut WriteTransaction {
+        let CursorSource::Writer(tx) = &mut self.source else {
+            unreachable!("writer cursor");
+        };
+        tx
+    }
+
+    fn reload(&mut self, key: &[u8]) -> Result<()> {
+        self.root_node_id = self.writer().root_node_id();
+        self.reset_stack()?;
+        self.seek_entry(key)
+    }
+
+    /// Moves cursor back to the first entry as if it was created anew, re-reading root
+    /// and clearing bound set by `seek_bounded`. Stack keeps its capacity, so a single
+    /// cursor can be reused for many seeks.
+    pub fn reset(&mut self) -> Result<()> {
+        if let CursorSource::Writer(tx) = &self.source {
+            self.root_node_id = tx.root_node_id();
+        }
+        self.now = self.source.node_reader().now();
+        self.reset_stack()?;
+        self.first()
+    }
+
+    fn reset_stack(&mut self) -> Result<()> {
+        let root = self.source.read_node(self.root_node_id)?;
+        self.stack.clear();
+        self.stack.push(CursorNodeRef {
+            node: root,
+            index: 0,
+            node_id: self.root_node_id,
+        });
+        Ok(())
+    }
+
     fn move_to_first_leaf(&mut self) -> Result<()> {
         loop {
             let element = self.stack.last().expect("cursor stack last element");
@@ -216,7 +532,11 @@ impl<'a> Cursor<'a> {
                 break;
             };
             let node_address = nodes[element.index].node_id;
-            let node = self.node_reader.read_node(node_address)?;
+            self.read_ahead(node_address)?;
+            let node = self.source.read_node(node_address)?;
+            if node.is_leaf() {
+                self.prefetch_siblings();
+            }
             self.stack.push(CursorNodeRef {
                 node,
                 index: 0,
@@ -226,6 +546,57 @@ impl<'a> Cursor<'a> {
         Ok(())
     }
 
+    /// Reads pages following node being descended to in one go, caching
+    /// next leaves of the current branch which are stored right after it.
+    fn read_ahead(&self, node_id: NodeId) -> Result<()> {
+        let node_reader = self.source.node_reader();
+        let pages = node_reader.read_ahead_leaves();
+        let NodeId::Address(page_address) = node_id else {
+            return Ok(());
+        };
+        if pages == 0 {
+            return Ok(());
+        }
+        let element = self.stack.last().expect("cursor stack last element");
+        let InternalNodes::Branch(ref nodes) = element.node.as_ref() else {
+            return Ok(());
+        };
+        let leaf_addresses = nodes
+            .iter()
+            .skip(element.index + 1)
+            .take(pages)
+            .filter_map(|node| match node.node_id {
+                NodeId::Address(page_address) => Some(page_address),
+                NodeId::Id(_) => None,
+            })
+            .collect::<Vec<_>>();
+        node_reader.read_ahead(page_address, &leaf_addresses)
+    }
+
+    /// Reads ahead next leaves of the parent branch, once per window of
+    /// `prefetch_leaves` leaves, so that a forward scan reads them in batches.
+    fn prefetch_siblings(&self) {
+        let node_reader = self.source.node_reader();
+        let window = node_reader.prefetch_leaves();
+        let element = self.stack.last().expect("cursor stack last element");
+        if window == 0 || !element.index.is_multiple_of(window) {
+            return;
+        }
+        let InternalNodes::Branch(ref nodes) = element.node.as_ref() else {
+            return;
+        };
+        let page_addresses = nodes
+            .iter()
+            .skip(element.index + 1)
+            .take(window)
+            .filter_map(|node| match node.node_id {
+                NodeId::Address(page_address) => Some(page_address),
+                NodeId::Id(_) => None,
+            })
+            .collect::<Vec<_>>();
+        node_reader.prefetch(&page_addresses)
+    }
+
     fn move_to_last_leaf(&mut self) -> Result<()> {
         loop {
             let element = self.stack.last().expect("cursor stack last element");
@@ -233,7 +604,7 @@ impl<'a> Cursor<'a> {
                 break;
             };
             let node_address = nodes[element.index].node_id;
-            let node = self.node_reader.read_node(node_address)?;
+            let node = self.source.read_node(node_address)?;
             let index = if node.is_empty() { 0 } else { node.len() - 1 };
             self.stack.push(CursorNodeRef {
                 node,
@@ -244,3 +615,658 @@ impl<'a> Cursor<'a> {
         Ok(())
     }
 }
+
+/// Iterator over owned entries starting at cursor position.
+/// Errors are yielded as items, after which iteration ends.
+pub struct Iter<'a> {
+    cursor: Option<Result<Cursor<'a>>>,
+}
+
+impl<'a> Iter<'a> {
+    pub fn new(cursor: Result<Cursor<'a>>) -> Self {
+        Self {
+            cursor: Some(cursor),
+        }
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut cursor = match self.cursor.take()? {
+            Ok(cursor) => cursor,
+            Err(e) => return Some(Err(e)),
+        };
+        if !cursor.is_valid() {
+            return None;
+        }
+        let (key, value) = cursor.key_value();
+        let entry = (key.to_vec(), value.to_vec());
+        // Failed move is reported by the following call
+        self.cursor = Some(cursor.next_entry().map(|_| cursor));
+        Some(Ok(entry))
+    }
+}
+
+impl<'a> IntoIterator for Cursor<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(Ok(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    use super::*;
+    use crate::node::LeafInternalNode;
+    use crate::test_utils::{self, collect, key, open_database, value, Rng, TempPath};
+    use crate::{Database, Options};
+
+    /// Serves nodes built by test, which may include empty leaves.
+    #[derive(Default)]
+    struct TestNodes {
+        nodes: HashMap<u64, InternalNodes>,
+    }
+
+    impl TestNodes {
+        fn leaf(&mut self, keys: &[&str]) -> NodeId {
+            let nodes = keys
+                .iter()
+                .map(|key| LeafInternalNode {
+                    key: key.as_bytes().to_vec(),
+                    value: Vec::new(),
+                    expires_at: None,
+                })
+                .collect();
+            self.insert(InternalNodes::Leaf(nodes))
+        }
+
+        fn branch(&mut self, children: &[(&str, NodeId)]) -> NodeId {
+            let nodes = children
+                .iter()
+                .map(|(key, node_id)| BranchInternalNode {
+                    key: key.as_bytes().to_vec(),
+                    node_id: *node_id,
+                    count: 0,
+                })
+                .collect();
+            self.insert(InternalNodes::Branch(nodes))
+        }
+
+        fn insert(&mut self, node: InternalNodes) -> NodeId {
+            let node_id = self.nodes.len() as u64 + 1;
+            self.nodes.insert(node_id, node);
+            NodeId::Id(node_id)
+        }
+    }
+
+    impl NodeReader for TestNodes {
+        fn read_node(&self, node_id: NodeId) -> Result<Node<'_>> {
+            let NodeId::Id(node_id) = node_id else {
+                panic!("unexpected node {node_id:?}");
+            };
+            Ok(Node::Dirty(&self.nodes[&node_id]))
+        }
+    }
+
+    /// Walks cursor backward from the last entry checking that `prev_entry`
+    /// returns `false` once, right after the first entry.
+    fn collect_backward(cursor: &mut Cursor) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        cursor.last().unwrap();
+        if !cursor.is_valid() {
+            return keys;
+        }
+        loop {
+            keys.push(cursor.key().to_vec());
+            if !cursor.prev_entry().unwrap() {
+                break;
+            }
+            assert!(cursor.is_valid());
+        }
+        assert!(!cursor.is_valid());
+        keys
+    }
+
+    #[test]
+    fn moves_backward_over_trees_of_different_shapes() {
+        for (entries, max_keys) in [(0, None), (1, None), (10, None), (1000, None), (200, Some(4))] {
+            let path = TempPath::new();
+            let db = Database::create(&path, test_utils::options().max_keys_per_node(max_keys)).unwrap();
+            let mut tx = db.begin_write();
+            for i in 1000..1000 + entries {
+                tx.put(&key(i), &value(i)).unwrap();
+            }
+            tx.commit().unwrap();
+
+            let tx = db.begin_read();
+            let mut cursor = tx.cursor().unwrap();
+            let expected = (1000..1000 + entries).rev().map(key).collect::<Vec<_>>();
+            assert_eq!(collect_backward(&mut cursor), expected, "{entries} entries");
+        }
+    }
+
+    #[test]
+    fn moves_backward_over_empty_leaves() {
+        let mut nodes = TestNodes::default();
+        let leaves = [
+            nodes.leaf(&[]),
+            nodes.leaf(&["a", "b"]),
+            nodes.leaf(&[]),
+            nodes.leaf(&[]),
+            nodes.leaf(&["c"]),
+            nodes.leaf(&[]),
+        ];
+        let empty_subtree = [nodes.leaf(&[]), nodes.leaf(&[])];
+        let empty_branch = nodes.branch(&[("d", empty_subtree[0]), ("e", empty_subtree[1])]);
+        let last_leaf = nodes.leaf(&["f", "g"]);
+        let trailing_leaf = nodes.leaf(&[]);
+        let left = nodes.branch(&[
+            ("", leaves[0]),
+            ("a", leaves[1]),
+            ("b0", leaves[2]),
+            ("b1", leaves[3]),
+            ("c", leaves[4]),
+            ("c0", leaves[5]),
+        ]);
+        let right = nodes.branch(&[("f", last_leaf), ("h", trailing_leaf)]);
+        let root = nodes.branch(&[("", left), ("d", empty_branch), ("f", right)]);
+
+        let mut cursor = Cursor::new(root, &nodes).unwrap();
+        let keys = collect_backward(&mut cursor);
+        assert_eq!(keys, [b"g", b"f", b"c", b"b", b"a"].map(|key| key.to_vec()));
+
+        // Forward iteration visits the same keys
+        cursor.first().unwrap();
+        let mut forward = collect(&mut cursor).into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        forward.reverse();
+        assert_eq!(forward, keys);
+    }
+
+    #[test]
+    fn moves_backward_over_only_empty_leaves() {
+        let mut nodes = TestNodes::default();
+        let children = [nodes.leaf(&[]), nodes.leaf(&[]), nodes.leaf(&[])];
+        let root = nodes.branch(&[("", children[0]), ("a", children[1]), ("b", children[2])]);
+        let mut cursor = Cursor::new(root, &nodes).unwrap();
+        assert!(collect_backward(&mut cursor).is_empty());
+        cursor.first().unwrap();
+        assert!(!cursor.is_valid());
+    }
+
+    #[test]
+    fn consumes_cursor_into_current_entry() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 100..200 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor().unwrap();
+        cursor.seek(&key(150)).unwrap();
+        assert_eq!(cursor.into_key_value(), Some((key(150), value(150))));
+
+        let mut cursor = tx.cursor().unwrap();
+        cursor.seek(&key(999)).unwrap();
+        assert_eq!(cursor.into_key_value(), None);
+    }
+
+    #[test]
+    fn returns_key_and_value_together() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 100..300 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor().unwrap();
+        let mut count = 0;
+        while cursor.is_valid() {
+            assert_eq!(cursor.key_value(), (cursor.key(), cursor.value()));
+            count += 1;
+            cursor.next_entry().unwrap();
+        }
+        assert_eq!(count, 200);
+    }
+
+    #[test]
+    fn seeks_with_bias() {
+        let path = TempPath::new();
+        let options = test_utils::options().max_keys_per_node(Some(4));
+        let db = Database::create(&path, options).unwrap();
+        let mut tx = db.begin_write();
+        // Keys 1000, 1010, .. 1990 spread over many leaves
+        for i in (1000..2000).step_by(10) {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor().unwrap();
+        let mut check = |target: &[u8], bias, expected: Option<usize>| {
+            cursor.seek_biased(target, bias).unwrap();
+            let actual = cursor.is_valid().then(|| cursor.key().to_vec());
+            assert_eq!(
+                actual,
+                expected.map(key),
+                "{:?} {bias:?}",
+                String::from_utf8_lossy(target)
+            );
+        };
+        use SeekBias::*;
+        for (target, [greater_or_equal, less_or_equal, greater, less]) in [
+            // Exact matches, including first and last keys
+            (key(1500), [Some(1500), Some(1500), Some(1510), Some(1490)]),
+            (key(1000), [Some(1000), Some(1000), Some(1010), None]),
+            (key(1990), [Some(1990), Some(1990), None, Some(1980)]),
+            // Between keys
+            (key(1505), [Some(1510), Some(1500), Some(1510), Some(1500)]),
+            (key(1001), [Some(1010), Some(1000), Some(1010), Some(1000)]),
+            // Outside of key range
+            (b"a".to_vec(), [Some(1000), None, Some(1000), None]),
+            (b"z".to_vec(), [None, Some(1990), None, Some(1990)]),
+        ] {
+            check(&target, GreaterOrEqual, greater_or_equal);
+            check(&target, LessOrEqual, less_or_equal);
+            check(&target, Greater, greater);
+            check(&target, Less, less);
+        }
+
+        // Empty tree
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor().unwrap();
+        for bias in [GreaterOrEqual, LessOrEqual, Greater, Less] {
+            cursor.seek_biased(b"key", bias).unwrap();
+            assert!(!cursor.is_valid());
+        }
+    }
+
+    #[test]
+    fn seeks_to_nth_entry() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 100..1000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor().unwrap();
+        assert!(cursor.seek_to_nth(0).unwrap());
+        assert_eq!(cursor.key(), key(100));
+        assert!(cursor.seek_to_nth(450).unwrap());
+        assert_eq!(cursor.key(), key(550));
+        assert!(cursor.seek_to_nth(899).unwrap());
+        assert_eq!(cursor.key(), key(999));
+        assert!(!cursor.seek_to_nth(900).unwrap());
+        assert!(!cursor.is_valid());
+        drop(cursor);
+        drop(tx);
+
+        // Counts of dirty nodes are stale until commit
+        let mut tx = db.begin_write();
+        for i in 1000..1300 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.remove(&key(100)).unwrap();
+        let mut keys = (101..1300).map(key).collect::<Vec<_>>();
+        keys.sort();
+        let mut cursor = tx.cursor_mut().unwrap();
+        for n in (0..keys.len()).step_by(7) {
+            assert!(cursor.seek_to_nth(n as u64).unwrap());
+            assert_eq!(cursor.key(), keys[n]);
+        }
+        assert!(!cursor.seek_to_nth(keys.len() as u64).unwrap());
+    }
+
+    #[test]
+    fn seeks_to_nth_entry_same_as_select() {
+        let path = TempPath::new();
+        let (clock, time) = test_utils::manual_clock(1_000);
+        let db = Database::open_or_create(&path, test_utils::options().clock(clock)).unwrap();
+        let mut tx = db.begin_write();
+        for i in 100..600usize {
+            if i.is_multiple_of(3) {
+                tx.put_with_ttl(&key(i), &value(i), std::time::Duration::from_millis(10)).unwrap();
+            } else {
+                tx.put(&key(i), &value(i)).unwrap();
+            }
+        }
+        tx.commit().unwrap();
+        time.fetch_add(10, std::sync::atomic::Ordering::Relaxed);
+
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor().unwrap();
+        for n in 0..=500 {
+            let selected = tx.select(n).unwrap();
+            assert_eq!(cursor.seek_to_nth(n).unwrap(), selected.is_some());
+            if let Some(selected) = selected {
+                assert_eq!(cursor.key(), selected);
+                // Expired entry at n is skipped
+                let i = 100 + n as usize;
+                assert_eq!(selected, key(if i.is_multiple_of(3) { i + 1 } else { i }));
+            }
+        }
+    }
+
+    #[test]
+    fn reuses_reset_cursor_for_seeks() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in (1000..3000).step_by(3) {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor().unwrap();
+        let mut rng = Rng::new(3);
+        for _ in 0..1000 {
+            let target = key(900 + rng.below(2200) as usize);
+            cursor.reset().unwrap();
+            assert_eq!(cursor.key(), key(1000));
+            cursor.seek(&target).unwrap();
+            let mut fresh = tx.cursor().unwrap();
+            fresh.seek(&target).unwrap();
+            assert_eq!(cursor.is_valid(), fresh.is_valid());
+            if fresh.is_valid() {
+                assert_eq!((cursor.key(), cursor.value()), (fresh.key(), fresh.value()));
+            }
+        }
+
+        // Bound is cleared
+        cursor.seek_bounded(&key(2000), &key(2010)).unwrap();
+        cursor.reset().unwrap();
+        assert_eq!(collect(&mut cursor).len(), 667);
+
+        // Writer cursor picks up root replaced by its changes
+        let mut tx = db.begin_write();
+        let mut cursor = tx.cursor_mut().unwrap();
+        while cursor.is_valid() && cursor.key() < &key(2000)[..] {
+            cursor.delete().unwrap();
+        }
+        cursor.reset().unwrap();
+        assert_eq!(cursor.key(), key(2002));
+        drop(cursor);
+        assert_eq!(tx.cursor_mut().unwrap().key(), key(2002));
+    }
+
+    #[test]
+    fn seeks_within_bounds() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in (100..1000).step_by(2) {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor().unwrap();
+        cursor.seek_bounded(&key(301), &key(321)).unwrap();
+        assert_eq!(cursor.key(), key(302));
+        assert_eq!(cursor.value(), value(302));
+        assert_eq!(
+            collect(&mut cursor),
+            (302..=320).step_by(2).map(|i| (key(i), value(i))).collect::<Vec<_>>()
+        );
+
+        // Equal bounds match single key or nothing
+        cursor.seek_bounded(&key(500), &key(500)).unwrap();
+        assert!(cursor.is_valid());
+        assert_eq!(cursor.key(), key(500));
+        cursor.next_entry().unwrap();
+        assert!(!cursor.is_valid());
+        cursor.seek_bounded(&key(501), &key(501)).unwrap();
+        assert!(!cursor.is_valid());
+
+        // Bounds past the last key
+        cursor.seek_bounded(&key(999), b"zzz").unwrap();
+        assert!(!cursor.is_valid());
+
+        // Reversed bounds
+        assert!(cursor.seek_bounded(&key(600), &key(500)).is_err());
+
+        // Other seeks clear bound
+        cursor.seek_bounded(&key(500), &key(500)).unwrap();
+        cursor.seek(&key(700)).unwrap();
+        assert_eq!(collect(&mut cursor).len(), 150);
+    }
+
+    #[test]
+    fn prefetches_leaves_during_scan() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 10_000..20_000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        drop(db);
+
+        let scan = |prefetch_leaves| {
+            let options = Options {
+                prefetch_leaves,
+                ..test_utils::options()
+            };
+            let db = Database::open(&path, options).unwrap();
+            let tx = db.begin_read();
+            assert_eq!(collect(&mut tx.cursor().unwrap()).len(), 10_000);
+            db.metrics()
+        };
+        let metrics = scan(0);
+        assert_eq!(metrics.prefetches, 0);
+        let prefetched_metrics = scan(8);
+        assert!(prefetched_metrics.prefetches > 0);
+        assert_eq!(
+            prefetched_metrics.cache_misses + prefetched_metrics.prefetched_nodes,
+            metrics.cache_misses
+        );
+        assert!(prefetched_metrics.cache_misses * 4 < metrics.cache_misses);
+    }
+
+    #[test]
+    fn scans_past_prefetch_errors() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..2000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        let root = db.internal.begin_read_with_meta().1.root_node;
+        let root = db.internal.node_manager.read_node(root).unwrap();
+        let InternalNodes::Branch(ref children) = root.1 else {
+            panic!("root is not a branch");
+        };
+        let NodeId::Address(first_leaf) = children[0].node_id else {
+            panic!("child is not written");
+        };
+        let first_leaf_len = db.internal.node_manager.read_node(first_leaf).unwrap().1.len();
+        let NodeId::Address(corrupted_leaf) = children[2].node_id else {
+            panic!("child is not written");
+        };
+        drop(db);
+
+        // Clobber node flags of the third leaf, which first leaf prefetches
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(corrupted_leaf)).unwrap();
+        file.write_all(&7u16.to_be_bytes()).unwrap();
+        drop(file);
+
+        let options = Options {
+            prefetch_leaves: 8,
+            ..test_utils::options()
+        };
+        let db = Database::open(&path, options).unwrap();
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor().unwrap();
+        for _ in 0..first_leaf_len {
+            assert!(cursor.is_valid());
+            cursor.next_entry().unwrap();
+        }
+        // Corrupted leaf is reported once scan reaches it
+        let err = loop {
+            if let Err(err) = cursor.next_entry() {
+                break err;
+            }
+        };
+        assert!(matches!(err.downcast_ref::<crate::Error>(), Some(crate::Error::Corruption(_))), "{err:#}");
+    }
+
+    #[test]
+    fn reads_ahead_sequentially_written_leaves() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 10_000..20_000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        drop(db);
+
+        let scan = |read_ahead_leaves| {
+            let options = test_utils::options().read_ahead_leaves(read_ahead_leaves);
+            let db = Database::open(&path, options).unwrap();
+            let tx = db.begin_read();
+            let expected = (10_000..20_000).map(|i| (key(i), value(i))).collect::<Vec<_>>();
+            assert_eq!(collect(&mut tx.cursor().unwrap()), expected);
+            db.metrics()
+        };
+        let metrics = scan(0);
+        let read_ahead_metrics = scan(8);
+        assert!(
+            read_ahead_metrics.node_reads * 4 < metrics.node_reads,
+            "{} reads with read ahead, {} without",
+            read_ahead_metrics.node_reads,
+            metrics.node_reads,
+        );
+    }
+
+    #[test]
+    fn formats_cursor_position() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 100..1000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor().unwrap();
+        cursor.seek(&key(500)).unwrap();
+        let depth = cursor.stack.len();
+        assert!(depth > 1);
+        let output = format!("{cursor:?}");
+        assert!(output.contains(&format!("depth: {depth}")), "{output}");
+        assert!(output.contains("key: Some(\"key500\")"), "{output}");
+
+        cursor.last().unwrap();
+        cursor.next_entry().unwrap();
+        let output = format!("{cursor:?}");
+        assert!(output.contains("key: None"), "{output}");
+    }
+
+    #[test]
+    fn deletes_entries_during_iteration() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..10 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        {
+            let mut cursor = tx.cursor_mut().unwrap();
+            cursor.first().unwrap();
+            while cursor.is_valid() {
+                let i: usize = String::from_utf8_lossy(&cursor.key()[3..]).parse().unwrap();
+                if i.is_multiple_of(2) {
+                    cursor.delete().unwrap();
+                } else {
+                    cursor.next_entry().unwrap();
+                }
+            }
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let entries = collect(&mut tx.cursor().unwrap());
+        let expected = (0..10)
+            .filter(|i| i % 2 == 1)
+            .map(|i| (key(i), value(i)))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn read_only_cursor_cannot_delete() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        tx.put(&key(0), &value(0)).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor().unwrap();
+        assert!(cursor.is_valid());
+        assert!(cursor.delete().is_err());
+
+        let tx = db.begin_write();
+        let mut cursor = tx.cursor().unwrap();
+        assert!(cursor.delete().is_err());
+        assert!(cursor.put(b"value").is_err());
+    }
+
+    #[test]
+    fn puts_value_of_current_entry() {
+        let path = TempPath::new();
+        {
+            let db = open_database(&path);
+            let mut tx = db.begin_write();
+            for i in 0..10 {
+                tx.put(&key(i), &value(i)).unwrap();
+            }
+            tx.commit().unwrap();
+
+            let large_value = vec![b'x'; 3000];
+            let mut tx = db.begin_write();
+            {
+                let mut cursor = tx.cursor_mut().unwrap();
+                cursor.seek(&key(5)).unwrap();
+                cursor.put(&large_value).unwrap();
+                assert_eq!(cursor.key(), key(5));
+                assert_eq!(cursor.value(), large_value);
+                cursor.next_entry().unwrap();
+                assert_eq!(cursor.key(), key(6));
+            }
+            tx.commit().unwrap();
+        }
+
+        let db = open_database(&path);
+        let tx = db.begin_read();
+        assert_eq!(tx.get(&key(5)).unwrap(), Some(vec![b'x'; 3000]));
+        assert_eq!(tx.get(&key(4)).unwrap(), Some(value(4)));
+        assert_eq!(tx.get(&key(6)).unwrap(), Some(value(6)));
+    }
+}
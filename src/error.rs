@@ -0,0 +1,57 @@
+use std::fmt;
+
+use crate::node::{MAX_KEY_SIZE, MAX_VALUE_SIZE};
+
+/// Errors callers may want to handle distinctly. They are returned wrapped
+/// into `anyhow::Error` and can be inspected with `downcast_ref::<Error>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    BadMagic,
+    /// File of given size is too small to contain meta nodes.
+    NotADatabase(u64),
+    UnsupportedVersion(u16),
+    /// File is shorter than end address recorded in meta node.
+    Truncated { expected: u64, actual: u64 },
+    WouldBlock,
+    /// Node address is not a multiple of page size.
+    Misaligned { address: u64, page_size: u32 },
+    Corruption(String),
+    KeyTooLarge(usize),
+    ValueTooLarge(usize),
+    /// Commit would take transaction id reserved as `MAX_TRANSACTION_ID` sentinel.
+    TransactionIdsExhausted,
+    /// Write transaction needs to write pages of database opened read-only.
+    ReadOnly,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a bonsai-db database file"),
+            Self::NotADatabase(size) => {
+                write!(f, "file of {size} bytes is too small to be a bonsai-db database")
+            }
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported database format version: {version}")
+            }
+            Self::Truncated { expected, actual } => {
+                write!(f, "database file is truncated to {actual} bytes, expected at least {expected}")
+            }
+            Self::WouldBlock => write!(f, "operation would block"),
+            Self::Misaligned { address, page_size } => {
+                write!(f, "node address {address} is not aligned to page size {page_size}")
+            }
+            Self::Corruption(description) => write!(f, "database is corrupted: {description}"),
+            Self::KeyTooLarge(size) => {
+                write!(f, "key of {size} bytes exceeds limit of {MAX_KEY_SIZE} bytes")
+            }
+            Self::ValueTooLarge(size) => {
+                write!(f, "value of {size} bytes exceeds limit of {MAX_VALUE_SIZE} bytes")
+            }
+            Self::TransactionIdsExhausted => write!(f, "no transaction ids left to commit"),
+            Self::ReadOnly => write!(f, "database is opened read-only"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
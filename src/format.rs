@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::io::{Read, Write};
 
 #[inline]
@@ -22,17 +22,6 @@ pub(crate) fn read_u16<R: Read>(reader: &mut R) -> Result<u16> {
     Ok(u16::from_be_bytes(buf))
 }
 
-#[inline]
-pub(crate) fn read_vec_u64<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u64>> {
-    let mut buf = [0u8; 8];
-    let mut res = Vec::new();
-    for _ in 0..len {
-        reader.read_exact(&mut buf)?;
-        res.push(u64::from_be_bytes(buf));
-    }
-    Ok(res)
-}
-
 #[inline]
 pub(crate) fn write_u64<W: Write>(w: &mut W, value: u64) -> Result<()> {
     let bytes = u64::to_be_bytes(value);
@@ -54,11 +43,236 @@ pub(crate) fn write_u16<W: Write>(w: &mut W, value: u16) -> Result<()> {
     Ok(())
 }
 
+/// Max number of bytes of LEB128 encoded u64.
+const MAX_VARINT_SIZE: usize = 10;
+
+#[inline]
+pub(crate) fn varint_size(mut value: u64) -> usize {
+    let mut size = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+    size
+}
+
+#[inline]
+pub(crate) fn read_varint<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut value = 0u64;
+    let mut buf = [0u8; 1];
+    for i in 0..MAX_VARINT_SIZE {
+        reader.read_exact(&mut buf)?;
+        let byte = buf[0];
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("varint is too long"))
+}
+
 #[inline]
-pub(crate) fn write_slice_u64<W: Write>(w: &mut W, values: &[u64]) -> Result<()> {
-    for value in values {
-        let bytes = u64::to_be_bytes(*value);
-        w.write_all(&bytes)?;
+pub(crate) fn write_varint<W: Write>(w: &mut W, mut value: u64) -> Result<()> {
+    let mut buf = [0u8; MAX_VARINT_SIZE];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
     }
+    w.write_all(&buf[..len])?;
     Ok(())
 }
+
+/// Encodes integer so that encoded values compare byte-wise in numeric order.
+pub fn encode_u64(value: u64) -> [u8; 8] {
+    value.to_be_bytes()
+}
+
+pub fn decode_u64(bytes: [u8; 8]) -> u64 {
+    u64::from_be_bytes(bytes)
+}
+
+/// Encodes integer so that encoded values compare byte-wise in numeric order.
+/// Sign bit is flipped, so negative values sort before positive ones.
+pub fn encode_i64(value: i64) -> [u8; 8] {
+    ((value as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+pub fn decode_i64(bytes: [u8; 8]) -> i64 {
+    (u64::from_be_bytes(bytes) ^ (1 << 63)) as i64
+}
+
+/// Builds composite keys which compare byte-wise in the same order as their fields
+/// compare one after another, so they can be scanned in logical order by cursor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyBuilder {
+    key: Vec<u8>,
+}
+
+impl KeyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u64(mut self, value: u64) -> Self {
+        self.key.extend_from_slice(&encode_u64(value));
+        self
+    }
+
+    pub fn i64(mut self, value: i64) -> Self {
+        self.key.extend_from_slice(&encode_i64(value));
+        self
+    }
+
+    pub fn u32(mut self, value: u32) -> Self {
+        self.key.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn u16(mut self, value: u16) -> Self {
+        self.key.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    /// Appends NUL terminated string, so it sorts before its extensions.
+    pub fn str(self, value: &str) -> Self {
+        self.bytes(value.as_bytes())
+    }
+
+    /// Appends variable width bytes terminated with `0x00 0x00`. NUL bytes within
+    /// value are escaped as `0x00 0xff`, which sorts after the terminator whatever
+    /// the following field starts with.
+    pub fn bytes(mut self, value: &[u8]) -> Self {
+        for &byte in value {
+            self.key.push(byte);
+            if byte == 0 {
+                self.key.push(0xff);
+            }
+        }
+        self.key.extend_from_slice(&[0, 0]);
+        self
+    }
+
+    /// Appends bytes as is. Field must have the same width in all keys
+    /// it is compared with, otherwise following fields are misaligned.
+    pub fn fixed(mut self, value: &[u8]) -> Self {
+        self.key.extend_from_slice(value);
+        self
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        self.key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn round_trips_varints() {
+        let values = [
+            (0, vec![0x00]),
+            (1, vec![0x01]),
+            (127, vec![0x7f]),
+            (128, vec![0x80, 0x01]),
+            (300, vec![0xac, 0x02]),
+            (16_383, vec![0xff, 0x7f]),
+            (16_384, vec![0x80, 0x80, 0x01]),
+            (u32::MAX as u64, vec![0xff, 0xff, 0xff, 0xff, 0x0f]),
+        ];
+        for (value, encoded) in values {
+            let mut writer = Cursor::new(Vec::new());
+            write_varint(&mut writer, value).unwrap();
+            let bytes = writer.into_inner();
+            assert_eq!(bytes, encoded, "encoding of {value}");
+            assert_eq!(varint_size(value), bytes.len(), "size of {value}");
+            let decoded = read_varint(&mut Cursor::new(bytes)).unwrap();
+            assert_eq!(decoded, value);
+        }
+    }
+
+    #[test]
+    fn round_trips_u64_max_varint() {
+        let mut writer = Cursor::new(Vec::new());
+        write_varint(&mut writer, u64::MAX).unwrap();
+        let bytes = writer.into_inner();
+        assert_eq!(bytes.len(), MAX_VARINT_SIZE);
+        assert_eq!(varint_size(u64::MAX), MAX_VARINT_SIZE);
+        assert_eq!(read_varint(&mut Cursor::new(bytes)).unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn builds_keys_sorting_in_field_order() {
+        let mut fields = Vec::new();
+        for table in ["", "a", "a\0", "a\0b", "a\x01", "ab", "b"] {
+            for id in [0, 1, 255, 256, u64::MAX] {
+                for name in ["", "x", "xy", "y"] {
+                    fields.push((table, id, *b"AB", name));
+                    fields.push((table, id, *b"BA", name));
+                }
+            }
+        }
+        let build = |(table, id, code, name): &(&str, u64, [u8; 2], &str)| {
+            KeyBuilder::new().str(table).u64(*id).fixed(code).str(name).build()
+        };
+        let mut keys = fields.iter().map(|fields| (build(fields), *fields)).collect::<Vec<_>>();
+        keys.sort();
+        fields.sort();
+        let sorted_fields = keys.into_iter().map(|(_, fields)| fields).collect::<Vec<_>>();
+        assert_eq!(sorted_fields, fields);
+    }
+
+    #[test]
+    fn escapes_nul_bytes_of_variable_width_fields() {
+        let key = KeyBuilder::new().bytes(b"a\0b").u16(1).u32(2);
+        assert_eq!(key.as_bytes(), b"a\0\xffb\0\0\0\x01\0\0\0\x02");
+        // Field ending with NUL must not be mistaken for a shorter one followed by another field
+        assert_ne!(KeyBuilder::new().str("a\0").build(), KeyBuilder::new().str("a").str("").build());
+    }
+
+    #[test]
+    fn encodes_integers_in_numeric_order() {
+        let mut rng = crate::test_utils::Rng::new(7);
+        let mut values = vec![i64::MIN, i64::MIN + 1, -256, -255, -1, 0, 1, 255, 256, i64::MAX - 1, i64::MAX];
+        values.extend((0..200).map(|_| rng.next() as i64));
+        values.extend((0..200).map(|_| rng.below(2000) as i64 - 1000));
+        // Shuffle so that sorting does not start from numeric order
+        for i in (1..values.len()).rev() {
+            values.swap(i, rng.below(i as u64 + 1) as usize);
+        }
+
+        let mut encoded = values.iter().map(|value| encode_i64(*value)).collect::<Vec<_>>();
+        encoded.sort();
+        let decoded = encoded.into_iter().map(decode_i64).collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(decoded, values);
+        assert!(encode_i64(-1) < encode_i64(1));
+
+        let mut values = values.into_iter().map(|value| value as u64).collect::<Vec<_>>();
+        let mut encoded = values.iter().map(|value| encode_u64(*value)).collect::<Vec<_>>();
+        encoded.sort();
+        let decoded = encoded.into_iter().map(decode_u64).collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(decoded, values);
+    }
+
+    #[test]
+    fn rejects_truncated_and_too_long_varints() {
+        assert!(read_varint(&mut Cursor::new(vec![0x80])).is_err());
+        assert!(read_varint(&mut Cursor::new(vec![0x80; 11])).is_err());
+    }
+}
@@ -1,13 +1,12 @@
 use crate::{
-    format::{read_vec_u64, write_slice_u64},
-    node::{Address, NodeHeader, FREELIST_NODE},
+    format::{read_varint, varint_size, write_varint},
+    node::{read_pages, write_pages, Address, ByteAddr, NodeHeader, PageIndex, FREELIST_NODE},
     tx::TransactionId,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::io::{Read, Write};
-use std::mem::size_of;
 
 #[derive(Debug, Default)]
 pub struct FreeList {
@@ -18,60 +17,92 @@ pub struct FreeList {
 }
 
 impl FreeList {
-    pub fn allocate(&mut self, required_pages: u64, page_size: u64) -> Option<Address> {
-        let mut previous_page_address: Option<Address> = None;
-        let mut initial_page_address = *self.free.first()?;
+    /// Takes `required_pages` adjacent free pages and returns index of the first one.
+    pub fn allocate(&mut self, required_pages: u64, page_size: u64) -> Option<PageIndex> {
+        let mut previous_page: Option<PageIndex> = None;
+        let mut initial_page = ByteAddr::new(*self.free.first()?).page_index(page_size);
 
         for page_address in self.free.difference(&self.pending_allocated) {
-            assert_eq!(page_address % page_size, 0, "invalid page address");
-            let restart_initial_page = previous_page_address
-                .map(|previous_page_address| page_address - previous_page_address != page_size)
+            let page = ByteAddr::new(*page_address).page_index(page_size);
+            let restart_initial_page = previous_page
+                .map(|previous_page| page != previous_page.offset(1))
                 .unwrap_or(false);
 
             if restart_initial_page {
-                initial_page_address = *page_address;
+                initial_page = page;
             }
 
-            if ((page_address - initial_page_address) / page_size) + 1 == required_pages as Address {
+            if page.pages_since(initial_page) + 1 == required_pages {
                 // Remove found pages from free list
-                // println!("ALLOCATED PAGES: {required_pages:?}");
                 for i in 0..required_pages {
-                    self.free.remove(&(initial_page_address + i * page_size));
-                    self.pending_allocated
-                        .insert(initial_page_address + i * page_size);
+                    let address = initial_page.offset(i).to_byte_addr(page_size).get();
+                    self.free.remove(&address);
+                    self.pending_allocated.insert(address);
                 }
-                return Some(initial_page_address);
+                return Some(initial_page);
             }
-            previous_page_address = Some(*page_address);
+            previous_page = Some(page);
         }
-        // println!("NO {required_pages:?} PAGES IN FREE LIST");
         None
     }
 
-    pub fn register_allocation(&mut self, page_address: Address, transaction_id: TransactionId) {
-        let replaced = self.allocated_by.insert(page_address, transaction_id).is_some();
-        assert!(!replaced, "page address already registered");
+    /// Returns length in pages of the longest run of adjacent free pages not taken by
+    /// this transaction, which is the most `allocate` can return without growing file.
+    pub fn largest_free_run(&self, page_size: u64) -> u64 {
+        let mut largest_run = 0;
+        let mut run = 0;
+        let mut previous_page: Option<PageIndex> = None;
+        for page_address in self.free.difference(&self.pending_allocated) {
+            let page = ByteAddr::new(*page_address).page_index(page_size);
+            let is_adjacent = previous_page.is_some_and(|previous_page| page == previous_page.offset(1));
+            run = if is_adjacent { run + 1 } else { 1 };
+            largest_run = largest_run.max(run);
+            previous_page = Some(page);
+        }
+        largest_run
+    }
+
+    /// Records transaction which allocated each of `pages` starting at `first_page`.
+    pub fn register_allocation(
+        &mut self,
+        first_page: PageIndex,
+        pages: u64,
+        page_size: u64,
+        transaction_id: TransactionId,
+    ) {
+        for i in 0..pages {
+            let address = first_page.offset(i).to_byte_addr(page_size).get();
+            let replaced = self.allocated_by.insert(address, transaction_id).is_some();
+            assert!(!replaced, "page address already registered");
+        }
     }
 
-    pub fn read<R: Read>(reader: &mut R) -> Result<(NodeHeader, Self)> {
+    pub fn read<R: Read>(reader: &mut R, page_size: u32) -> Result<(NodeHeader, Self)> {
+        let page_size = page_size as u64;
         let header = NodeHeader::read(reader)?;
-        let free = read_vec_u64(reader, header.internal_nodes_len as usize)?;
-        let free = BTreeSet::from_iter(free);
+        if header.flags != FREELIST_NODE {
+            return Err(anyhow!("invalid free list node type {}", header.flags));
+        }
+        // Pages are stored sorted as gaps (in pages) from previous page
+        let mut free = BTreeSet::new();
+        let mut page = PageIndex::new(0);
+        for _ in 0..header.internal_nodes_len {
+            page = page.offset(read_varint(reader)?);
+            free.insert(page.to_byte_addr(page_size).get());
+        }
         let node = Self {
             free,
             allocated_by: HashMap::new(),
             pending_allocated: BTreeSet::new(),
             pending_free: BTreeMap::new(),
         };
-        // println!("FREE LIST: {node:?}");
         Ok((header, node))
     }
 
-    pub fn write<W: Write>(&self, writer: &mut W, page_size: u32) -> Result<NodeHeader> {
+    pub fn write<W: Write>(&self, writer: &mut W, page_size: u32, transaction_id: TransactionId) -> Result<NodeHeader> {
         let page_size = page_size as u64;
         let data = self.copy_all();
-        let data_size = NodeHeader::size() + (self.size()) as u64;
-        assert_eq!(data.len()*8, self.size());
+        let data_size = NodeHeader::size() + Self::encoded_size(&data, page_size) as u64;
         let overflow_len = if data_size <= page_size {
             0
         } else {
@@ -81,29 +112,60 @@ impl FreeList {
             flags: FREELIST_NODE,
             internal_nodes_len: data.len() as u64,
             overflow_len,
+            checksum: 0,
+            transaction_id,
         };
         header.write(writer)?;
-        write_slice_u64(writer, &data)?;
+        let mut previous_page = PageIndex::new(0);
+        for page_address in data {
+            let page = ByteAddr::new(page_address).page_index(page_size);
+            write_varint(writer, page.pages_since(previous_page))?;
+            previous_page = page;
+        }
+        Ok(header)
+    }
+
+    /// Reads free list pages, which must fit into `max_len` bytes, verifying
+    /// their checksum if `verify_checksum` is set.
+    pub fn read2<R: Read>(
+        reader: &mut R,
+        page_size: u32,
+        max_len: u64,
+        verify_checksum: bool,
+    ) -> Result<(NodeHeader, Self)> {
+        let buf = read_pages(reader, page_size as u64, max_len, verify_checksum)?;
+        Self::read(&mut buf.as_slice(), page_size)
+    }
+
+    /// Writes free list of transaction `transaction_id` as zero-filled pages with checksum.
+    pub fn write2<W: Write>(&self, writer: &mut W, page_size: u32, transaction_id: TransactionId) -> Result<NodeHeader> {
+        let mut buf = Vec::new();
+        let mut header = self.write(&mut buf, page_size, transaction_id)?;
+        header.checksum = write_pages(writer, buf, page_size as u64)?;
         Ok(header)
     }
 
     pub fn free(
         &mut self,
         transaction_id: TransactionId,
-        page_start_address: Address,
+        first_page: PageIndex,
         page_overflow: u64,
         page_size: u64,
     ) {
         let pending = self.pending_free.entry(transaction_id).or_default();
-        let page_end_addess = page_start_address + (page_overflow + 1) * page_size;
-        let mut page_address = page_start_address;
-        // println!("FREEING PAGE WITH OVERFLOW: {page_overflow:?}");
-        while page_address < page_end_addess {
-            pending.insert(page_address);
-            page_address += page_size;
+        for i in 0..=page_overflow {
+            pending.insert(first_page.offset(i).to_byte_addr(page_size).get());
         }
     }
 
+    /// Moves pages which are no longer visible to any reader from pending to free list.
+    /// `min_tx` and `max_tx` are the oldest and newest transactions seen by open readers,
+    /// `MAX_TRANSACTION_ID` and `0` if there are none.
+    ///
+    /// Page freed by transaction `T` is still seen by readers of transactions older than `T`,
+    /// so it is released once `T <= min_tx`. Page allocated by transaction `A` is seen only by
+    /// readers of `A` and newer transactions, so if `A > max_tx` it is released right away
+    /// regardless of when it was freed.
     pub fn release(&mut self, min_tx: TransactionId, max_tx: TransactionId) -> Vec<Address> {
         let txs = self
             .pending_free
@@ -120,7 +182,6 @@ impl FreeList {
                 .expect("pending transactions");
 
             freed.extend(&pages);
-            self.free.extend(pages);
         }
 
         // Release pages freed by write transactions
@@ -141,16 +202,28 @@ impl FreeList {
         }
 
         self.pending_free.retain(|_, pages| !pages.is_empty());
-        // println!("RELEASING PAGES COUNT: {:?}", freed.len());
 
         for page in freed.iter() {
             self.allocated_by.remove(page);
+            self.free.insert(*page);
         }
         freed
     }
 
-    pub fn size(&self) -> usize {
-        size_of::<u64>() * self.pages_len()
+    pub fn size(&self, page_size: u64) -> usize {
+        Self::encoded_size(&self.copy_all(), page_size)
+    }
+
+    fn encoded_size(data: &[Address], page_size: u64) -> usize {
+        let mut previous_page = PageIndex::new(0);
+        data.iter()
+            .map(|page_address| {
+                let page = ByteAddr::new(*page_address).page_index(page_size);
+                let size = varint_size(page.pages_since(previous_page));
+                previous_page = page;
+                size
+            })
+            .sum()
     }
 
     pub fn pages_len(&self) -> usize {
@@ -167,7 +240,7 @@ impl FreeList {
             self.free.len(),
             self.pending_free.len(),
             self.pending_allocated.len(),
-            self.free_summary().unwrap_or(String::new()),
+            self.free_summary().unwrap_or_default(),
         )
     }
 
@@ -198,11 +271,46 @@ impl FreeList {
         Some(res)
     }
 
+    /// Returns whether page is free or pending to be freed and is not
+    /// allocated by the current transaction.
+    pub fn contains(&self, address: Address) -> bool {
+        if self.pending_allocated.contains(&address) {
+            return false;
+        }
+        self.free.contains(&address)
+            || self.pending_free.values().any(|pages| pages.contains(&address))
+    }
+
+    /// Returns addresses which are contained in the free list.
+    pub fn overlaps_any(&self, addresses: &[Address]) -> Vec<Address> {
+        addresses
+            .iter()
+            .copied()
+            .filter(|address| self.contains(*address))
+            .collect()
+    }
+
+    /// Removes free pages right before `end` page and returns their addresses,
+    /// highest first, so that file can be shrunk by their number.
+    pub fn trim_tail(&mut self, end: PageIndex, page_size: u64) -> Vec<Address> {
+        let mut trimmed = Vec::new();
+        let mut end = end.get();
+        while end > 0 {
+            let last = PageIndex::new(end - 1).to_byte_addr(page_size).get();
+            if !self.free.remove(&last) {
+                break;
+            }
+            trimmed.push(last);
+            end -= 1;
+        }
+        trimmed
+    }
+
     fn copy_all(&self) -> Vec<u64> {
         let pending = self
             .pending_free
-            .iter()
-            .flat_map(|(_, pages)| pages.iter().copied())
+            .values()
+            .flat_map(|pages| pages.iter().copied())
             .collect::<BTreeSet<_>>();
 
         self.free.union(&pending).copied().collect()
@@ -213,15 +321,16 @@ impl FreeList {
     }
 
     pub fn rollback(&mut self, transaction_id: TransactionId) {
-        let Some(_) = self.pending_free.remove(&transaction_id) else {
-            return;
-        };
+        self.pending_free.remove(&transaction_id);
         self.free.extend(self.pending_allocated.iter());
         for allocated in self.pending_allocated.iter() {
             let removed = self.allocated_by.remove(allocated).is_some();
             assert!(removed, "allocated page was not registered");
         }
         self.pending_allocated.clear();
+        // Pages allocated by growing the file are not in pending allocations
+        self.allocated_by
+            .retain(|_, allocated_by| *allocated_by != transaction_id);
     }
 }
 
@@ -237,57 +346,98 @@ mod tests {
         };
     }
 
+    /// Returns index of page at `address` with page size 10 used by tests.
+    fn page(address: Address) -> PageIndex {
+        ByteAddr::new(address).page_index(10)
+    }
+
     const FREE_LIST_DATA: &[u8] = &[
         // header
         0x00, 0x03, // flags
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, // len=3
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // overflow
-        // contents
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, // 16
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, // 32
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x30, // 48
+        0x00, 0x00, 0x00, 0x00, // checksum
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // transaction id
+        // contents (page size 128)
+        0x01, // 128
+        0x02, // 384 = 128 + 2 * 128
+        0x81, 0x01, // 16896 = 384 + 129 * 128
     ];
 
     #[test]
     fn allocates_multiple_pages_at_the_start() {
-        let mut free_list = FreeList::default();
-        free_list.free = free_list![20, 30, 40, 50, 110, 130, 150, 160, 170, 180];
+        let mut free_list = FreeList {
+            free: free_list![20, 30, 40, 50, 110, 130, 150, 160, 170, 180],
+            ..Default::default()
+        };
         let page_address = free_list.allocate(4, 10);
-        assert_eq!(page_address, Some(20));
+        assert_eq!(page_address, Some(page(20)));
         assert_eq!(free_list.free, free_list![110, 130, 150, 160, 170, 180]);
     }
 
     #[test]
-    fn allocates_multiple_pages_at_the_middle() {
+    fn trims_free_pages_at_the_tail() {
+        let mut free_list = FreeList {
+            free: free_list![20, 40, 50, 60],
+            ..Default::default()
+        };
+        free_list.pending_free.insert(1, free_list![30]);
+        assert_eq!(free_list.trim_tail(page(70), 10), vec![60, 50, 40]);
+        assert_eq!(free_list.free, free_list![20]);
+        // Pending page is still visible to readers
+        assert_eq!(free_list.trim_tail(page(40), 10), Vec::<Address>::new());
+        assert_eq!(free_list.trim_tail(page(30), 10), vec![20]);
+    }
+
+    #[test]
+    fn finds_largest_free_run() {
         let mut free_list = FreeList::default();
-        free_list.free = free_list![20, 110, 130, 150, 160, 170, 180];
+        assert_eq!(free_list.largest_free_run(10), 0);
+        free_list.free = free_list![20, 40, 50, 60, 80, 90];
+        assert_eq!(free_list.largest_free_run(10), 3);
+        free_list.allocate(3, 10).unwrap();
+        assert_eq!(free_list.largest_free_run(10), 2);
+    }
+
+    #[test]
+    fn allocates_multiple_pages_at_the_middle() {
+        let mut free_list = FreeList {
+            free: free_list![20, 110, 130, 150, 160, 170, 180],
+            ..Default::default()
+        };
         let page_address = free_list.allocate(3, 10);
-        assert_eq!(page_address, Some(150));
+        assert_eq!(page_address, Some(page(150)));
         assert_eq!(free_list.free, free_list![20, 110, 130, 180]);
     }
 
     #[test]
     fn allocates_multiple_pages_at_the_end() {
-        let mut free_list = FreeList::default();
-        free_list.free = free_list![20, 110, 130, 150, 160, 170, 180];
+        let mut free_list = FreeList {
+            free: free_list![20, 110, 130, 150, 160, 170, 180],
+            ..Default::default()
+        };
         let page_address = free_list.allocate(4, 10);
-        assert_eq!(page_address, Some(150));
+        assert_eq!(page_address, Some(page(150)));
         assert_eq!(free_list.free, free_list![20, 110, 130]);
     }
 
     #[test]
     fn allocates_one_page() {
-        let mut free_list = FreeList::default();
-        free_list.free = free_list![20, 110, 130, 150, 160, 170, 180];
+        let mut free_list = FreeList {
+            free: free_list![20, 110, 130, 150, 160, 170, 180],
+            ..Default::default()
+        };
         let page_address = free_list.allocate(1, 10);
-        assert_eq!(page_address, Some(20));
+        assert_eq!(page_address, Some(page(20)));
         assert_eq!(free_list.free, free_list![110, 130, 150, 160, 170, 180]);
     }
 
     #[test]
     fn cannot_allocates_when_page_runs_are_too_small() {
-        let mut free_list = FreeList::default();
-        free_list.free = free_list![20, 110, 130, 150, 160, 170, 180];
+        let mut free_list = FreeList {
+            free: free_list![20, 110, 130, 150, 160, 170, 180],
+            ..Default::default()
+        };
         let page_address = free_list.allocate(10, 10);
         assert_eq!(page_address, None);
         assert_eq!(free_list.free, free_list![20, 110, 130, 150, 160, 170, 180]);
@@ -304,25 +454,203 @@ mod tests {
     #[test]
     fn reads_free_list() {
         let mut reader = Cursor::new(FREE_LIST_DATA);
-        let (header, free_list) = FreeList::read(&mut reader).unwrap();
+        let (header, free_list) = FreeList::read(&mut reader, 128).unwrap();
         assert_eq!(
             header,
             NodeHeader {
                 flags: FREELIST_NODE,
                 internal_nodes_len: 3,
-                overflow_len: 0
+                overflow_len: 0,
+                checksum: 0,
+                transaction_id: 1,
             }
         );
-        assert_eq!(free_list.free, free_list![16, 32, 48]);
+        assert_eq!(free_list.free, free_list![128, 384, 16896]);
         assert!(free_list.pending_free.is_empty());
     }
 
     #[test]
     fn writes_free_list() {
-        let mut free_list = FreeList::default();
-        free_list.free = free_list![16, 32, 48];
+        let free_list = FreeList {
+            free: free_list![128, 384, 16896],
+            ..Default::default()
+        };
         let mut writer = Cursor::new(Vec::new());
-        free_list.write(&mut writer, 128).unwrap();
+        free_list.write(&mut writer, 128, 1).unwrap();
         assert_eq!(writer.into_inner(), FREE_LIST_DATA);
     }
+
+    #[test]
+    fn round_trips_free_list_gaps() {
+        let free_list = FreeList {
+            free: free_list![4096, 8192, 12288, 1 << 20, (1 << 40) + 4096],
+            pending_free: BTreeMap::from([(3, free_list![16384, 20480])]),
+            ..Default::default()
+        };
+        let mut writer = Cursor::new(Vec::new());
+        let header = free_list.write(&mut writer, 4096, 1).unwrap();
+        let data = writer.into_inner();
+        assert_eq!(header.internal_nodes_len, 7);
+        assert_eq!(data.len(), NodeHeader::size() as usize + free_list.size(4096));
+
+        let (_, read_free_list) = FreeList::read(&mut Cursor::new(data), 4096).unwrap();
+        assert_eq!(
+            read_free_list.free,
+            free_list![4096, 8192, 12288, 16384, 20480, 1 << 20, (1 << 40) + 4096],
+        );
+    }
+
+    #[test]
+    fn encodes_dense_free_range_independently_of_its_position() {
+        let page_size = 4096;
+        let pages = 10_000;
+        let sizes = [0u64, 1 << 30, 1 << 45].map(|offset| {
+            let free_list = FreeList {
+                free: (0..pages).map(|i| offset + (i + 1) * page_size).collect(),
+                ..Default::default()
+            };
+            let mut writer = Cursor::new(Vec::new());
+            free_list.write(&mut writer, page_size as u32, 1).unwrap();
+            let data = writer.into_inner();
+
+            let (_, read_free_list) = FreeList::read(&mut Cursor::new(&data), page_size as u32).unwrap();
+            assert_eq!(read_free_list.free, free_list.free);
+            data.len() - NodeHeader::size() as usize
+        });
+
+        // First page index plus one byte per each following page
+        for size in sizes {
+            assert!(size >= pages as usize && size <= pages as usize + 10, "size {size}");
+        }
+    }
+
+    #[test]
+    fn rolls_back_allocations_without_frees() {
+        let mut free_list = FreeList {
+            free: free_list![10, 20, 30],
+            ..Default::default()
+        };
+        let page_address = free_list.allocate(1, 10).unwrap();
+        free_list.register_allocation(page_address, 1, 10, 5);
+        free_list.register_allocation(page(40), 1, 10, 5);
+        free_list.rollback(5);
+        assert_eq!(free_list.free, free_list![10, 20, 30]);
+        assert!(free_list.pending_allocated.is_empty());
+        assert!(free_list.allocated_by.is_empty());
+    }
+
+    #[test]
+    fn rolls_back_multi_page_allocations() {
+        let mut free_list = FreeList {
+            free: free_list![10, 20, 30, 50],
+            ..Default::default()
+        };
+        let page_address = free_list.allocate(3, 10).unwrap();
+        free_list.register_allocation(page_address, 3, 10, 5);
+        free_list.register_allocation(page(60), 2, 10, 5);
+        free_list.rollback(5);
+        assert_eq!(free_list.free, free_list![10, 20, 30, 50]);
+        assert!(free_list.pending_allocated.is_empty());
+        assert!(free_list.allocated_by.is_empty());
+    }
+
+    fn pending_free_list(pages: &[(TransactionId, &[Address])]) -> BTreeMap<TransactionId, BTreeSet<Address>> {
+        pages
+            .iter()
+            .map(|(transaction_id, pages)| (*transaction_id, pages.iter().copied().collect()))
+            .collect()
+    }
+
+    #[test]
+    fn contains_free_and_pending_pages() {
+        let mut free_list = FreeList {
+            free: free_list![10, 20, 30],
+            pending_free: pending_free_list(&[(2, &[40]), (3, &[50, 60])]),
+            ..Default::default()
+        };
+        let page_address = free_list.allocate(1, 10).unwrap();
+        assert_eq!(page_address, page(10));
+        assert!(!free_list.contains(10));
+        assert!(free_list.contains(20));
+        assert!(free_list.contains(40));
+        assert!(free_list.contains(60));
+        assert!(!free_list.contains(70));
+
+        free_list.pending_allocated.insert(50);
+        assert!(!free_list.contains(50));
+    }
+
+    #[test]
+    fn reports_overlapping_pages() {
+        let free_list = FreeList {
+            free: free_list![10, 20],
+            pending_free: pending_free_list(&[(2, &[30]), (4, &[40])]),
+            pending_allocated: free_list![20],
+            ..Default::default()
+        };
+        assert_eq!(free_list.overlaps_any(&[]), Vec::<Address>::new());
+        assert_eq!(free_list.overlaps_any(&[40, 5, 10, 20, 30, 50]), vec![40, 10, 30]);
+    }
+
+    #[test]
+    fn releases_all_pending_pages_without_readers() {
+        let mut free_list = FreeList {
+            free: free_list![10],
+            pending_free: pending_free_list(&[(2, &[20, 30]), (5, &[40])]),
+            allocated_by: HashMap::from([(20, 1), (40, 3)]),
+            ..Default::default()
+        };
+        let mut released = free_list.release(TransactionId::MAX, 0);
+        released.sort();
+        assert_eq!(released, vec![20, 30, 40]);
+        assert_eq!(free_list.free, free_list![10, 20, 30, 40]);
+        assert!(free_list.pending_free.is_empty());
+        assert!(free_list.allocated_by.is_empty());
+    }
+
+    #[test]
+    fn keeps_pages_freed_after_oldest_reader() {
+        // Reader of transaction 3 sees pages freed by transactions 4 and 5
+        let mut free_list = FreeList {
+            pending_free: pending_free_list(&[(2, &[20]), (3, &[30]), (4, &[40]), (5, &[50])]),
+            allocated_by: HashMap::from([(40, 1), (50, 2)]),
+            ..Default::default()
+        };
+        let released = free_list.release(3, 3);
+        assert_eq!(released, vec![20, 30]);
+        assert_eq!(free_list.free, free_list![20, 30]);
+        assert_eq!(free_list.pending_free, pending_free_list(&[(4, &[40]), (5, &[50])]));
+
+        // Once reader is closed remaining pages are released
+        let released = free_list.release(TransactionId::MAX, 0);
+        assert_eq!(released, vec![40, 50]);
+        assert!(free_list.pending_free.is_empty());
+    }
+
+    #[test]
+    fn releases_pages_allocated_after_newest_reader() {
+        // Readers of transactions 3 and 5 are open. Page 60 was allocated by transaction 6
+        // and freed by transaction 7, so none of readers has seen it. Page 50 was
+        // allocated by transaction 5 and is still seen by reader of transaction 5.
+        let mut free_list = FreeList {
+            pending_free: pending_free_list(&[(4, &[40]), (7, &[50, 60])]),
+            allocated_by: HashMap::from([(40, 2), (50, 5), (60, 6)]),
+            ..Default::default()
+        };
+        let released = free_list.release(3, 5);
+        assert_eq!(released, vec![60]);
+        assert_eq!(free_list.free, free_list![60]);
+        assert_eq!(free_list.pending_free, pending_free_list(&[(4, &[40]), (7, &[50])]));
+        assert_eq!(free_list.allocated_by, HashMap::from([(40, 2), (50, 5)]));
+    }
+
+    #[test]
+    fn keeps_pages_of_unknown_allocation_while_pinned() {
+        let mut free_list = FreeList {
+            pending_free: pending_free_list(&[(7, &[50])]),
+            ..Default::default()
+        };
+        assert!(free_list.release(3, 5).is_empty());
+        assert_eq!(free_list.pending_free, pending_free_list(&[(7, &[50])]));
+    }
 }
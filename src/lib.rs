@@ -1,25 +1,252 @@
 pub mod cursor;
+pub mod error;
+pub mod format;
 pub mod free_list;
+pub mod metrics;
 pub mod node;
+pub mod stats;
+pub mod verify;
 
-mod format;
+#[cfg(feature = "async")]
+pub mod r#async;
+mod recovery;
 mod tx;
 
-use anyhow::{anyhow, Result};
+#[cfg(test)]
+mod model_tests;
+#[cfg(test)]
+mod test_utils;
+
+use anyhow::{anyhow, Context, Result};
+use cursor::Cursor;
+pub use cursor::SeekBias;
+pub use error::Error;
+pub use tx::{CommitProgress, Entry, LoggedOp, Op, TransactionId, TreeDump, MAX_TRANSACTION_ID};
 use free_list::FreeList;
-use node::{Address, InternalNodes, MetaNode, NodeHeader, NodeManager};
+use metrics::{DatabaseMetrics, Metrics};
+use node::{unix_time_millis, Address, InternalNodes, MetaNode, NodeHeader, NodeManager};
 use std::{
-    collections::{btree_map::Entry, BTreeMap}, io::{Seek, Write}, path::Path, sync::{Arc, Condvar, Mutex}
+    collections::{btree_map, BTreeMap}, fmt, io::{Seek, Write}, ops::Bound, path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
 };
-use tx::{ReadTransaction, TransactionId, WriteTransaction};
+use stats::{FileStats, Stats};
+use tx::{ReadTransaction, Snapshot, WriteTransaction};
+use verify::Violation;
 
 const MIN_PAGE_SIZE: usize = 1 << 7;
+const MAX_PAGE_SIZE: usize = 1 << 20;
 
-#[derive(Debug)]
+/// Checks that page size is a power of two within supported range. Allocation and
+/// free list encoding rely on page addresses being multiples of page size, and
+/// meta nodes must fit into pages preceding the first data page.
+fn check_page_size(page_size: u32) -> Result<()> {
+    let page_size = page_size as usize;
+    if page_size < MIN_PAGE_SIZE {
+        return Err(anyhow!(
+            "page size is too small: {page_size}. must be at least {MIN_PAGE_SIZE}"
+        ));
+    }
+    if page_size > MAX_PAGE_SIZE {
+        return Err(anyhow!(
+            "page size is too large: {page_size}. must be at most {MAX_PAGE_SIZE}"
+        ));
+    }
+    if !page_size.is_power_of_two() {
+        return Err(anyhow!("page size must be a power of two: {page_size}"));
+    }
+    Ok(())
+}
+
+/// Returns whether creating database failed because its file already exists.
+fn is_already_exists(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(|err| err.kind() == std::io::ErrorKind::AlreadyExists)
+}
+
+/// Called with id and logical operations of each committed transaction.
+pub type CommitHook = Arc<dyn Fn(TransactionId, &[LoggedOp]) + Send + Sync>;
+
+/// Receives internal diagnostic messages such as node splits and merges.
+pub type TraceSink = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Combines key, its existing value, if any, and operand into new value.
+pub type MergeOperator = Arc<dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync>;
+
+/// Returns current unix time in milliseconds.
+pub type Clock = Arc<dyn Fn() -> u64 + Send + Sync>;
+
+#[derive(Clone)]
 pub struct Options {
     pub max_files: u16,
     pub page_size: u32,
     pub cache_size: u64,
+    /// Dirty nodes of write transaction are written to disk before commit
+    /// once their size exceeds this threshold. Disabled when `None`. Copies of
+    /// nodes kept for savepoints are not counted and stay in memory, while pages
+    /// spilled after a savepoint are freed when transaction is rolled back to it.
+    pub spill_threshold: Option<u64>,
+    /// Verifies tree of every commit before its meta node is written and fails the commit
+    /// with `Error::Corruption` listing all violations, rolling it back. Meant for tests
+    /// and debugging.
+    pub paranoid: bool,
+    /// Called after meta node of a transaction with logged operations is durably
+    /// written, while writer is still held, so it must not begin write transactions.
+    pub on_commit: Option<CommitHook>,
+    /// Used by `WriteTransaction::put_merge` to combine existing value with operand.
+    pub merge_operator: Option<MergeOperator>,
+    /// Number of following sibling leaves read into node cache at once
+    /// when cursor descends to a leaf during forward scan. Disabled when zero.
+    pub prefetch_leaves: usize,
+    /// Number of contiguous pages read with a single read when cursor descends to
+    /// a node missing from cache during forward scan. Following leaves found in them
+    /// are cached, which pays off for sequentially written trees. Disabled when zero.
+    pub read_ahead_leaves: usize,
+    /// Size new database file is preallocated to, rounded up to page size. Pages past
+    /// the end address are allocated without growing the file until it is filled up.
+    pub initial_file_size: Option<u64>,
+    /// How file grows once allocation runs past its end.
+    pub growth_policy: GrowthPolicy,
+    /// Maximum number of entries in a node, which is split once it has more of them
+    /// even if it fits into a page. Values below 4 are raised to 4.
+    pub max_keys_per_node: Option<usize>,
+    /// Sink of diagnostic messages. Messages are not formatted when it is not set.
+    pub trace: Option<TraceSink>,
+    /// Relocates nodes from the tail of file on every non-empty commit, so that file can
+    /// be shrunk without stalling writers as `Database::compact` does. Disabled when `None`.
+    pub auto_compact: Option<AutoCompactConfig>,
+    /// Verifies checksums of node and free list pages when they are read. Turning it
+    /// off speeds up reads on trusted storage, but corrupted pages are then parsed as is.
+    /// Checksums are written either way, so file can be verified with it turned on later.
+    pub checksum_reads: bool,
+    /// Opens file without write access. Reads work as usual, while commits of
+    /// non-empty transactions fail with `Error::ReadOnly`.
+    pub read_only: bool,
+    /// Time used to set and check expiry of entries. System time when `None`.
+    pub clock: Option<Clock>,
+}
+
+/// Bounds work done by incremental compaction on each commit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoCompactConfig {
+    /// Maximum number of pages at the tail of file whose nodes are rewritten
+    /// into free pages closer to its start.
+    pub pages_per_commit: u64,
+    /// Share of used pages in file, between 0 and 1, at which relocation stops.
+    pub target_fill: f64,
+}
+
+impl Default for AutoCompactConfig {
+    fn default() -> Self {
+        Self {
+            pages_per_commit: 16,
+            target_fill: 0.9,
+        }
+    }
+}
+
+/// Strategy of growing database file when allocated pages do not fit into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GrowthPolicy {
+    /// Doubles file size until it reaches 1GiB, then grows it by 1GiB steps.
+    #[default]
+    Double,
+    /// Grows file by fixed number of bytes.
+    Fixed(u64),
+    /// Grows file by percentage of its current size.
+    Percent(u8),
+}
+
+impl GrowthPolicy {
+    /// Returns file size to grow file of `file_size` to, so that it covers `required_size`.
+    /// Result is rounded up to page size.
+    pub fn next_file_size(&self, file_size: u64, required_size: u64, page_size: u64) -> u64 {
+        let new_file_size = match self {
+            Self::Double => {
+                const GB: u64 = 1 << 30;
+                file_size.saturating_add(file_size.min(GB))
+            }
+            Self::Fixed(step) => file_size.saturating_add(*step),
+            Self::Percent(percent) => file_size.saturating_add(file_size / 100 * *percent as u64),
+        };
+        new_file_size.max(required_size).div_ceil(page_size) * page_size
+    }
+}
+
+impl Options {
+    pub fn on_commit(mut self, hook: CommitHook) -> Self {
+        self.on_commit = Some(hook);
+        self
+    }
+
+    pub fn merge_operator(mut self, merge_operator: MergeOperator) -> Self {
+        self.merge_operator = Some(merge_operator);
+        self
+    }
+
+    pub fn growth_policy(mut self, growth_policy: GrowthPolicy) -> Self {
+        self.growth_policy = growth_policy;
+        self
+    }
+
+    pub fn max_keys_per_node(mut self, max_keys_per_node: Option<usize>) -> Self {
+        self.max_keys_per_node = max_keys_per_node;
+        self
+    }
+
+    pub fn read_ahead_leaves(mut self, pages: usize) -> Self {
+        self.read_ahead_leaves = pages;
+        self
+    }
+
+    pub fn trace(mut self, sink: TraceSink) -> Self {
+        self.trace = Some(sink);
+        self
+    }
+
+    pub fn auto_compact(mut self, config: Option<AutoCompactConfig>) -> Self {
+        self.auto_compact = config;
+        self
+    }
+
+    pub fn checksum_reads(mut self, checksum_reads: bool) -> Self {
+        self.checksum_reads = checksum_reads;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+}
+
+impl fmt::Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Options")
+            .field("max_files", &self.max_files)
+            .field("page_size", &self.page_size)
+            .field("cache_size", &self.cache_size)
+            .field("spill_threshold", &self.spill_threshold)
+            .field("paranoid", &self.paranoid)
+            .field("on_commit", &self.on_commit.is_some())
+            .field("merge_operator", &self.merge_operator.is_some())
+            .field("prefetch_leaves", &self.prefetch_leaves)
+            .field("initial_file_size", &self.initial_file_size)
+            .field("growth_policy", &self.growth_policy)
+            .field("max_keys_per_node", &self.max_keys_per_node)
+            .field("read_ahead_leaves", &self.read_ahead_leaves)
+            .field("trace", &self.trace.is_some())
+            .field("auto_compact", &self.auto_compact)
+            .field("checksum_reads", &self.checksum_reads)
+            .field("read_only", &self.read_only)
+            .field("clock", &self.clock.is_some())
+            .finish()
+    }
 }
 
 impl Default for Options {
@@ -28,6 +255,20 @@ impl Default for Options {
             max_files: 16,
             page_size: 4 << 10,  // 4KiB
             cache_size: 100 << 20, // 1MiB
+            spill_threshold: None,
+            paranoid: false,
+            on_commit: None,
+            merge_operator: None,
+            prefetch_leaves: 0,
+            read_ahead_leaves: 0,
+            initial_file_size: None,
+            growth_policy: GrowthPolicy::Double,
+            max_keys_per_node: None,
+            trace: None,
+            auto_compact: None,
+            checksum_reads: true,
+            read_only: false,
+            clock: None,
         }
     }
 }
@@ -37,39 +278,158 @@ pub struct Database {
     internal: Arc<DatabaseInternal>,
 }
 
+// Database is shared between threads by reference or by cloning
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Database>();
+};
+
 impl Database {
+    /// Creates new database file. Fails if file already exists.
+    pub fn create(file_path: impl AsRef<Path>, options: Options) -> Result<Self> {
+        let internal = Self::write_initial_state(file_path, &options)?;
+        Ok(Self {
+            internal: Arc::new(internal),
+        })
+    }
+
+    /// Opens existing database file. Fails if file does not exist.
     pub fn open(file_path: impl AsRef<Path>, options: Options) -> Result<Self> {
-        // open or create file
-        let internal = if !std::fs::exists(file_path.as_ref())? {
-            Self::write_initial_state(file_path, &options)?
-        } else {
-            Self::read_state(file_path, &options)?
-        };
+        let internal = Self::read_state(file_path, &options)?;
+        Ok(Self {
+            internal: Arc::new(internal),
+        })
+    }
+
+    /// Opens existing database file. If both meta nodes are unreadable, rebuilds them
+    /// and free list from the tree found by scanning the file with `options.page_size`.
+    /// Meant as a last resort since recovered tree may be older than the latest commit.
+    /// Fails with `Error::ReadOnly` if recovery is needed and `options.read_only` is set.
+    pub fn open_with_recovery(file_path: impl AsRef<Path>, options: Options) -> Result<Self> {
+        let mut file = std::fs::File::open(file_path.as_ref())?;
+        if let Err(err) = Self::read_meta_node(&mut file) {
+            if let Some(Error::UnsupportedVersion(_) | Error::NotADatabase(_)) = err.downcast_ref::<Error>() {
+                return Err(err);
+            }
+            if options.read_only {
+                return Err(anyhow::Error::new(Error::ReadOnly))
+                    .with_context(|| format!("cannot recover database: {err}"));
+            }
+            recovery::recover(file_path.as_ref(), options.page_size)
+                .with_context(|| format!("failed to recover database: {err}"))?;
+        }
+        Self::open(file_path, options)
+    }
+
+    /// Reloads meta node and free list from file in place, picking up changes made to it
+    /// by another process, e.g. by compaction, and drops cached nodes. Fails with
+    /// `Error::WouldBlock` if write or read transactions are active, since pages they
+    /// read may have been reused by the other process. State is left unchanged then.
+    pub fn reopen(&self) -> Result<()> {
+        let writer = self
+            .internal
+            .take_write_state(Some(Instant::now()))
+            .ok_or(Error::WouldBlock)?;
+        match self.internal.reload() {
+            Ok(reloaded) => self.internal.release_writer(reloaded),
+            Err(err) => {
+                self.internal.release_writer(writer);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Opens database file creating it if it does not exist. Read-only database
+    /// is never created, so it fails if file does not exist.
+    pub fn open_or_create(file_path: impl AsRef<Path>, options: Options) -> Result<Self> {
+        if !options.read_only {
+            // File may be created concurrently, so it is not checked for existence first
+            match Self::create(file_path.as_ref(), options.clone()) {
+                Err(err) if is_already_exists(&err) => {}
+                result => return result,
+            }
+        }
+        Self::open(file_path, options)
+    }
 
+    /// Opens an existing database using page size stored in its meta node.
+    pub fn open_auto(file_path: impl AsRef<Path>) -> Result<Self> {
+        let options = Options {
+            page_size: Self::read_page_size(file_path.as_ref())?,
+            ..Options::default()
+        };
+        let internal = Self::read_state(file_path, &options)?;
         Ok(Self {
             internal: Arc::new(internal),
         })
     }
 
+    /// Returns builder opening database at `file_path` with chainable settings.
+    pub fn builder(file_path: impl AsRef<Path>) -> DatabaseBuilder {
+        DatabaseBuilder::new(file_path)
+    }
+
     pub fn begin_write(&self) -> WriteTransaction {
         self.internal.begin_write()
     }
 
+    /// Returns page size stored in database file.
+    pub fn page_size(&self) -> u32 {
+        self.internal.page_size
+    }
+
+    /// Returns largest key size accepted by `put`.
+    pub fn max_key_size(&self) -> usize {
+        node::MAX_KEY_SIZE
+    }
+
+    /// Returns largest value size accepted by `put`.
+    pub fn max_value_size(&self) -> usize {
+        node::MAX_VALUE_SIZE
+    }
+
+    /// Returns options database was opened with. Page size is the one
+    /// stored in database file.
+    pub fn options(&self) -> &Options {
+        &self.internal.options
+    }
+
+    /// Runs `f` in a write transaction which is committed if `f` succeeds.
+    /// Transaction is rolled back if `f` fails or panics.
+    pub fn write<T>(&self, f: impl FnOnce(&mut WriteTransaction) -> Result<T>) -> Result<T> {
+        let mut tx = self.begin_write();
+        let result = f(&mut tx)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    /// Waits for the writer at most `timeout` and fails with `Error::WouldBlock`
+    /// if another write transaction is still active.
+    pub fn begin_write_timeout(&self, timeout: Duration) -> Result<WriteTransaction> {
+        self.internal.begin_write_timeout(timeout)
+    }
+
+    /// Returns `None` without waiting if another write transaction is active.
+    pub fn try_begin_write(&self) -> Option<WriteTransaction> {
+        self.internal.try_begin_write()
+    }
+
     pub fn begin_read(&self) -> ReadTransaction {
         self.internal.begin_read()
     }
 
+    /// Creates read view of latest committed transaction which can be cloned
+    /// and shared between threads.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.internal.begin_read())
+    }
+
     fn write_initial_state(
         file_path: impl AsRef<Path>,
         options: &Options,
     ) -> Result<DatabaseInternal> {
-        if (options.page_size as usize) < MIN_PAGE_SIZE {
-            return Err(anyhow!(
-                "page size is too small: {}. must be at least {}",
-                options.page_size,
-                MIN_PAGE_SIZE,
-            ));
-        }
+        check_page_size(options.page_size)?;
 
         let mut file = std::fs::OpenOptions::new()
             .read(true)
@@ -77,11 +437,8 @@ impl Database {
             .create_new(true)
             .open(file_path.as_ref())?;
 
-        let initial_alignment = options.page_size as u64
-            * (MetaNode::page_size() * 2).div_ceil(options.page_size as u64);
-
-        let free_list_address = initial_alignment;
-        let root_node_address = initial_alignment + options.page_size as u64;
+        let free_list_address = MetaNode::data_start_address(options.page_size);
+        let root_node_address = free_list_address + options.page_size as u64;
         let end_address = root_node_address + options.page_size as u64;
         let meta_nodes = [
             MetaNode {
@@ -90,6 +447,7 @@ impl Database {
                 free_list_node: free_list_address,
                 transaction_id: 0,
                 end_address,
+                entries: 0,
             },
             MetaNode {
                 page_size: options.page_size,
@@ -97,6 +455,7 @@ impl Database {
                 free_list_node: free_list_address,
                 transaction_id: 1,
                 end_address,
+                entries: 0,
             },
         ];
         for (i, meta_node) in meta_nodes.iter().enumerate() {
@@ -107,14 +466,18 @@ impl Database {
         // write free list node
         let free_list = FreeList::default();
         file.seek(std::io::SeekFrom::Start(free_list_address))?;
-        let free_list_header = free_list.write(&mut file, options.page_size)?;
+        let free_list_header = free_list.write2(&mut file, options.page_size, 0)?;
 
         // write root node
         let node = InternalNodes::Leaf(Vec::new());
         file.seek(std::io::SeekFrom::Start(root_node_address))?;
-        node.write(&mut file, options.page_size as u64)?;
+        node.write2(&mut file, options.page_size as u64, 0)?;
 
-        file.set_len(end_address)?;
+        let page_size = options.page_size as u64;
+        let file_size = options
+            .initial_file_size
+            .map_or(end_address, |size| end_address.max(size.div_ceil(page_size) * page_size));
+        file.set_len(file_size)?;
         file.flush()?;
 
         let write_state = WriteState {
@@ -126,67 +489,59 @@ impl Database {
 
         let reader_meta = write_state.meta().clone();
 
+        let metrics = Arc::new(Metrics::default());
         Ok(DatabaseInternal {
             node_manager: NodeManager::new(
                 file_path,
                 options.max_files as usize,
                 options.page_size,
                 options.cache_size,
-            ),
+                metrics.clone(),
+            )
+            .with_checksum_reads(options.checksum_reads)
+            .with_read_only(options.read_only),
+            metrics,
             write_state: Mutex::new(Some(write_state)),
             write_state_condvar: Condvar::new(),
             read_state: Mutex::new(ReadState {
                 meta_node: reader_meta,
                 transactions: BTreeMap::new(),
+                pinned_pages: 0,
             }),
             page_size: options.page_size,
+            options: options.clone(),
         })
     }
 
     fn read_state(file_path: impl AsRef<Path>, options: &Options) -> Result<DatabaseInternal> {
         let mut file = std::fs::OpenOptions::new()
             .read(true)
-            .write(true)
+            .write(!options.read_only)
             .create(false)
             .open(file_path.as_ref())?;
 
-        file.seek(std::io::SeekFrom::Start(0))?;
-        let meta_node0 = MetaNode::read(&mut file);
-        file.seek(std::io::SeekFrom::Start(MetaNode::page_size()))?;
-        let meta_node1 = MetaNode::read(&mut file);
-
-        let meta_node = match (meta_node0, meta_node1) {
-            (Ok(meta_node0), Ok(meta_node1)) => {
-                if meta_node0.transaction_id < meta_node1.transaction_id {
-                    Ok(meta_node1)
-                } else {
-                    Ok(meta_node0)
-                }
-            }
-            (Ok(meta_node0), _) => Ok(meta_node0),
-            (_, Ok(meta_node1)) => Ok(meta_node1),
-            (e @ Err(_), _) => e,
-        }?;
-
-        if options.page_size != meta_node.page_size {
-            return Err(anyhow!(
-                "unexpected page size: {}. actual database page size is {}",
-                options.page_size,
-                meta_node.page_size,
-            ));
-        }
+        let meta_node = Self::read_checked_meta_node(&mut file, options.page_size)?;
 
-        file.seek(std::io::SeekFrom::Start(meta_node.free_list_node))?;
-        let (free_list_header, free_list) = FreeList::read(&mut file)?;
+        let metrics = Arc::new(Metrics::default());
+        let node_manager = NodeManager::new(
+            file_path,
+            options.max_files as usize,
+            meta_node.page_size,
+            options.cache_size,
+            metrics.clone(),
+        )
+        .with_checksum_reads(options.checksum_reads)
+        .with_read_only(options.read_only);
+        let (free_list_header, free_list) = node_manager.read_free_list(meta_node.free_list_node)?;
 
         Ok(DatabaseInternal {
-            node_manager: NodeManager::new(
-                file_path,
-                options.max_files as usize,
-                meta_node.page_size,
-                options.cache_size,
-            ),
+            node_manager,
+            metrics,
             page_size: meta_node.page_size,
+            options: Options {
+                page_size: meta_node.page_size,
+                ..options.clone()
+            },
             write_state: Mutex::new(Some(WriteState {
                 free_list_header,
                 free_list,
@@ -197,81 +552,481 @@ impl Database {
             read_state: Mutex::new(ReadState {
                 meta_node,
                 transactions: BTreeMap::new(),
+                pinned_pages: 0,
             }),
         })
     }
 }
 
+/// Opens database with settings chained on top of `Options`. Unless page size is set
+/// explicitly, existing file is opened with page size stored in it.
+#[derive(Debug, Clone)]
+pub struct DatabaseBuilder {
+    file_path: PathBuf,
+    options: Options,
+    page_size: Option<u32>,
+    create_if_missing: bool,
+}
+
+impl DatabaseBuilder {
+    fn new(file_path: impl AsRef<Path>) -> Self {
+        Self {
+            file_path: file_path.as_ref().to_path_buf(),
+            options: Options::default(),
+            page_size: None,
+            create_if_missing: false,
+        }
+    }
+
+    /// Sets options remaining settings are taken from. Replaces settings chained before it,
+    /// except for page size.
+    pub fn options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn page_size(mut self, page_size: u32) -> Self {
+        self.page_size = Some(page_size);
+        self
+    }
+
+    pub fn cache_size(mut self, cache_size: u64) -> Self {
+        self.options.cache_size = cache_size;
+        self
+    }
+
+    pub fn max_files(mut self, max_files: u16) -> Self {
+        self.options.max_files = max_files;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    /// Creates database file if it does not exist instead of failing.
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Opens database file, creating it first if `create_if_missing` is set. Fails with
+    /// `Error::ReadOnly` if it is combined with `read_only`, since file could be written.
+    pub fn open(self) -> Result<Database> {
+        let mut options = self.options;
+        if self.create_if_missing {
+            if options.read_only {
+                return Err(anyhow::Error::new(Error::ReadOnly))
+                    .context("cannot create database opened read-only");
+            }
+            let mut create_options = options.clone();
+            create_options.page_size = self.page_size.unwrap_or(create_options.page_size);
+            // File may be created concurrently, so it is not checked for existence first
+            match Database::create(&self.file_path, create_options) {
+                Err(err) if is_already_exists(&err) => {}
+                result => return result,
+            }
+        }
+        options.page_size = match self.page_size {
+            Some(page_size) => page_size,
+            None => Database::read_page_size(&self.file_path)?,
+        };
+        Database::open(&self.file_path, options)
+    }
+}
+
+impl Database {
+    /// Reads page size of an existing database file.
+    pub fn read_page_size(file_path: impl AsRef<Path>) -> Result<u32> {
+        let mut file = std::fs::File::open(file_path.as_ref())?;
+        Ok(Self::read_meta_node(&mut file)?.page_size)
+    }
+
+    /// Returns transaction id of the oldest open read transaction.
+    /// Pages freed after it cannot be reclaimed until it is closed.
+    pub fn oldest_reader(&self) -> Option<TransactionId> {
+        let read_state = self.internal.read_state.lock().expect("read state lock");
+        read_state.transactions.keys().next().copied()
+    }
+
+    /// Returns number of freed pages which are still held back from reuse.
+    /// Updated whenever write transaction begins or ends.
+    pub fn pinned_page_count(&self) -> u64 {
+        self.internal.read_state.lock().expect("read state lock").pinned_pages
+    }
+
+    /// Applies transaction logged by `Options::on_commit` hook of another database
+    /// as a single write transaction. Transaction id must follow the id of latest
+    /// committed transaction, so logs can't be skipped or applied twice.
+    pub fn apply_log(&self, transaction_id: TransactionId, ops: &[LoggedOp]) -> Result<()> {
+        let mut tx = self.begin_write();
+        if tx.transaction_id() != transaction_id {
+            return Err(anyhow!(
+                "unexpected transaction id: {transaction_id}. expected transaction id is {}",
+                tx.transaction_id(),
+            ));
+        }
+        if let Err(err) = tx.apply_logged(ops) {
+            tx.rollback()?;
+            return Err(err);
+        }
+        tx.commit()
+    }
+
+    /// Returns counters accumulated since database was opened.
+    pub fn metrics(&self) -> DatabaseMetrics {
+        self.internal.metrics.snapshot()
+    }
+
+    /// Returns number of entries in latest committed transaction.
+    /// Expired entries which were not evicted yet are counted.
+    pub fn key_count(&self) -> u64 {
+        let read_state = self.internal.read_state.lock().expect("read state lock");
+        read_state.meta_node.entries
+    }
+
+    /// Collects statistics about tree and free list of latest committed transaction.
+    pub fn stats(&self) -> Result<Stats> {
+        let (tx, meta_node) = self.internal.begin_read_with_meta();
+        stats::collect(&tx, &meta_node, &self.internal.node_manager)
+    }
+
+    /// Returns page accounting of the file for latest committed transaction.
+    pub fn file_stats(&self) -> Result<FileStats> {
+        let (_tx, meta_node) = self.internal.begin_read_with_meta();
+        stats::collect_file(&meta_node, &self.internal.node_manager)
+    }
+
+    /// Checks tree invariants of latest committed transaction.
+    /// Returns empty list if no violations were found.
+    pub fn verify(&self) -> Result<Vec<Violation>> {
+        self.internal.verify()
+    }
+
+    /// Reads all branch nodes of latest committed transaction and first `leaves` leaves
+    /// into node cache, so that first lookups after open do not read upper tree levels
+    /// from file. Leftmost leaf is read even when `leaves` is zero to find tree depth.
+    /// Nodes may be evicted again if they don't fit into `Options::cache_size`.
+    pub fn warmup(&self, leaves: usize) -> Result<()> {
+        let (_tx, meta_node) = self.internal.begin_read_with_meta();
+        let node_manager = &self.internal.node_manager;
+        let mut level = vec![meta_node.root_node];
+        loop {
+            if node_manager.read_node(level[0])?.1.is_leaf() {
+                for &page_address in level.iter().skip(1).take(leaves.saturating_sub(1)) {
+                    node_manager.read_node(page_address)?;
+                }
+                return Ok(());
+            }
+            let mut next_level = Vec::new();
+            for &page_address in &level {
+                let node = node_manager.read_node(page_address)?;
+                let children = node.1.as_branch().ok_or_else(|| {
+                    Error::Corruption(format!("leaf at page {page_address} among branch nodes"))
+                })?;
+                next_level.extend(children.iter().map(|child| child.node_id.node_address()));
+            }
+            if next_level.is_empty() {
+                return Err(Error::Corruption(format!("empty branch node at page {}", level[0])).into());
+            }
+            level = next_level;
+        }
+    }
+
+    /// Copies all entries of latest committed transaction
+    /// into a new database file with the same page size.
+    pub fn compact(&self, destination: impl AsRef<Path>) -> Result<()> {
+        if std::fs::exists(destination.as_ref())? {
+            return Err(anyhow!(
+                "compaction destination already exists: {}",
+                destination.as_ref().display(),
+            ));
+        }
+
+        let compacted = Database::create(
+            destination,
+            Options {
+                page_size: self.internal.page_size,
+                ..Options::default()
+            },
+        )?;
+
+        let tx = self.begin_read();
+        let mut cursor = tx.cursor()?;
+        Self::copy_entries(&mut cursor, &compacted, Bound::Unbounded)?;
+        Ok(())
+    }
+
+    /// Creates new database with `options` and copies entries of latest committed
+    /// transaction with keys within `start` and `end` bounds into it, e.g. to split
+    /// database into shards. Returns number of copied entries.
+    pub fn copy_range(
+        &self,
+        destination: impl AsRef<Path>,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        options: Options,
+    ) -> Result<u64> {
+        let copy = Database::create(destination, options)?;
+        let tx = self.begin_read();
+        let mut cursor = tx.cursor()?;
+        match start {
+            Bound::Included(key) => cursor.seek(key)?,
+            Bound::Excluded(key) => cursor.seek_biased(key, SeekBias::Greater)?,
+            Bound::Unbounded => {}
+        }
+        Self::copy_entries(&mut cursor, &copy, end)
+    }
+
+    /// Puts entries from cursor position up to `end` into `destination`,
+    /// committing them in batches to bound size of write transactions.
+    fn copy_entries(cursor: &mut Cursor<'_>, destination: &Database, end: Bound<&[u8]>) -> Result<u64> {
+        const BATCH_LEN: u64 = 10_000;
+        let mut tx = destination.begin_write();
+        let mut copied = 0;
+        while cursor.is_valid() {
+            let in_range = match end {
+                Bound::Included(end) => cursor.key() <= end,
+                Bound::Excluded(end) => cursor.key() < end,
+                Bound::Unbounded => true,
+            };
+            if !in_range {
+                break;
+            }
+            tx.put_expiring(cursor.key(), cursor.value(), cursor.expires_at())?;
+            copied += 1;
+            if copied % BATCH_LEN == 0 {
+                tx.commit()?;
+                tx = destination.begin_write();
+            }
+            cursor.next_entry()?;
+        }
+        tx.commit()?;
+        Ok(copied)
+    }
+
+    /// Reads latest meta node checking that file is not truncated
+    /// and has expected page size.
+    fn read_checked_meta_node(file: &mut std::fs::File, page_size: u32) -> Result<MetaNode> {
+        let meta_node = Self::read_meta_node(file)?;
+        let file_size = file.metadata()?.len();
+        if file_size < meta_node.end_address {
+            return Err(Error::Truncated {
+                expected: meta_node.end_address,
+                actual: file_size,
+            }
+            .into());
+        }
+
+        if page_size != meta_node.page_size {
+            return Err(anyhow!(
+                "unexpected page size: {}. actual database page size is {}",
+                page_size,
+                meta_node.page_size,
+            ));
+        }
+        Ok(meta_node)
+    }
+
+    fn read_meta_node(file: &mut std::fs::File) -> Result<MetaNode> {
+        let file_size = file.metadata()?.len();
+        if file_size < 2 * MetaNode::page_size() {
+            return Err(Error::NotADatabase(file_size).into());
+        }
+        file.seek(std::io::SeekFrom::Start(0))?;
+        let meta_node0 = MetaNode::read(file);
+        file.seek(std::io::SeekFrom::Start(MetaNode::page_size()))?;
+        let meta_node1 = MetaNode::read(file);
+
+        match (meta_node0, meta_node1) {
+            (Ok(meta_node0), Ok(meta_node1)) => {
+                if meta_node0.transaction_id < meta_node1.transaction_id {
+                    Ok(meta_node1)
+                } else {
+                    Ok(meta_node0)
+                }
+            }
+            (Ok(meta_node0), _) => Ok(meta_node0),
+            (_, Ok(meta_node1)) => Ok(meta_node1),
+            (e @ Err(_), _) => e,
+        }
+    }
+}
+
 pub struct DatabaseInternal {
     pub node_manager: NodeManager,
     pub write_state: Mutex<Option<WriteState>>,
     pub write_state_condvar: Condvar,
     pub read_state: Mutex<ReadState>,
     pub page_size: u32,
+    pub options: Options,
+    pub(crate) metrics: Arc<Metrics>,
 }
 
 impl DatabaseInternal {
+    /// Returns current time of `Options::clock` in milliseconds.
+    pub fn now(&self) -> u64 {
+        match &self.options.clock {
+            Some(clock) => clock(),
+            None => unix_time_millis(),
+        }
+    }
+
     pub fn begin_write(self: &Arc<Self>) -> WriteTransaction {
-        let writer = self.take_write_state();
+        let writer = self
+            .take_write_state(None)
+            .expect("writer is taken when waiting without deadline");
         WriteTransaction::new(self.clone(), writer)
     }
 
+    pub fn begin_write_timeout(self: &Arc<Self>, timeout: Duration) -> Result<WriteTransaction> {
+        let writer = self
+            .take_write_state(Some(Instant::now() + timeout))
+            .ok_or(Error::WouldBlock)?;
+        Ok(WriteTransaction::new(self.clone(), writer))
+    }
+
+    pub fn try_begin_write(self: &Arc<Self>) -> Option<WriteTransaction> {
+        let writer = self.take_write_state(Some(Instant::now()))?;
+        Some(WriteTransaction::new(self.clone(), writer))
+    }
+
     pub fn begin_read(self: &Arc<Self>) -> ReadTransaction {
+        self.begin_read_with_meta().0
+    }
+
+    pub fn verify(self: &Arc<Self>) -> Result<Vec<Violation>> {
+        let (tx, meta_node) = self.begin_read_with_meta();
+        let (_, free_list) = self.node_manager.read_free_list(meta_node.free_list_node)?;
+        verify::verify(&tx, &meta_node, &free_list)
+    }
+
+    pub fn begin_read_with_meta(self: &Arc<Self>) -> (ReadTransaction, MetaNode) {
         let mut read_state_lock = self.read_state.lock().expect("read state lock");
-        let root_node = read_state_lock.meta_node.root_node;
-        let transaction_id = read_state_lock.meta_node.transaction_id;
+        let meta_node = read_state_lock.meta_node.clone();
         *read_state_lock.transactions
-            .entry(transaction_id)
+            .entry(meta_node.transaction_id)
             .or_default() += 1;
 
-        ReadTransaction::new(self.clone(), root_node, transaction_id)
+        let tx = ReadTransaction::new(self.clone(), meta_node.root_node, meta_node.transaction_id);
+        (tx, meta_node)
     }
 
-    pub fn take_write_state(&self) -> WriteState {
+    /// Takes writer token waiting for it until deadline if one is given.
+    pub fn take_write_state(&self, deadline: Option<Instant>) -> Option<WriteState> {
         let mut write_state_lock = self.write_state.lock().expect("writer lock");
         loop {
             if let Some(mut write_state) = write_state_lock.take() {
-                let read_state_lock = self.read_state.lock().expect("read state lock");
-                let min_transaction_id = read_state_lock
-                    .transactions
-                    .first_key_value()
-                    .map(|(transaction_id, _)| *transaction_id)
-                    .unwrap_or(TransactionId::MAX);
-
-                let max_transaction_id = read_state_lock
-                .transactions
-                .last_key_value()
-                .map(|(transaction_id, _)| *transaction_id)
-                .unwrap_or(0);
-
-                if min_transaction_id > 0 {
-                    let freed = write_state.free_list.release(min_transaction_id, max_transaction_id);
-                    self.node_manager.invalidate_nodes_cache(freed);
-                }
+                self.release_pages(&mut write_state);
+                return Some(write_state);
+            }
 
-                return write_state;
-            } else {
-                write_state_lock = self
+            write_state_lock = match deadline {
+                None => self
                     .write_state_condvar
                     .wait(write_state_lock)
-                    .expect("writer cond var");
-            }
+                    .expect("writer cond var"),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.write_state_condvar
+                        .wait_timeout(write_state_lock, deadline - now)
+                        .expect("writer cond var")
+                        .0
+                }
+            };
         }
     }
 
-    pub fn release_writer(&self, writer: WriteState) {
-        let mut write_state_lock = self.write_state.lock().expect("transaction state lock");
-        assert!(write_state_lock.is_none(), "there must be only one writer token");
+    fn release_pages(&self, write_state: &mut WriteState) {
         let mut read_state_lock = self.read_state.lock().expect("read state lock");
-        read_state_lock.meta_node = writer.meta().clone();
-        *write_state_lock = Some(writer);
-        self.write_state_condvar.notify_one();
+        let min_transaction_id = read_state_lock
+            .transactions
+            .first_key_value()
+            .map(|(transaction_id, _)| *transaction_id)
+            .unwrap_or(MAX_TRANSACTION_ID);
+
+        let max_transaction_id = read_state_lock
+            .transactions
+            .last_key_value()
+            .map(|(transaction_id, _)| *transaction_id)
+            .unwrap_or(0);
+
+        if min_transaction_id > 0 {
+            let freed = write_state.free_list.release(min_transaction_id, max_transaction_id);
+            if !freed.is_empty() {
+                self.trace(|| format!("released {} pages", freed.len()));
+            }
+            self.metrics.add_pages_released(freed.len() as u64);
+            self.node_manager.invalidate_nodes_cache(freed);
+        }
+        read_state_lock.pinned_pages = write_state.free_list.pending_pages_len() as u64;
     }
 
-    pub fn release_reader(&self, transaction_id: TransactionId) {
-        let mut read_state_lock = self.read_state.lock().expect("transaction state lock");
+    /// Reads writer state from file for `Database::reopen`, while writer token is held.
+    /// Publishes read meta node if no read transaction is open.
+    fn reload(&self) -> Result<WriteState> {
+        let mut file = std::fs::File::open(self.node_manager.file_path())?;
+        let meta_node = Database::read_checked_meta_node(&mut file, self.page_size)?;
+        // Free list is read from file bypassing nodes cache
+        let (free_list_header, free_list) = self.node_manager.read_free_list(meta_node.free_list_node)?;
+        let mut read_state_lock = self.read_state.lock().expect("read state lock");
+        if !read_state_lock.transactions.is_empty() {
+            return Err(Error::WouldBlock.into());
+        }
+        // Node at any address may have been rewritten by the other process
+        self.node_manager.invalidate_all_nodes();
+        let reloaded = WriteState {
+            free_list_header,
+            free_list,
+            free_list_node_address: meta_node.free_list_node,
+            meta_nodes: [meta_node.clone(), meta_node],
+        };
+        self.publish_locked(&mut read_state_lock, &reloaded);
+        Ok(reloaded)
+    }
+
+    pub fn release_writer(&self, writer: WriteState) {
+        let mut write_state_lock = self.write_state.lock().expect("transaction state lock");
+        assert!(write_state_lock.is_none(), "there must be only one writer token");
+        self.publish(&writer);
+        *write_state_lock = Some(writer);
+        self.write_state_condvar.notify_one();
+    }
+
+    /// Passes message to trace sink, formatting it only if sink is set.
+    pub(crate) fn trace(&self, message: impl FnOnce() -> String) {
+        if let Some(trace) = &self.options.trace {
+            trace(&message());
+        }
+    }
+
+    /// Makes latest transaction committed by writer visible to new readers.
+    pub fn publish(&self, writer: &WriteState) {
+        let mut read_state_lock = self.read_state.lock().expect("read state lock");
+        self.publish_locked(&mut read_state_lock, writer);
+    }
+
+    fn publish_locked(&self, read_state: &mut ReadState, writer: &WriteState) {
+        read_state.meta_node = writer.meta().clone();
+        read_state.pinned_pages = writer.free_list.pending_pages_len() as u64;
+    }
+
+    /// Commits transaction chained by `WriteTransaction::commit_and_begin`
+    /// and begins the next one keeping writer token.
+    pub(crate) fn begin_chained_write(self: &Arc<Self>, mut writer: WriteState) -> WriteTransaction {
+        self.publish(&writer);
+        self.release_pages(&mut writer);
+        WriteTransaction::new(self.clone(), writer)
+    }
+
+    pub fn release_reader(&self, transaction_id: TransactionId) {
+        let mut read_state_lock = self.read_state.lock().expect("transaction state lock");
         match read_state_lock.transactions.entry(transaction_id) {
-            Entry::Occupied(mut entry) => {
+            btree_map::Entry::Occupied(mut entry) => {
                 assert!(*entry.get() > 0, "missing read transaction_id entry");
                 if *entry.get() == 1 {
                     entry.remove();
@@ -279,7 +1034,7 @@ impl DatabaseInternal {
                     *entry.get_mut() -= 1;
                 }
             }
-            Entry::Vacant(_) => panic!("missing entries for transaction"),
+            btree_map::Entry::Vacant(_) => panic!("missing entries for transaction"),
         }
     }
 }
@@ -287,6 +1042,8 @@ impl DatabaseInternal {
 pub struct ReadState {
     pub meta_node: MetaNode,
     pub transactions: BTreeMap<TransactionId, usize>,
+    /// Freed pages which cannot be reused yet, as of last writer release.
+    pub pinned_pages: u64,
 }
 
 #[derive(Debug)]
@@ -314,3 +1071,1646 @@ impl WriteState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::OpenOptions, io::{Read, SeekFrom}};
+
+    use crate::test_utils::{self, key, open_database, value, Rng, TempPath};
+
+    #[test]
+    fn verifies_tree_after_churn() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        for round in 0..4 {
+            let mut tx = db.begin_write();
+            for i in 0..2000 {
+                tx.put(&key(i), &value(i * round)).unwrap();
+            }
+            tx.commit().unwrap();
+            assert_eq!(db.verify().unwrap(), Vec::new());
+
+            let mut tx = db.begin_write();
+            for i in (0..2000).filter(|i| i % 3 != round % 3) {
+                tx.remove(&key(i)).unwrap();
+            }
+            tx.commit().unwrap();
+            assert_eq!(db.verify().unwrap(), Vec::new());
+        }
+    }
+
+    #[test]
+    fn reports_separator_differing_from_first_key_of_child() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 1000..3000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        // Lower separator of the third child below its first key, but keep it
+        // above the last key of the second child so that key bounds still hold
+        let root_node = db.internal.begin_read_with_meta().1.root_node;
+        let node_manager = &db.internal.node_manager;
+        let mut root = node_manager.read_node(root_node).unwrap().1.clone();
+        let InternalNodes::Branch(ref mut children) = root else {
+            panic!("root must be a branch");
+        };
+        let previous_child = node_manager.read_node(children[1].node_id.node_address()).unwrap();
+        let previous_child = &previous_child.1;
+        let mut separator = previous_child.key_at(previous_child.len() - 1).to_vec();
+        separator.push(0);
+        let first_key = children[2].key.clone();
+        children[2].key = separator.clone();
+        node_manager.write_node(root_node, &root, 1).unwrap();
+        node_manager.invalidate_nodes_cache(vec![root_node]);
+
+        assert_eq!(
+            db.verify().unwrap(),
+            vec![Violation {
+                page_address: root_node,
+                description: format!(
+                    "separator {:?} at index 2 differs from first key {:?} of child",
+                    String::from_utf8_lossy(&separator),
+                    String::from_utf8_lossy(&first_key),
+                ),
+            }],
+        );
+    }
+
+    #[test]
+    fn reports_live_pages_in_free_list() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..10 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let meta = db.internal.begin_read_with_meta().1;
+        let node_manager = &db.internal.node_manager;
+        let (_, mut free_list) = node_manager.read_free_list(meta.free_list_node).unwrap();
+        free_list.free.insert(meta.root_node);
+        node_manager.write_free_list(meta.free_list_node, &free_list, meta.transaction_id).unwrap();
+
+        assert_eq!(
+            db.verify().unwrap(),
+            vec![Violation {
+                page_address: meta.root_node,
+                description: "page is referenced by the tree and is in the free list".to_string(),
+            }],
+        );
+    }
+
+    fn io_error_kind(err: &anyhow::Error) -> Option<std::io::ErrorKind> {
+        err.downcast_ref::<std::io::Error>().map(|err| err.kind())
+    }
+
+    #[test]
+    fn creates_and_opens_database() {
+        let path = TempPath::new();
+        let err = Database::open(&path, test_utils::options()).err().unwrap();
+        assert_eq!(io_error_kind(&err), Some(std::io::ErrorKind::NotFound));
+        assert!(!std::fs::exists(&path).unwrap());
+
+        let db = Database::create(&path, test_utils::options()).unwrap();
+        let mut tx = db.begin_write();
+        tx.put(&key(0), &value(0)).unwrap();
+        tx.commit().unwrap();
+        drop(db);
+
+        let err = Database::create(&path, test_utils::options()).err().unwrap();
+        assert_eq!(io_error_kind(&err), Some(std::io::ErrorKind::AlreadyExists));
+
+        let db = Database::open(&path, test_utils::options()).unwrap();
+        assert_eq!(db.begin_read().get(&key(0)).unwrap(), Some(value(0)));
+    }
+
+    #[test]
+    fn opens_or_creates_database() {
+        let path = TempPath::new();
+        let db = Database::open_or_create(&path, test_utils::options()).unwrap();
+        let mut tx = db.begin_write();
+        tx.put(&key(0), &value(0)).unwrap();
+        tx.commit().unwrap();
+        drop(db);
+
+        let db = Database::open_or_create(&path, test_utils::options()).unwrap();
+        assert_eq!(db.begin_read().get(&key(0)).unwrap(), Some(value(0)));
+    }
+
+    #[test]
+    fn opens_database_with_detected_page_size() {
+        let path = TempPath::new();
+        {
+            let db = Database::create(
+                &path,
+                Options {
+                    page_size: 8 << 10,
+                    ..Options::default()
+                },
+            )
+            .unwrap();
+            let mut tx = db.begin_write();
+            for i in 0..100 {
+                tx.put(&key(i), &value(i)).unwrap();
+            }
+            tx.commit().unwrap();
+        }
+
+        assert!(Database::open(&path, Options::default()).is_err());
+
+        let db = Database::open_auto(&path).unwrap();
+        assert_eq!(db.stats().unwrap().page_size, 8 << 10);
+        let tx = db.begin_read();
+        for i in 0..100 {
+            assert_eq!(tx.get(&key(i)).unwrap(), Some(value(i)));
+        }
+    }
+
+    #[test]
+    fn reports_stored_page_size() {
+        let path = TempPath::new();
+        let options = Options {
+            page_size: 8 << 10,
+            ..Options::default()
+        };
+        drop(Database::create(&path, options).unwrap());
+
+        let db = Database::open_auto(&path).unwrap();
+        assert_eq!(db.page_size(), 8 << 10);
+        assert_eq!(db.options().page_size, 8 << 10);
+        assert_eq!(db.options().max_files, Options::default().max_files);
+    }
+
+    #[test]
+    fn fails_to_detect_page_size_of_missing_database() {
+        let path = TempPath::new();
+        assert!(Database::open_auto(&path).is_err());
+        assert!(!std::fs::exists(&path).unwrap());
+    }
+
+    #[test]
+    fn rejects_file_without_magic() {
+        let path = TempPath::new();
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let data = (0..4096)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect::<Vec<_>>();
+        std::fs::write(&path, data).unwrap();
+
+        let err = Database::open(&path, test_utils::options()).err().unwrap();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::BadMagic));
+        let err = Database::open_auto(&path).err().unwrap();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::BadMagic));
+    }
+
+    #[test]
+    fn rejects_file_too_small_to_be_database() {
+        let path = TempPath::new();
+        std::fs::write(&path, []).unwrap();
+        let err = Database::open(&path, test_utils::options()).err().unwrap();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::NotADatabase(0)));
+        let err = Database::open_with_recovery(&path, test_utils::options()).err().unwrap();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::NotADatabase(0)));
+
+        let garbage = b"#!/bin/sh\necho garbage\n";
+        std::fs::write(&path, garbage).unwrap();
+        let err = Database::open_auto(&path).err().unwrap();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::NotADatabase(garbage.len() as u64)));
+
+        // Truncated in the middle of the second meta node
+        std::fs::remove_file(&path).unwrap();
+        drop(open_database(&path));
+        let size = MetaNode::page_size() + MetaNode::size() as u64 / 2;
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(size).unwrap();
+        let err = Database::open(&path, test_utils::options()).err().unwrap();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::NotADatabase(size)));
+    }
+
+    #[test]
+    fn checks_page_size_on_create() {
+        for page_size in [128, 1 << 10, 4 << 10, 64 << 10, 1 << 20] {
+            let path = TempPath::new();
+            let options = Options {
+                page_size,
+                ..test_utils::options()
+            };
+            let db = Database::create(&path, options).unwrap();
+            assert_eq!(db.page_size(), page_size);
+            let mut tx = db.begin_write();
+            tx.put(&key(0), &value(0)).unwrap();
+            tx.commit().unwrap();
+        }
+
+        for (page_size, message) in [
+            (0, "page size is too small: 0. must be at least 128"),
+            (64, "page size is too small: 64. must be at least 128"),
+            (2 << 20, "page size is too large: 2097152. must be at most 1048576"),
+            (100 << 3, "page size must be a power of two: 800"),
+            (4097, "page size must be a power of two: 4097"),
+        ] {
+            let path = TempPath::new();
+            let options = Options {
+                page_size,
+                ..test_utils::options()
+            };
+            let err = Database::create(&path, options).err().unwrap();
+            assert_eq!(err.to_string(), message);
+            assert!(!std::fs::exists(&path).unwrap());
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let path = TempPath::new();
+        let options = test_utils::options().growth_policy(GrowthPolicy::Fixed(0));
+        let db = Database::create(&path, options.clone()).unwrap();
+        let mut tx = db.begin_write();
+        for i in 1000..3000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        let end_address = db.internal.begin_read_with_meta().1.end_address;
+        drop(db);
+
+        let size = std::fs::metadata(&path).unwrap().len() / 2;
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(size).unwrap();
+        let err = Database::open(&path, options.clone()).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::Truncated {
+                expected: end_address,
+                actual: size,
+            })
+        );
+        let err = Database::open_with_recovery(&path, options).err().unwrap();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Truncated { .. })), "{err:#}");
+    }
+
+    #[test]
+    fn reports_meta_node_checksum_mismatch() {
+        let path = TempPath::new();
+        drop(open_database(&path));
+        // Clobber checksums stored at the end of both meta nodes
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        for meta_address in [0, MetaNode::page_size()] {
+            file.seek(SeekFrom::Start(meta_address + MetaNode::size() as u64 - 4)).unwrap();
+            file.write_all(&[0xFF; 4]).unwrap();
+        }
+        drop(file);
+
+        let err = Database::open(&path, test_utils::options()).err().unwrap();
+        let Some(Error::Corruption(description)) = err.downcast_ref::<Error>() else {
+            panic!("unexpected error: {err:#}");
+        };
+        assert!(description.starts_with("meta node checksum mismatch"), "{description}");
+    }
+
+    #[test]
+    fn reports_address_of_corrupted_page() {
+        let path = TempPath::new();
+        let meta = {
+            let db = test_utils::open_database(&path);
+            let mut tx = db.begin_write();
+            for i in 0..10 {
+                tx.put(&key(i), &value(i)).unwrap();
+            }
+            tx.commit().unwrap();
+            db.internal.begin_read_with_meta().1
+        };
+
+        // Clobber node flags of the root page
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(meta.root_node)).unwrap();
+        file.write_all(&7u16.to_be_bytes()).unwrap();
+        drop(file);
+
+        let db = test_utils::open_database(&path);
+        let err = db.begin_read().get(&key(0)).err().unwrap();
+        let message = format!("{err:#}");
+        assert!(
+            message.starts_with(&format!("corruption at page {}: ", meta.root_node))
+                && message.contains("checksum mismatch"),
+            "{message}"
+        );
+        // Error of read shared through nodes cache keeps its type
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Corruption(_))), "{err:#}");
+        let err = db.begin_read().get(&key(0)).err().unwrap();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Corruption(_))), "{err:#}");
+        assert_eq!(format!("{err:#}"), message);
+
+        // Clobber node flags of the free list page
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(meta.free_list_node)).unwrap();
+        file.write_all(&[0xFF; 32]).unwrap();
+        drop(file);
+
+        let err = Database::open(&path, test_utils::options()).err().unwrap();
+        assert_eq!(
+            format!("{err:#}"),
+            format!(
+                "corruption at page {}: database is corrupted: overflow length {} exceeds {} bytes left in file",
+                meta.free_list_node,
+                u64::MAX,
+                std::fs::metadata(&path).unwrap().len() - meta.free_list_node
+            )
+        );
+    }
+
+    #[test]
+    fn skips_checksum_verification_of_reads() {
+        let path = TempPath::new();
+        let meta = {
+            let db = test_utils::open_database(&path);
+            let mut tx = db.begin_write();
+            for i in 0..10 {
+                tx.put(&key(i), &value(i)).unwrap();
+            }
+            tx.commit().unwrap();
+            db.internal.begin_read_with_meta().1
+        };
+
+        // Flip byte of a value stored in the root leaf
+        let mut page = vec![0; 1 << 10];
+        let mut file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(meta.root_node)).unwrap();
+        file.read_exact(&mut page).unwrap();
+        let offset = page.windows(6).position(|window| window == b"value5").unwrap();
+        file.seek(SeekFrom::Start(meta.root_node + offset as u64)).unwrap();
+        file.write_all(b"V").unwrap();
+        drop(file);
+
+        // Corrupted page is read as is, which is the tradeoff of skipping verification
+        let db = Database::open(&path, test_utils::options().checksum_reads(false)).unwrap();
+        assert_eq!(db.begin_read().get(&key(5)).unwrap(), Some(b"Value5".to_vec()));
+        assert_eq!(db.begin_read().get(&key(6)).unwrap(), Some(value(6)));
+        drop(db);
+
+        let db = Database::open(&path, test_utils::options().checksum_reads(true)).unwrap();
+        let err = db.begin_read().get(&key(5)).unwrap_err();
+        assert!(format!("{err:#}").contains("checksum mismatch"), "{err:#}");
+    }
+
+    #[test]
+    fn opens_database_with_builder() {
+        let path = TempPath::new();
+        assert!(Database::builder(&path).open().is_err());
+
+        let db = Database::builder(&path)
+            .page_size(2 << 10)
+            .cache_size(1 << 20)
+            .max_files(2)
+            .create_if_missing(true)
+            .open()
+            .unwrap();
+        assert_eq!(db.page_size(), 2 << 10);
+        assert_eq!(db.options().cache_size, 1 << 20);
+        assert_eq!(db.options().max_files, 2);
+        assert!(!db.options().read_only);
+        db.write(|tx| tx.put(&key(1), &value(1))).unwrap();
+        drop(db);
+
+        // Page size stored in file is picked up when not set
+        let db = Database::builder(&path).read_only(true).open().unwrap();
+        assert_eq!(db.page_size(), 2 << 10);
+        assert!(db.options().read_only);
+        assert_eq!(db.begin_read().get(&key(1)).unwrap(), Some(value(1)));
+        let mut tx = db.begin_write();
+        tx.put(&key(2), &value(2)).unwrap();
+        let err = tx.commit().unwrap_err();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::ReadOnly)), "{err:#}");
+        assert_eq!(db.begin_read().get(&key(2)).unwrap(), None);
+
+        // Read-only database is never created, even if file exists
+        let missing = TempPath::new();
+        for path in [missing.as_ref(), path.as_ref()] {
+            let err = Database::builder(path)
+                .read_only(true)
+                .create_if_missing(true)
+                .open()
+                .err()
+                .unwrap();
+            assert!(matches!(err.downcast_ref::<Error>(), Some(Error::ReadOnly)), "{err:#}");
+        }
+        assert!(!std::fs::exists(&missing).unwrap());
+        let options = Options {
+            read_only: true,
+            ..test_utils::options()
+        };
+        assert!(Database::open_or_create(&missing, options).is_err());
+        assert!(!std::fs::exists(&missing).unwrap());
+
+        // Existing file is opened with page size stored in it
+        let db = Database::builder(&path).create_if_missing(true).open().unwrap();
+        assert_eq!(db.page_size(), 2 << 10);
+        assert_eq!(db.begin_read().get(&key(1)).unwrap(), Some(value(1)));
+        drop(db);
+        let options = Options {
+            page_size: 2 << 10,
+            ..test_utils::options()
+        };
+        let db = Database::open_or_create(&path, options).unwrap();
+        assert_eq!(db.begin_read().get(&key(1)).unwrap(), Some(value(1)));
+        drop(db);
+
+        let Err(err) = Database::builder(&path).page_size(1 << 10).open() else {
+            panic!("opened with mismatching page size");
+        };
+        assert!(format!("{err:#}").contains("page size"), "{err:#}");
+    }
+
+    #[test]
+    fn recovers_database_without_meta_nodes() {
+        let path = TempPath::new();
+        {
+            let db = open_database(&path);
+            for round in 0..10 {
+                let mut tx = db.begin_write();
+                for i in 0..200 {
+                    tx.put(&key(round * 200 + i), &value(i)).unwrap();
+                }
+                tx.remove(&key(round)).unwrap();
+                tx.commit().unwrap();
+            }
+        }
+
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all(&vec![0; 2 * MetaNode::page_size() as usize]).unwrap();
+        drop(file);
+        assert!(Database::open(&path, test_utils::options()).is_err());
+
+        // Read-only open leaves file untouched
+        let contents = std::fs::read(&path).unwrap();
+        let err = Database::open_with_recovery(&path, test_utils::options().read_only(true))
+            .err()
+            .unwrap();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::ReadOnly)), "{err:#}");
+        assert!(std::fs::read(&path).unwrap() == contents);
+
+        let db = Database::open_with_recovery(&path, test_utils::options()).unwrap();
+        assert!(db.verify().unwrap().is_empty());
+        let tx = db.begin_read();
+        for i in 0..2000 {
+            let expected = (i >= 10).then(|| value(i % 200));
+            assert_eq!(tx.get(&key(i)).unwrap(), expected);
+        }
+        drop(tx);
+
+        // Recovered database accepts writes and reopens normally
+        let mut tx = db.begin_write();
+        for i in 0..2000 {
+            tx.put(&key(i), &value(0)).unwrap();
+        }
+        tx.commit().unwrap();
+        drop(db);
+        let db = Database::open(&path, test_utils::options()).unwrap();
+        assert_eq!(db.stats().unwrap().keys, 2000);
+        assert!(db.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn recovers_root_of_latest_transaction() {
+        let path = TempPath::new();
+        {
+            let db = open_database(&path);
+            let mut tx = db.begin_write();
+            for i in 0..2000 {
+                tx.put(&key(i), &value(i)).unwrap();
+            }
+            tx.commit().unwrap();
+            // Root of the previous commit with more entries stays in free pages
+            let mut tx = db.begin_write();
+            for i in 200..2000 {
+                tx.remove(&key(i)).unwrap();
+            }
+            tx.commit().unwrap();
+        }
+
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all(&vec![0; 2 * MetaNode::page_size() as usize]).unwrap();
+        drop(file);
+
+        let db = Database::open_with_recovery(&path, test_utils::options()).unwrap();
+        assert!(db.verify().unwrap().is_empty());
+        assert_eq!(db.stats().unwrap().keys, 200);
+        let tx = db.begin_read();
+        assert_eq!(tx.get(&key(199)).unwrap(), Some(value(199)));
+        assert_eq!(tx.get(&key(200)).unwrap(), None);
+    }
+
+    #[test]
+    fn skips_nodes_spilled_by_uncommitted_transaction_on_recovery() {
+        let path = TempPath::new();
+        {
+            let options = Options {
+                spill_threshold: Some(4 << 10),
+                ..test_utils::options()
+            };
+            let db = Database::open_or_create(&path, options).unwrap();
+            let mut tx = db.begin_write();
+            for i in 0..200 {
+                tx.put(&key(i), &value(i)).unwrap();
+            }
+            tx.commit().unwrap();
+            // Spilled nodes are newer than the last commit and head consistent subtrees
+            let mut tx = db.begin_write();
+            for i in 200..2000 {
+                tx.put(&key(i), &value(i)).unwrap();
+            }
+            drop(tx);
+        }
+
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all(&vec![0; 2 * MetaNode::page_size() as usize]).unwrap();
+        drop(file);
+
+        let db = Database::open_with_recovery(&path, test_utils::options()).unwrap();
+        assert!(db.verify().unwrap().is_empty());
+        assert_eq!(db.stats().unwrap().keys, 200);
+    }
+
+    #[test]
+    fn rejects_unsupported_format_version() {
+        let path = TempPath::new();
+        drop(open_database(&path));
+
+        let mut data = std::fs::read(&path).unwrap();
+        let version = (node::FORMAT_VERSION + 1).to_be_bytes();
+        for meta_page in 0..2 {
+            let offset = (meta_page * MetaNode::page_size()) as usize + node::MAGIC.len();
+            data[offset..offset + 2].copy_from_slice(&version);
+        }
+        std::fs::write(&path, data).unwrap();
+
+        let err = Database::open(&path, test_utils::options()).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::UnsupportedVersion(node::FORMAT_VERSION + 1)),
+        );
+    }
+
+    #[test]
+    fn failed_commit_restores_writer_state() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..100 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        // Let writer reclaim pages freed by previous commit
+        drop(db.begin_write());
+
+        let (meta_before, free_before) = {
+            let write_state = db.internal.write_state.lock().unwrap();
+            let write_state = write_state.as_ref().unwrap();
+            (write_state.meta().clone(), write_state.free_list.free.clone())
+        };
+
+        let mut tx = db.begin_write();
+        for i in 100..300 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        db.internal.node_manager.fail_writes.store(true, std::sync::atomic::Ordering::Relaxed);
+        assert!(tx.commit().is_err());
+        db.internal.node_manager.fail_writes.store(false, std::sync::atomic::Ordering::Relaxed);
+
+        {
+            let write_state = db.internal.write_state.lock().unwrap();
+            let write_state = write_state.as_ref().unwrap();
+            let meta = write_state.meta();
+            assert_eq!(meta.root_node, meta_before.root_node);
+            assert_eq!(meta.transaction_id, meta_before.transaction_id);
+            assert_eq!(meta.end_address, meta_before.end_address);
+            assert_eq!(write_state.free_list.free, free_before);
+            assert!(write_state.free_list.pending_allocated.is_empty());
+            assert!(!write_state
+                .free_list
+                .allocated_by
+                .values()
+                .any(|transaction_id| *transaction_id > meta.transaction_id));
+        }
+
+        let mut tx = db.begin_write();
+        for i in 100..300 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        assert_eq!(db.verify().unwrap(), Vec::new());
+        let tx = db.begin_read();
+        for i in 0..300 {
+            assert_eq!(tx.get(&key(i)).unwrap(), Some(value(i)));
+        }
+    }
+
+    #[test]
+    fn fails_to_commit_past_last_transaction_id() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut writer = db.internal.take_write_state(None).unwrap();
+        writer.meta_mut().transaction_id = MAX_TRANSACTION_ID - 2;
+        db.internal.release_writer(writer);
+
+        let mut tx = db.begin_write();
+        tx.put(&key(1), &value(1)).unwrap();
+        assert_eq!(tx.transaction_id(), MAX_TRANSACTION_ID - 1);
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        assert_eq!(tx.transaction_id(), MAX_TRANSACTION_ID);
+        tx.put(&key(2), &value(2)).unwrap();
+        let err = tx.commit().unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::TransactionIdsExhausted));
+
+        // Empty transactions do not take ids, and committed state stays readable
+        db.begin_write().commit().unwrap();
+        let tx = db.begin_read();
+        assert_eq!(tx.get(&key(1)).unwrap(), Some(value(1)));
+        assert_eq!(tx.get(&key(2)).unwrap(), None);
+        assert_eq!(db.verify().unwrap(), vec![]);
+        drop(tx);
+        let meta = db.internal.begin_read_with_meta().1;
+        assert_eq!(meta.transaction_id, MAX_TRANSACTION_ID - 1);
+    }
+
+    #[test]
+    fn times_out_waiting_for_writer() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let tx = db.begin_write();
+
+        let handle = std::thread::spawn({
+            let db = db.clone();
+            move || {
+                let timeout = Duration::from_millis(50);
+                let started = Instant::now();
+                let err = db.begin_write_timeout(timeout).err().expect("writer must be busy");
+                let elapsed = started.elapsed();
+                assert_eq!(err.downcast_ref::<Error>(), Some(&Error::WouldBlock));
+                assert!(elapsed >= timeout, "returned too early: {elapsed:?}");
+                assert!(elapsed < Duration::from_secs(5), "returned too late: {elapsed:?}");
+            }
+        });
+        handle.join().unwrap();
+
+        drop(tx);
+        let mut tx = db.begin_write_timeout(Duration::from_millis(50)).unwrap();
+        tx.put(&key(0), &value(0)).unwrap();
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn tries_to_begin_write() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        tx.put(&key(0), &value(0)).unwrap();
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        tx.remove(&key(0)).unwrap();
+        assert!(db.try_begin_write().is_none());
+        tx.commit().unwrap();
+
+        // Pages freed by previous commit are reclaimed when writer is taken
+        drop(db.try_begin_write().expect("writer must be available"));
+        let write_state = db.internal.write_state.lock().unwrap();
+        let free_list = &write_state.as_ref().unwrap().free_list;
+        assert!(free_list.pending_free.is_empty());
+        assert!(!free_list.free.is_empty());
+    }
+
+    #[test]
+    fn reports_pages_pinned_by_reader() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..100 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        assert_eq!(db.oldest_reader(), None);
+
+        let reader = db.begin_read();
+        let reader_transaction_id = db.oldest_reader().expect("open reader");
+        let newer_reader = {
+            let mut tx = db.begin_write();
+            tx.put(&key(0), &value(1)).unwrap();
+            tx.commit().unwrap();
+            db.begin_read()
+        };
+        assert_eq!(db.oldest_reader(), Some(reader_transaction_id));
+
+        for i in 0..100 {
+            let mut tx = db.begin_write();
+            tx.remove(&key(i)).unwrap();
+            tx.commit().unwrap();
+        }
+        // Pages of snapshots seen by open readers stay pinned
+        drop(db.begin_write());
+        assert!(db.pinned_page_count() > 0);
+
+        drop(reader);
+        drop(newer_reader);
+        assert_eq!(db.oldest_reader(), None);
+        drop(db.begin_write());
+        assert_eq!(db.pinned_page_count(), 0);
+    }
+
+    #[test]
+    fn reuses_pages_unpinned_during_transaction() {
+        let path = TempPath::new();
+        let options = test_utils::options().growth_policy(GrowthPolicy::Fixed(0));
+        let db = Database::create(&path, options).unwrap();
+        let mut tx = db.begin_write();
+        for i in 0..100 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.put(b"large", &[1; 6000]).unwrap();
+        tx.commit().unwrap();
+
+        // Pages of removed value stay pinned by reader when next transaction begins
+        let reader = db.begin_read();
+        let mut tx = db.begin_write();
+        tx.remove(b"large").unwrap();
+        tx.commit().unwrap();
+        let mut tx = db.begin_write();
+        let end_address = db.internal.begin_read_with_meta().1.end_address;
+        let file_len = std::fs::metadata(&path).unwrap().len();
+
+        drop(reader);
+        tx.put(b"other", &[2; 6000]).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(db.internal.begin_read_with_meta().1.end_address, end_address);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), file_len);
+        assert_eq!(db.begin_read().get(b"other").unwrap(), Some(vec![2; 6000]));
+        assert_eq!(db.verify().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn shares_snapshot_between_threads() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..100 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let snapshot = db.snapshot();
+        let mut tx = db.begin_write();
+        for i in 0..100 {
+            tx.remove(&key(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let handles = (0..4)
+            .map(|_| {
+                let snapshot = snapshot.clone();
+                std::thread::spawn(move || {
+                    let keys = test_utils::collect(&mut snapshot.cursor().unwrap());
+                    assert_eq!(keys.len(), 100);
+                    assert_eq!(snapshot.get(&key(42)).unwrap(), Some(value(42)));
+                    snapshot
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut clones = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>();
+
+        let transaction_id = snapshot.transaction_id();
+        drop(snapshot);
+        while let Some(clone) = clones.pop() {
+            assert_eq!(db.oldest_reader(), Some(transaction_id));
+            drop(db.begin_write());
+            assert!(db.pinned_page_count() > 0);
+            drop(clone);
+        }
+
+        assert_eq!(db.oldest_reader(), None);
+        drop(db.begin_write());
+        assert_eq!(db.pinned_page_count(), 0);
+    }
+
+    #[test]
+    fn commits_write_transaction_on_another_thread() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let tx = db.begin_write();
+        std::thread::spawn(move || {
+            let mut tx = tx;
+            for i in 0..100 {
+                tx.put(&key(i), &value(i)).unwrap();
+            }
+            tx.commit().unwrap();
+        })
+        .join()
+        .unwrap();
+
+        let tx = db.begin_read();
+        assert_eq!(tx.count_prefix(b"").unwrap(), 100);
+    }
+
+    #[test]
+    fn reads_consistent_generations_while_writer_commits() {
+        const KEYS: usize = 200;
+        const GENERATIONS: u64 = 60;
+        let path = TempPath::new();
+        let db = open_database(&path);
+        // Values grow with generation, so nodes keep splitting under readers
+        let generation_value = |i: usize, generation: u64| {
+            format!("{generation}:").repeat(1 + (i + generation as usize) % 8).into_bytes()
+        };
+        let parse_generation = |value: &[u8]| -> u64 {
+            let value = std::str::from_utf8(value).unwrap();
+            let (generation, _) = value.split_once(':').unwrap();
+            generation.parse().unwrap()
+        };
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            let readers = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut last_generation = 0;
+                        let mut reads = 0;
+                        while !done.load(std::sync::atomic::Ordering::Relaxed) || reads == 0 {
+                            let tx = db.begin_read();
+                            let entries = test_utils::collect(&mut tx.cursor().unwrap());
+                            reads += 1;
+                            if entries.is_empty() {
+                                continue;
+                            }
+                            assert_eq!(entries.len(), KEYS);
+                            let generation = parse_generation(&entries[0].1);
+                            assert!(generation >= last_generation, "{generation} < {last_generation}");
+                            for i in 0..KEYS {
+                                let value = tx.get(&key(i)).unwrap().unwrap();
+                                assert_eq!(value, generation_value(i, generation), "key {i}");
+                            }
+                            for (k, v) in &entries {
+                                assert_eq!(parse_generation(v), generation, "{}", String::from_utf8_lossy(k));
+                            }
+                            last_generation = generation;
+                        }
+                        reads
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for generation in 1..=GENERATIONS {
+                let mut tx = db.begin_write();
+                for i in 0..KEYS {
+                    tx.put(&key(i), &generation_value(i, generation)).unwrap();
+                }
+                tx.commit().unwrap();
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+            for reader in readers {
+                assert!(reader.join().unwrap() > 0);
+            }
+        });
+        assert_eq!(db.verify().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn warms_up_branch_nodes() {
+        let path = TempPath::new();
+        {
+            let db = Database::create(&path, test_utils::options().max_keys_per_node(Some(4))).unwrap();
+            let mut tx = db.begin_write();
+            for i in 0..500 {
+                tx.put(&key(i), &value(i)).unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        let reads_per_lookup = |db: &Database, keys: &mut dyn Iterator<Item = usize>| {
+            let tx = db.begin_read();
+            keys.map(|i| {
+                let reads = db.metrics().node_reads;
+                assert_eq!(tx.get(&key(i)).unwrap(), Some(value(i)));
+                db.metrics().node_reads - reads
+            })
+            .collect::<Vec<_>>()
+        };
+
+        // Cold lookup reads every level
+        let db = Database::open(&path, test_utils::options()).unwrap();
+        let cold = reads_per_lookup(&db, &mut std::iter::once(250));
+        assert!(cold[0] >= 4, "{cold:?}");
+        drop(db);
+
+        // Lookups in distinct leaves read only the leaf itself
+        let db = Database::open(&path, test_utils::options()).unwrap();
+        db.warmup(0).unwrap();
+        let warm = reads_per_lookup(&db, &mut (0..500).step_by(50));
+        assert_eq!(warm[0], 0, "leftmost leaf is cached by warmup");
+        assert!(warm.iter().all(|&reads| reads <= 1), "{warm:?}");
+        assert!(warm.contains(&1), "{warm:?}");
+        drop(db);
+
+        let db = Database::open(&path, test_utils::options()).unwrap();
+        db.warmup(usize::MAX).unwrap();
+        let warm = reads_per_lookup(&db, &mut (0..500).step_by(7));
+        assert!(warm.iter().all(|&reads| reads == 0), "{warm:?}");
+    }
+
+    #[test]
+    fn verifies_shrinking_database_in_paranoid_mode() {
+        const LEN: usize = 150;
+        const BATCH_LEN: usize = 80;
+        let path = TempPath::new();
+        for _ in 0..2 {
+            let options = Options {
+                paranoid: true,
+                ..test_utils::options()
+            };
+            let db = Database::open_or_create(&path, options).unwrap();
+            let mut tx = db.begin_write();
+            for i in 0..LEN {
+                tx.put(&key(i), &value(i)).unwrap();
+                if i.is_multiple_of(BATCH_LEN) {
+                    tx.commit().unwrap();
+                    tx = db.begin_write();
+                }
+            }
+            tx.commit().unwrap();
+
+            let mut tx = db.begin_write();
+            for i in 0..LEN {
+                tx.remove(&key(i)).unwrap();
+                if i.is_multiple_of(BATCH_LEN) {
+                    tx.commit().unwrap();
+                    tx = db.begin_write();
+                }
+            }
+            tx.commit().unwrap();
+            assert_eq!(db.begin_read().first_key().unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn readers_see_consistent_snapshots_while_writer_commits() {
+        const KEYS_LEN: usize = 400;
+        const ROUNDS: usize = 40;
+        const READERS: usize = 4;
+
+        // Round writes its number to every key and keeps either half or all of the keys
+        fn round_keys_len(round: usize) -> usize {
+            KEYS_LEN / 2 + (round % 2) * KEYS_LEN / 2
+        }
+
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..round_keys_len(0) {
+            tx.put(&key(i), &0usize.to_be_bytes()).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let readers = (0..READERS)
+            .map(|_| {
+                let db = db.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    let mut snapshots = 0;
+                    while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                        let tx = db.begin_read();
+                        let entries = test_utils::collect(&mut tx.cursor().unwrap());
+                        let round = usize::from_be_bytes(entries[0].1.as_slice().try_into().unwrap());
+                        assert_eq!(entries.len(), round_keys_len(round), "round {round}");
+                        for (key, value) in entries.iter() {
+                            assert_eq!(value.as_slice(), round.to_be_bytes(), "key {key:?}");
+                        }
+                        snapshots += 1;
+                    }
+                    snapshots
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for round in 1..=ROUNDS {
+            let mut tx = db.begin_write();
+            for i in 0..KEYS_LEN {
+                if i < round_keys_len(round) {
+                    tx.put(&key(i), &round.to_be_bytes()).unwrap();
+                } else {
+                    tx.remove(&key(i)).unwrap();
+                }
+            }
+            tx.commit().unwrap();
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+
+        for reader in readers {
+            assert!(reader.join().unwrap() > 0);
+        }
+        assert!(db.verify().unwrap().is_empty());
+        assert_eq!(db.oldest_reader(), None);
+    }
+
+    #[test]
+    fn reopens_database_changed_out_of_band() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        tx.put(&key(1), &value(1)).unwrap();
+        tx.commit().unwrap();
+        // Cache root node of the current tree
+        assert_eq!(db.begin_read().get(&key(1)).unwrap(), Some(value(1)));
+
+        {
+            let other = open_database(&path);
+            let mut tx = other.begin_write();
+            tx.remove(&key(1)).unwrap();
+            tx.put(&key(2), &value(2)).unwrap();
+            tx.commit().unwrap();
+        }
+        let tx = db.begin_read();
+        assert_eq!(tx.get(&key(2)).unwrap(), None);
+
+        // Open reader may read pages reused by the other process
+        let err = db.reopen().err().unwrap();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::WouldBlock));
+        assert_eq!(tx.get(&key(1)).unwrap(), Some(value(1)));
+        drop(tx);
+
+        let tx_writer = db.begin_write();
+        let err = db.reopen().err().unwrap();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::WouldBlock));
+        drop(tx_writer);
+
+        let clone = db.clone();
+        db.reopen().unwrap();
+        // Clones share refreshed state
+        assert_eq!(clone.begin_read().get(&key(2)).unwrap(), Some(value(2)));
+        let tx = db.begin_read();
+        assert_eq!(tx.get(&key(1)).unwrap(), None);
+        assert_eq!(tx.get(&key(2)).unwrap(), Some(value(2)));
+        let mut tx = db.begin_write();
+        tx.put(&key(3), &value(3)).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(db.key_count(), 2);
+        assert!(db.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rolls_back_panicking_write() {
+        let path = TempPath::new();
+        let options = Options {
+            spill_threshold: Some(4096),
+            ..test_utils::options()
+        };
+        let db = Database::create(&path, options).unwrap();
+        db.write(|tx| {
+            for i in 100..200 {
+                tx.put(&key(i), &value(i))?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let end_address = db.internal.begin_read_with_meta().1.end_address;
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            db.write::<()>(|tx| {
+                for i in 200..2000 {
+                    tx.put(&key(i), &value(i))?;
+                }
+                panic!("write failed");
+            })
+        }));
+        assert!(result.is_err());
+        let err = db.write(|tx| tx.put(&key(0), b"").and(Err::<(), _>(anyhow!("abort"))));
+        assert_eq!(err.unwrap_err().to_string(), "abort");
+
+        let writer_end_address = {
+            let write_state = db.internal.write_state.lock().unwrap();
+            write_state.as_ref().expect("writer must be released").meta().end_address
+        };
+        assert_eq!(writer_end_address, end_address);
+        let mut tx = db.try_begin_write().unwrap();
+        tx.put(&key(2000), &value(2000)).unwrap();
+        tx.commit().unwrap();
+        let tx = db.begin_read();
+        assert_eq!(tx.get(&key(0)).unwrap(), None);
+        assert_eq!(tx.get(&key(500)).unwrap(), None);
+        assert_eq!(tx.count_prefix(b"").unwrap(), 101);
+        assert!(db.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn restores_free_list_when_uncommitted_transaction_is_dropped() {
+        let path = TempPath::new();
+        let options = Options {
+            spill_threshold: Some(4096),
+            ..test_utils::options()
+        };
+        let db = Database::create(&path, options).unwrap();
+        for round in 0..3 {
+            db.write(|tx| {
+                for i in 0..300 {
+                    tx.put(&key(round * 1000 + i), &value(i))?;
+                }
+                tx.remove(&key(round * 1000))
+            })
+            .unwrap();
+        }
+        let writer_state = || {
+            let write_state = db.internal.write_state.lock().unwrap();
+            let write_state = write_state.as_ref().expect("writer must be released");
+            let free_list = &write_state.free_list;
+            (
+                free_list.free.clone(),
+                free_list.allocated_by.clone(),
+                free_list.pending_allocated.clone(),
+                free_list.pending_free.clone(),
+                write_state.meta().end_address,
+            )
+        };
+        // Beginning a transaction releases pages freed by previous ones
+        drop(db.begin_write());
+        let expected = writer_state();
+
+        // Spilled pages are allocated before transaction is dropped
+        let mut tx = db.begin_write();
+        for i in 5000..7000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        assert!(db.metrics().pages_allocated > 0);
+        drop(tx);
+        assert_eq!(writer_state(), expected);
+
+        // Explicitly rolled back transaction is not rolled back again
+        let rollbacks = db.metrics().rollbacks;
+        let mut tx = db.begin_write();
+        tx.put(&key(1), &value(1)).unwrap();
+        tx.rollback().unwrap();
+        drop(tx);
+        assert_eq!(db.metrics().rollbacks, rollbacks + 1);
+        assert_eq!(writer_state(), expected);
+
+        // Transaction written to after rollback is rolled back on drop
+        let mut tx = db.begin_write();
+        tx.put(&key(1), &value(1)).unwrap();
+        tx.rollback().unwrap();
+        tx.put(&key(2), &value(2)).unwrap();
+        drop(tx);
+        assert_eq!(db.metrics().rollbacks, rollbacks + 3);
+        assert_eq!(writer_state(), expected);
+
+        // Rolling back to savepoint cleans root, but pages spilled since are still allocated
+        let mut tx = db.begin_write();
+        let savepoint = tx.savepoint();
+        for i in 5000..7000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.rollback_to(savepoint).unwrap();
+        assert!(tx.is_empty());
+        drop(tx);
+        assert_eq!(db.metrics().rollbacks, rollbacks + 4);
+        assert_eq!(writer_state(), expected);
+
+        // Same when such transaction is committed
+        let mut tx = db.begin_write();
+        let savepoint = tx.savepoint();
+        for i in 5000..7000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.rollback_to(savepoint).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(writer_state(), expected);
+        assert!(db.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn traces_restructuring() {
+        let path = TempPath::new();
+        let messages = Arc::new(Mutex::new(Vec::<String>::new()));
+        let sink = messages.clone();
+        let options = test_utils::options().trace(Arc::new(move |message: &str| {
+            sink.lock().unwrap().push(message.to_string());
+        }));
+        let db = Database::create(&path, options).unwrap();
+        db.write(|tx| {
+            for i in 1000..3000 {
+                tx.put(&key(i), &value(i))?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        let has_message = |prefix: &str| {
+            messages
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|message| message.starts_with(prefix))
+        };
+        assert!(has_message("split node"));
+        assert!(has_message("committed transaction 2"));
+        assert!(!has_message("merging node"));
+
+        db.write(|tx| {
+            for i in (1000..3000).filter(|i| i % 10 != 0) {
+                tx.remove(&key(i))?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert!(has_message("merging node"));
+
+        messages.lock().unwrap().clear();
+        let tx = db.begin_write();
+        tx.traverse();
+        assert!(has_message("Branch {"));
+
+        // Messages are not even formatted without sink
+        let db = open_database(&path);
+        db.internal.trace(|| panic!("trace is disabled"));
+        db.begin_write().traverse();
+    }
+
+    #[test]
+    fn rejects_out_of_order_logs() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let transaction_id = db.begin_write().transaction_id();
+        let ops = [LoggedOp::Put(key(1), value(1))];
+
+        assert!(db.apply_log(transaction_id + 1, &ops).is_err());
+        db.apply_log(transaction_id, &ops).unwrap();
+        assert!(db.apply_log(transaction_id, &ops).is_err());
+        db.apply_log(transaction_id + 1, &[LoggedOp::Delete(key(1))]).unwrap();
+
+        assert_eq!(db.begin_read().get(&key(1)).unwrap(), None);
+        assert_eq!(db.begin_write().transaction_id(), transaction_id + 2);
+    }
+
+    #[test]
+    fn counts_keys() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        assert_eq!(db.key_count(), 0);
+        let mut rng = Rng::new(7);
+        let mut expected = std::collections::BTreeSet::new();
+        for _ in 0..20 {
+            let mut tx = db.begin_write();
+            for _ in 0..200 {
+                let i = rng.below(1000) as usize;
+                if rng.below(3) == 0 {
+                    tx.remove(&key(i)).unwrap();
+                    expected.remove(&i);
+                } else {
+                    tx.put(&key(i), &value(i)).unwrap();
+                    expected.insert(i);
+                }
+            }
+            tx.commit().unwrap();
+            assert_eq!(db.key_count(), expected.len() as u64);
+        }
+
+        // Rolled back changes are not counted
+        let mut tx = db.begin_write();
+        tx.put(&key(5000), &value(5000)).unwrap();
+        tx.rollback().unwrap();
+        assert_eq!(db.key_count(), expected.len() as u64);
+
+        drop(db);
+        let db = open_database(&path);
+        assert_eq!(db.key_count(), expected.len() as u64);
+        assert_eq!(db.begin_read().count_prefix(b"").unwrap(), expected.len() as u64);
+    }
+
+    #[test]
+    fn preallocates_initial_file_size() {
+        const INITIAL_FILE_SIZE: u64 = 4 << 20;
+        let path = TempPath::new();
+        let options = Options {
+            initial_file_size: Some(INITIAL_FILE_SIZE - 1),
+            ..test_utils::options()
+        };
+        let db = Database::create(&path, options).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), INITIAL_FILE_SIZE);
+
+        for round in 0..10 {
+            let mut tx = db.begin_write();
+            for i in 0..1000 {
+                tx.put(&key(round * 1000 + i), &value(i)).unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        assert_eq!(db.metrics().file_growths, 0);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), INITIAL_FILE_SIZE);
+        assert!(db.verify().unwrap().is_empty());
+
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..10_000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        assert!(db.metrics().file_growths > 0);
+    }
+
+    #[test]
+    fn computes_next_file_size() {
+        const KB: u64 = 1 << 10;
+        const MB: u64 = 1 << 20;
+        let cases = [
+            (GrowthPolicy::Double, 64 * KB, 65 * KB, 128 * KB),
+            (GrowthPolicy::Double, 64 * KB, 200 * KB, 200 * KB),
+            (GrowthPolicy::Fixed(MB), 64 * KB, 65 * KB, MB + 64 * KB),
+            (GrowthPolicy::Fixed(MB), 64 * KB, 2 * MB, 2 * MB),
+            (GrowthPolicy::Fixed(1000), 64 * KB, 65 * KB, 65 * KB),
+            (GrowthPolicy::Percent(50), 64 * KB, 65 * KB, 96 * KB),
+            (GrowthPolicy::Percent(50), 64 * KB, 100 * KB, 100 * KB),
+            (GrowthPolicy::Percent(0), 64 * KB, 65 * KB, 65 * KB),
+            (GrowthPolicy::Percent(10), 100 * KB, 101 * KB, 110 * KB),
+        ];
+        for (policy, file_size, required_size, expected) in cases {
+            assert_eq!(
+                policy.next_file_size(file_size, required_size, KB),
+                expected,
+                "{policy:?} {file_size} {required_size}"
+            );
+        }
+    }
+
+    #[test]
+    fn grows_by_step_past_1gb() {
+        const GB: u64 = 1 << 30;
+        let page_size = 4096;
+        let cases = [
+            (GB, GB + page_size, 2 * GB),
+            (5 * GB, 5 * GB + page_size, 6 * GB),
+            (5 * GB, 7 * GB + 1, 7 * GB + page_size),
+        ];
+        for (file_size, required_size, expected) in cases {
+            let new_file_size =
+                GrowthPolicy::Double.next_file_size(file_size, required_size, page_size);
+            assert_eq!(new_file_size, expected);
+            assert!(new_file_size > file_size);
+            assert!(new_file_size >= required_size);
+        }
+    }
+
+    #[test]
+    fn grows_file_using_policy() {
+        let path = TempPath::new();
+        let options = test_utils::options().growth_policy(GrowthPolicy::Fixed(64 << 10));
+        let db = Database::create(&path, options).unwrap();
+        let mut tx = db.begin_write();
+        tx.put(&key(0), &value(0)).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), (4 << 10) + (64 << 10));
+    }
+
+    #[test]
+    fn counts_metrics() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        assert_eq!(db.metrics(), DatabaseMetrics::default());
+
+        // Replaces empty root leaf and free list page
+        let mut tx = db.begin_write();
+        tx.put(&key(1), &value(1)).unwrap();
+        tx.commit().unwrap();
+        let metrics = db.metrics();
+        assert_eq!(
+            DatabaseMetrics {
+                bytes_written: 0,
+                ..metrics
+            },
+            DatabaseMetrics {
+                commits: 1,
+                pages_allocated: 2,
+                pages_freed: 2,
+                cache_misses: 1,
+                file_growths: 1,
+                node_reads: 1,
+                ..DatabaseMetrics::default()
+            }
+        );
+        // Leaf page, part of free list page and meta node
+        assert!(metrics.bytes_written > db.page_size() as u64);
+
+        let tx = db.begin_read();
+        tx.get(&key(1)).unwrap();
+        tx.get(&key(1)).unwrap();
+        drop(tx);
+        let metrics = db.metrics();
+        assert_eq!((metrics.cache_hits, metrics.cache_misses), (1, 2));
+        assert_eq!(metrics.node_reads, 2);
+
+        // Pages freed by first commit are released, nothing is written on rollback
+        let mut tx = db.begin_write();
+        tx.put(&key(2), &value(2)).unwrap();
+        tx.rollback().unwrap();
+        drop(tx);
+        let delta = db.metrics();
+        assert_eq!(delta.commits, 1);
+        assert_eq!(delta.rollbacks, 1);
+        assert_eq!(delta.pages_released, 2);
+        assert_eq!(delta.pages_allocated, metrics.pages_allocated);
+        assert_eq!(delta.bytes_written, metrics.bytes_written);
+    }
+
+    #[test]
+    fn collects_stats() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..1000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.page_size, 1 << 10);
+        assert_eq!(stats.keys, 1000);
+        assert!(stats.depth > 1);
+        assert!(stats.leaf_nodes > 1);
+        assert!(stats.branch_nodes >= 1);
+    }
+
+    #[test]
+    fn accounts_for_file_pages() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 1000..3000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.put(b"large", &[7; 5000]).unwrap();
+        tx.commit().unwrap();
+        let mut tx = db.begin_write();
+        for i in (1000..3000).filter(|i| i % 3 != 0) {
+            tx.remove(&key(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let file_stats = db.file_stats().unwrap();
+        let stats = db.stats().unwrap();
+        let meta = db.internal.begin_read_with_meta().1;
+        let (free_list_header, _) = db.internal.node_manager.read_free_list(meta.free_list_node).unwrap();
+        assert_eq!(file_stats.file_len, std::fs::metadata(&path).unwrap().len());
+        assert_eq!(file_stats.page_size, 1 << 10);
+        assert_eq!(file_stats.end_address, meta.end_address);
+        assert_eq!(file_stats.meta_pages, 2);
+        assert!(file_stats.free_pages > 0);
+        assert_eq!(
+            file_stats.used_pages,
+            stats.branch_nodes + stats.leaf_nodes + stats.overflow_pages + free_list_header.overflow_len + 1
+        );
+        assert_eq!(
+            file_stats.meta_pages + file_stats.used_pages + file_stats.free_pages,
+            meta.end_address / file_stats.page_size as u64
+        );
+        let mut meta_nodes = file_stats.meta.map(Option::unwrap);
+        meta_nodes.sort();
+        assert_eq!(meta_nodes[1], (meta.transaction_id, meta.root_node));
+        assert_eq!(meta_nodes[0].0, meta.transaction_id - 1);
+    }
+
+    #[test]
+    fn auto_compaction_keeps_file_size_bounded() {
+        // Returns peak and final file sizes after growing and then shrinking key set with churn
+        fn churn(auto_compact: Option<AutoCompactConfig>) -> (u64, u64) {
+            let path = TempPath::new();
+            let options = test_utils::options()
+                .growth_policy(GrowthPolicy::Fixed(0))
+                .auto_compact(auto_compact);
+            let db = Database::create(&path, options).unwrap();
+            let file_len = || std::fs::metadata(&path).unwrap().len();
+            let mut rng = Rng::new(7);
+            let mut tx = db.begin_write();
+            for i in 0..3000 {
+                tx.put(&key(i), &[i as u8; 100]).unwrap();
+            }
+            tx.commit().unwrap();
+            let peak = file_len();
+
+            let mut live = 3000;
+            for round in 0..300 {
+                let mut tx = db.begin_write();
+                // Shrink key set down to a tenth, then keep overwriting it
+                if live > 300 {
+                    for i in live - 10..live {
+                        tx.remove(&key(i)).unwrap();
+                    }
+                    live -= 10;
+                }
+                for _ in 0..5 {
+                    let i = rng.below(live as u64) as usize;
+                    tx.put(&key(i), &[round as u8; 100]).unwrap();
+                }
+                tx.commit().unwrap();
+                assert_eq!(db.verify().unwrap(), vec![], "round {round}");
+            }
+            assert_eq!(db.key_count(), live as u64);
+            (peak, file_len())
+        }
+
+        let (peak, uncompacted) = churn(None);
+        let (compacted_peak, compacted) = churn(Some(AutoCompactConfig::default()));
+        assert_eq!(compacted_peak, peak);
+        assert!(uncompacted > peak / 2, "{uncompacted} of {peak}");
+        assert!(compacted < uncompacted / 2, "{compacted} of {uncompacted}");
+
+        // File shrunk by compaction reopens with all entries
+        let path = TempPath::new();
+        let options = test_utils::options().auto_compact(Some(AutoCompactConfig::default()));
+        let db = Database::create(&path, options.clone()).unwrap();
+        let mut tx = db.begin_write();
+        for i in 0..2000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        for i in (200..2000).step_by(100) {
+            let mut tx = db.begin_write();
+            for j in i..i + 100 {
+                tx.remove(&key(j)).unwrap();
+            }
+            tx.commit().unwrap();
+        }
+        // Empty commits do not compact
+        for _ in 0..50 {
+            let mut tx = db.begin_write();
+            tx.put(&key(0), &value(0)).unwrap();
+            tx.commit().unwrap();
+        }
+        drop(db);
+        let db = Database::open(&path, options).unwrap();
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor().unwrap();
+        let entries = test_utils::collect(&mut cursor);
+        assert_eq!(entries.len(), 200);
+        assert_eq!(db.verify().unwrap(), vec![]);
+        assert!(db.file_stats().unwrap().free_pages < 10);
+    }
+
+    #[test]
+    fn copies_key_range_into_new_database() {
+        use std::ops::RangeBounds;
+
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..100 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let in_range = |start: Bound<&[u8]>, end: Bound<&[u8]>| {
+            let mut entries = (0..100)
+                .map(|i| (key(i), value(i)))
+                .filter(|(k, _)| (start, end).contains(k.as_slice()))
+                .collect::<Vec<_>>();
+            entries.sort();
+            entries
+        };
+        let (key30, key60) = (key(30), key(60));
+        for (start, end) in [
+            (Bound::Included(&key30[..]), Bound::Excluded(&key60[..])),
+            (Bound::Excluded(&key30[..]), Bound::Included(&key60[..])),
+            (Bound::Unbounded, Bound::Excluded(&key30[..])),
+            (Bound::Included(&key60[..]), Bound::Unbounded),
+        ] {
+            let copy_path = TempPath::new();
+            let copied = db
+                .copy_range(&copy_path, start, end, test_utils::options())
+                .unwrap();
+            let expected = in_range(start, end);
+            assert_eq!(copied, expected.len() as u64);
+
+            let copy = Database::open(&copy_path, test_utils::options()).unwrap();
+            let tx = copy.begin_read();
+            let mut cursor = tx.cursor().unwrap();
+            assert_eq!(test_utils::collect(&mut cursor), expected, "{start:?} {end:?}");
+            assert_eq!(copy.verify().unwrap(), vec![]);
+        }
+
+        // Lexicographic range of keys 30 to 59 includes keys 4, 5 and 6
+        let copy_path = TempPath::new();
+        let copied = db
+            .copy_range(&copy_path, Bound::Included(&key30), Bound::Excluded(&key60), test_utils::options())
+            .unwrap();
+        assert_eq!(copied, 33);
+        let err = db
+            .copy_range(&copy_path, Bound::Unbounded, Bound::Unbounded, test_utils::options())
+            .unwrap_err();
+        assert!(err.to_string().contains("exists"), "{err:#}");
+    }
+}
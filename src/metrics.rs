@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Snapshot of counters accumulated since database was opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DatabaseMetrics {
+    pub commits: u64,
+    pub rollbacks: u64,
+    pub pages_allocated: u64,
+    /// Pages freed by committed transactions. They become reusable once released.
+    pub pages_freed: u64,
+    /// Pages returned to free list once no reader could see them.
+    pub pages_released: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub bytes_written: u64,
+    /// Batches of nodes read into cache ahead of use.
+    pub prefetches: u64,
+    pub prefetched_nodes: u64,
+    pub file_growths: u64,
+    /// File reads of nodes. Pages read ahead at once count as a single read.
+    pub node_reads: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    commits: AtomicU64,
+    rollbacks: AtomicU64,
+    pages_allocated: AtomicU64,
+    pages_freed: AtomicU64,
+    pages_released: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    bytes_written: AtomicU64,
+    prefetches: AtomicU64,
+    prefetched_nodes: AtomicU64,
+    file_growths: AtomicU64,
+    node_reads: AtomicU64,
+}
+
+impl Metrics {
+    pub fn add_commit(&self) {
+        self.commits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_rollback(&self) {
+        self.rollbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_pages_allocated(&self, pages: u64) {
+        self.pages_allocated.fetch_add(pages, Ordering::Relaxed);
+    }
+
+    pub fn add_pages_freed(&self, pages: u64) {
+        self.pages_freed.fetch_add(pages, Ordering::Relaxed);
+    }
+
+    pub fn add_pages_released(&self, pages: u64) {
+        self.pages_released.fetch_add(pages, Ordering::Relaxed);
+    }
+
+    pub fn add_cache_access(&self, hit: bool) {
+        if hit {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_bytes_written(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_prefetch(&self, nodes: u64) {
+        self.prefetches.fetch_add(1, Ordering::Relaxed);
+        self.prefetched_nodes.fetch_add(nodes, Ordering::Relaxed);
+    }
+
+    pub fn add_file_growth(&self) {
+        self.file_growths.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_node_reads(&self, reads: u64) {
+        self.node_reads.fetch_add(reads, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> DatabaseMetrics {
+        DatabaseMetrics {
+            commits: self.commits.load(Ordering::Relaxed),
+            rollbacks: self.rollbacks.load(Ordering::Relaxed),
+            pages_allocated: self.pages_allocated.load(Ordering::Relaxed),
+            pages_freed: self.pages_freed.load(Ordering::Relaxed),
+            pages_released: self.pages_released.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            prefetches: self.prefetches.load(Ordering::Relaxed),
+            prefetched_nodes: self.prefetched_nodes.load(Ordering::Relaxed),
+            file_growths: self.file_growths.load(Ordering::Relaxed),
+            node_reads: self.node_reads.load(Ordering::Relaxed),
+        }
+    }
+}
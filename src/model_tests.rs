@@ -0,0 +1,244 @@
+//! Randomized differential tests comparing database with `BTreeMap` model.
+//!
+//! Random sequences of operations are applied to both, and after every commit
+//! cursor output, entries count and tree invariants are checked. Failing sequence
+//! is shrunk to a minimal one which is printed as a ready to paste `Vec<ModelOp>`.
+
+use std::collections::BTreeMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::test_utils::{self, key, Rng, TempPath};
+use crate::{Database, Options};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ModelOp {
+    /// Puts key with value of given length filled with given byte.
+    Put(usize, usize, u8),
+    Remove(usize),
+    Commit,
+    Rollback,
+}
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+fn generate(rng: &mut Rng, len: usize, key_space: u64) -> Vec<ModelOp> {
+    (0..len)
+        .map(|_| match rng.below(100) {
+            0..=49 => {
+                let value_len = match rng.below(10) {
+                    0 => 0,
+                    // Values spanning multiple pages
+                    1 => 1000 + rng.below(3000) as usize,
+                    _ => rng.below(100) as usize,
+                };
+                ModelOp::Put(rng.below(key_space) as usize, value_len, rng.below(256) as u8)
+            }
+            50..=84 => ModelOp::Remove(rng.below(key_space) as usize),
+            85..=95 => ModelOp::Commit,
+            _ => ModelOp::Rollback,
+        })
+        .collect()
+}
+
+/// Applies operations to database and model, returning description of first mismatch
+/// or violated invariant. Panics are reported as failures too.
+fn check(options: &Options, ops: &[ModelOp]) -> Result<(), String> {
+    catch_unwind(AssertUnwindSafe(|| run(options, ops)))
+        .unwrap_or_else(|panic| {
+            let message = panic
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_default();
+            Err(format!("panicked: {message}"))
+        })
+}
+
+fn run(options: &Options, ops: &[ModelOp]) -> Result<(), String> {
+    let path = TempPath::new();
+    let db = Database::create(&path, options.clone()).map_err(|e| format!("{e:#}"))?;
+    let mut committed = Model::new();
+    let mut pending = Model::new();
+    let mut tx = db.begin_write();
+    for (i, op) in ops.iter().enumerate() {
+        let context = |e: anyhow::Error| format!("op {i} {op:?}: {e:#}");
+        match op {
+            ModelOp::Put(k, value_len, byte) => {
+                let value = vec![*byte; *value_len];
+                tx.put(&key(*k), &value).map_err(context)?;
+                pending.insert(key(*k), value);
+            }
+            ModelOp::Remove(k) => {
+                tx.remove(&key(*k)).map_err(context)?;
+                pending.remove(&key(*k));
+            }
+            ModelOp::Commit => {
+                tx.commit().map_err(context)?;
+                committed = pending.clone();
+                check_database(&db, &committed).map_err(|e| format!("after op {i} {op:?}: {e}"))?;
+                tx = db.begin_write();
+            }
+            ModelOp::Rollback => {
+                tx.rollback().map_err(context)?;
+                pending = committed.clone();
+            }
+        }
+        if let ModelOp::Put(k, ..) | ModelOp::Remove(k) = op {
+            let actual = tx.get(&key(*k)).map_err(context)?;
+            if actual.as_ref() != pending.get(&key(*k)) {
+                return Err(format!("op {i} {op:?}: transaction sees {actual:?}"));
+            }
+        }
+    }
+    tx.commit().map_err(|e| format!("final commit: {e:#}"))?;
+    check_database(&db, &pending).map_err(|e| format!("after final commit: {e}"))?;
+
+    // Committed state survives reopening
+    drop(db);
+    let db = Database::open(&path, options.clone()).map_err(|e| format!("reopen: {e:#}"))?;
+    check_database(&db, &pending).map_err(|e| format!("after reopen: {e}"))
+}
+
+fn check_database(db: &Database, model: &Model) -> Result<(), String> {
+    let violations = db.verify().map_err(|e| format!("{e:#}"))?;
+    if !violations.is_empty() {
+        return Err(format!("violations {violations:?}"));
+    }
+    if db.key_count() != model.len() as u64 {
+        return Err(format!("key count {} != {}", db.key_count(), model.len()));
+    }
+    let tx = db.begin_read();
+    let mut cursor = tx.cursor().map_err(|e| format!("{e:#}"))?;
+    let forward = test_utils::collect(&mut cursor);
+    let expected = model
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect::<Vec<_>>();
+    if forward != expected {
+        return Err(first_difference("forward scan", &forward, &expected));
+    }
+    let mut cursor = tx.cursor_last().map_err(|e| format!("{e:#}"))?;
+    let mut backward = Vec::new();
+    while cursor.is_valid() {
+        backward.push((cursor.key().to_vec(), cursor.value().to_vec()));
+        cursor.prev_entry().map_err(|e| format!("{e:#}"))?;
+    }
+    backward.reverse();
+    if backward != expected {
+        return Err(first_difference("backward scan", &backward, &expected));
+    }
+    Ok(())
+}
+
+fn first_difference(scan: &str, actual: &[(Vec<u8>, Vec<u8>)], expected: &[(Vec<u8>, Vec<u8>)]) -> String {
+    let keys = |entries: &[(Vec<u8>, Vec<u8>)]| {
+        entries
+            .iter()
+            .map(|(k, _)| String::from_utf8_lossy(k).into_owned())
+            .collect::<Vec<_>>()
+    };
+    let index = actual
+        .iter()
+        .zip(expected)
+        .position(|(a, e)| a != e)
+        .unwrap_or(actual.len().min(expected.len()));
+    format!(
+        "{scan} differs at entry {index}: {} entries {:?}.., expected {} entries {:?}..",
+        actual.len(),
+        &keys(actual)[index..actual.len().min(index + 3)],
+        expected.len(),
+        &keys(expected)[index..expected.len().min(index + 3)],
+    )
+}
+
+/// Minimizes failing sequence by removing chunks of operations, halving chunk size
+/// down to single operations, and then simplifying remaining puts.
+fn shrink(mut ops: Vec<ModelOp>, fails: impl Fn(&[ModelOp]) -> bool) -> Vec<ModelOp> {
+    let mut chunk = ops.len().div_ceil(2).max(1);
+    loop {
+        let mut start = 0;
+        let mut removed = false;
+        while start < ops.len() {
+            let mut candidate = ops.clone();
+            candidate.drain(start..(start + chunk).min(ops.len()));
+            if fails(&candidate) {
+                ops = candidate;
+                removed = true;
+            } else {
+                start += chunk;
+            }
+        }
+        if chunk == 1 && !removed {
+            break;
+        }
+        if !removed {
+            chunk = chunk.div_ceil(2);
+        }
+    }
+    for i in 0..ops.len() {
+        let ModelOp::Put(k, value_len, _) = ops[i] else {
+            continue;
+        };
+        for simpler in [ModelOp::Put(k, 0, 0), ModelOp::Put(k, value_len, 0)] {
+            let mut candidate = ops.clone();
+            candidate[i] = simpler;
+            if candidate != ops && fails(&candidate) {
+                ops = candidate;
+                break;
+            }
+        }
+    }
+    ops
+}
+
+fn check_random_sequences(options: Options, cases: u64, len: usize, key_space: u64) {
+    for seed in 1..=cases {
+        let ops = generate(&mut Rng::new(seed), len, key_space);
+        if let Err(error) = check(&options, &ops) {
+            let minimal = shrink(ops, |ops| check(&options, ops).is_err());
+            let minimal_error = check(&options, &minimal).unwrap_err();
+            panic!(
+                "seed {seed} failed: {error}\nminimal sequence of {} ops fails with: {minimal_error}\n{minimal:?}",
+                minimal.len()
+            );
+        }
+    }
+}
+
+#[test]
+fn matches_model_with_small_nodes() {
+    let options = test_utils::options().max_keys_per_node(Some(4));
+    check_random_sequences(options, 40, 300, 60);
+}
+
+#[test]
+fn matches_model_with_large_key_space() {
+    check_random_sequences(test_utils::options(), 20, 600, 500);
+}
+
+#[test]
+fn matches_model_while_spilling_and_compacting() {
+    let options = Options {
+        spill_threshold: Some(2 << 10),
+        ..test_utils::options()
+    }
+    .max_keys_per_node(Some(8))
+    .auto_compact(Some(Default::default()));
+    check_random_sequences(options, 20, 400, 200);
+}
+
+#[test]
+fn shrinks_failing_sequence() {
+    use ModelOp::*;
+    // Fails when key 3 is removed after it was put with non-empty value
+    let fails = |ops: &[ModelOp]| {
+        let put = ops.iter().position(|op| matches!(op, Put(3, len, _) if *len > 0));
+        put.is_some_and(|put| ops[put..].contains(&Remove(3)))
+    };
+    let mut ops = generate(&mut Rng::new(5), 200, 10);
+    ops.insert(20, Put(3, 7, 9));
+    ops.push(Remove(3));
+    assert!(fails(&ops));
+    let minimal = shrink(ops, fails);
+    assert!(matches!(minimal[..], [Put(3, _, 0), Remove(3)]), "{minimal:?}");
+}
@@ -1,4 +1,4 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use std::fs::{File, OpenOptions};
 use std::hash::Hasher;
 use std::io::{Read, Seek, SeekFrom, Write};
@@ -6,23 +6,45 @@ use std::mem::size_of;
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Condvar, Mutex};
-use std::u64;
 
 use crate::{
-    format::{read_u16, read_u32, read_u64, write_u16, write_u32, write_u64},
+    error::Error,
+    format::{
+        read_u16, read_u32, read_u64, read_varint, varint_size, write_u16, write_u32, write_u64,
+        write_varint,
+    },
     free_list::FreeList,
+    metrics::Metrics,
     tx::TransactionId,
 };
 
-const BRANCH_NODE: u16 = 1;
-const LEAF_NODE: u16 = 2;
+pub(crate) const BRANCH_NODE: u16 = 1;
+pub(crate) const LEAF_NODE: u16 = 2;
 pub const FREELIST_NODE: u16 = 3;
 pub const MIN_KEYS_PER_PAGE: usize = 2;
+pub const MAGIC: &[u8; 8] = b"BONSAIDB";
+pub const FORMAT_VERSION: u16 = 8;
+/// Largest key and value sizes supported by current format.
+pub const MAX_KEY_SIZE: usize = u16::MAX as usize;
+pub const MAX_VALUE_SIZE: usize = u32::MAX as usize;
+
+/// Reads `len` bytes, failing before allocation if length of corrupted node exceeds `limit`.
+fn read_bytes<R: Read>(reader: &mut R, len: u64, limit: usize) -> Result<Vec<u8>> {
+    if len > limit as u64 {
+        return Err(Error::Corruption(format!("length {len} exceeds limit of {limit} bytes")).into());
+    }
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BranchInternalNode {
     pub key: Vec<u8>,
     pub node_id: NodeId,
+    /// Number of entries in child subtree, including expired ones which were
+    /// not evicted yet. Stale while child is dirty and updated once it is written.
+    pub count: u64,
 }
 
 impl BranchInternalNode {
@@ -31,29 +53,33 @@ impl BranchInternalNode {
             // page id
             size_of::<u64>() +
             // key len field
-            size_of::<u16>() +
+            varint_size(self.key.len() as u64) +
             // key
-            self.key.len()
+            self.key.len() +
+            // subtree entries count
+            varint_size(self.count)
         ) as u64
     }
 
     fn read<R: Read>(reader: &mut R) -> Result<Self> {
         let address = read_u64(reader)?;
-        let key_len = read_u16(reader)? as usize;
-        let mut key = vec![0; key_len];
-        reader.read_exact(&mut key)?;
+        let key_len = read_varint(reader)?;
+        let key = read_bytes(reader, key_len, MAX_KEY_SIZE)?;
+        let count = read_varint(reader)?;
         Ok(Self {
             key,
             node_id: NodeId::Address(address),
+            count,
         })
     }
 
     fn write<W: Write>(&self, writer: &mut W) -> Result<u64> {
-        let size = std::mem::size_of::<u64>() + std::mem::size_of::<u16>() + self.key.len();
+        assert!(self.key.len() <= MAX_KEY_SIZE);
         write_u64(writer, self.node_id.node_address())?;
-        write_u16(writer, self.key.len() as u16)?;
+        write_varint(writer, self.key.len() as u64)?;
         writer.write_all(&self.key)?;
-        Ok(size as u64)
+        write_varint(writer, self.count)?;
+        Ok(self.size())
     }
 }
 
@@ -61,64 +87,101 @@ impl BranchInternalNode {
 pub struct LeafInternalNode {
     pub key: Vec<u8>,
     pub value: Vec<u8>,
+    /// Unix time in milliseconds after which entry is no longer visible.
+    pub expires_at: Option<u64>,
 }
 
 impl LeafInternalNode {
     fn size(&self) -> u64 {
         (
             // key len field
-            size_of::<u16>() +
+            varint_size(self.key.len() as u64) +
             // key
             self.key.len() +
-            // value len field
-            size_of::<u32>() +
+            // value len and expiry flag field
+            varint_size(self.value_tag()) +
+            // expiry
+            self.expires_at.map_or(0, |_| size_of::<u64>()) +
             // value
             self.value.len()
         ) as u64
     }
 
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Value length shifted left with the lowest bit set if entry has expiry.
+    fn value_tag(&self) -> u64 {
+        ((self.value.len() as u64) << 1) | self.expires_at.is_some() as u64
+    }
+
     fn read<R: Read>(reader: &mut R) -> Result<Self> {
         // read key
-        let key_len = read_u16(reader)? as usize;
-        let mut key = vec![0; key_len];
-        if let Err(e) = reader.read_exact(&mut key) {
-            panic!("fuck {e:?}");
-        }
+        let key_len = read_varint(reader)?;
+        let key = read_bytes(reader, key_len, MAX_KEY_SIZE)?;
 
         // read value
-        let val_len = read_u32(reader)? as usize;
-        let mut value = vec![0; val_len];
-        reader.read_exact(&mut value)?;
-        Ok(Self { key, value })
+        let value_tag = read_varint(reader)?;
+        let expires_at = if value_tag & 1 == 1 {
+            Some(read_u64(reader)?)
+        } else {
+            None
+        };
+        let value = read_bytes(reader, value_tag >> 1, MAX_VALUE_SIZE)?;
+        Ok(Self { key, value, expires_at })
     }
 
     fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
-        assert!(self.key.len() <= u16::MAX as usize);
-        write_u16(writer, self.key.len() as u16)?;
+        assert!(self.key.len() <= MAX_KEY_SIZE);
+        write_varint(writer, self.key.len() as u64)?;
         writer.write_all(&self.key)?;
-        assert!(self.value.len() <= u32::MAX as usize);
-        write_u32(writer, self.value.len() as u32)?;
+        assert!(self.value.len() <= MAX_VALUE_SIZE);
+        write_varint(writer, self.value_tag())?;
+        if let Some(expires_at) = self.expires_at {
+            write_u64(writer, expires_at)?;
+        }
         writer.write_all(&self.value)?;
         Ok(())
     }
 }
 
+/// Returns current unix time in milliseconds used for entry expiry.
+pub fn unix_time_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NodeHeader {
     pub flags: u16,
     pub internal_nodes_len: u64,
     pub overflow_len: u64,
+    /// CRC32 of all pages taken by node with this field zeroed.
+    /// Set when node is written as pages.
+    pub checksum: u32,
+    /// Transaction which wrote node, so that recovery can tell the latest root
+    /// apart from stale ones left in free pages. Set when node is written.
+    pub transaction_id: TransactionId,
 }
 
 impl NodeHeader {
+    /// Offset of checksum within page.
+    const CHECKSUM_OFFSET: usize = size_of::<u16>() + size_of::<u64>() * 2;
+
     pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
         let flags = read_u16(reader)?;
         let internal_nodes_len = read_u64(reader)?;
         let overflow_len = read_u64(reader)?;
+        let checksum = read_u32(reader)?;
+        let transaction_id = read_u64(reader)?;
         Ok(Self {
             flags,
             internal_nodes_len,
             overflow_len,
+            checksum,
+            transaction_id,
         })
     }
 
@@ -126,6 +189,8 @@ impl NodeHeader {
         write_u16(writer, self.flags)?;
         write_u64(writer, self.internal_nodes_len)?;
         write_u64(writer, self.overflow_len)?;
+        write_u32(writer, self.checksum)?;
+        write_u64(writer, self.transaction_id)?;
         Ok(())
     }
 
@@ -135,10 +200,76 @@ impl NodeHeader {
         // internal_nodes_len
         std::mem::size_of::<u64>() as u64 +
         // overflow_len
+        std::mem::size_of::<u64>() as u64 +
+        // checksum
+        std::mem::size_of::<u32>() as u64 +
+        // transaction_id
         std::mem::size_of::<u64>() as u64
     }
 }
 
+/// Computes checksum of node pages as if checksum field was zeroed.
+fn pages_checksum(pages: &[u8]) -> u32 {
+    let mut h = crc32fast::Hasher::new();
+    h.update(&pages[..NodeHeader::CHECKSUM_OFFSET]);
+    h.update(&[0; size_of::<u32>()]);
+    h.update(&pages[NodeHeader::CHECKSUM_OFFSET + size_of::<u32>()..]);
+    h.finalize()
+}
+
+/// Writes serialized node zero-filled up to page boundary and stores checksum
+/// covering all its pages, so padding can't hide corruption or stale bytes.
+pub(crate) fn write_pages<W: Write>(writer: &mut W, mut node: Vec<u8>, page_size: u64) -> Result<u32> {
+    let pages = (node.len() as u64).div_ceil(page_size).max(1);
+    node.resize((pages * page_size) as usize, 0);
+    let checksum = pages_checksum(&node);
+    let offset = NodeHeader::CHECKSUM_OFFSET;
+    node[offset..offset + size_of::<u32>()].copy_from_slice(&checksum.to_be_bytes());
+    writer.write_all(&node)?;
+    Ok(checksum)
+}
+
+/// Reads all pages of node and verifies their checksum if `verify_checksum` is set.
+/// Node must fit into `max_len` bytes left in file from its first page, so that
+/// corrupted overflow length fails the read instead of allocating a huge buffer.
+pub(crate) fn read_pages<R: Read>(
+    reader: &mut R,
+    page_size: u64,
+    max_len: u64,
+    verify_checksum: bool,
+) -> Result<Vec<u8>> {
+    let mut buf = vec![0; page_size as usize];
+    reader.read_exact(&mut buf)?;
+    let header = NodeHeader::read(&mut buf.as_slice())?;
+    if header.overflow_len > 0 {
+        let len = header
+            .overflow_len
+            .checked_add(1)
+            .and_then(|pages| pages.checked_mul(page_size))
+            .filter(|len| *len <= max_len)
+            .ok_or_else(|| {
+                Error::Corruption(format!(
+                    "overflow length {} exceeds {max_len} bytes left in file",
+                    header.overflow_len
+                ))
+            })?;
+        buf.resize(len as usize, 0);
+        reader.read_exact(&mut buf[page_size as usize..])?;
+    }
+    if !verify_checksum {
+        return Ok(buf);
+    }
+    let checksum = pages_checksum(&buf);
+    if checksum != header.checksum {
+        return Err(Error::Corruption(format!(
+            "checksum mismatch: stored {:#010x}, computed {checksum:#010x}",
+            header.checksum
+        ))
+        .into());
+    }
+    Ok(buf)
+}
+
 #[derive(Debug, Clone)]
 pub struct MetaNode {
     pub page_size: u32,
@@ -146,10 +277,16 @@ pub struct MetaNode {
     pub free_list_node: Address,
     pub transaction_id: TransactionId,
     pub end_address: Address,
+    /// Number of entries in tree including expired ones not evicted yet.
+    pub entries: u64,
 }
 
 impl MetaNode {
     pub fn size() -> usize {
+        // magic
+        MAGIC.len() +
+        // format version
+        size_of::<u16>() +
         // page_size,
         size_of::<u32>() +
         // root_node
@@ -161,6 +298,8 @@ impl MetaNode {
         // checksum
         size_of::<u32>() +
         // end_address
+        size_of::<u64>() +
+        // entries
         size_of::<u64>()
     }
 
@@ -168,12 +307,28 @@ impl MetaNode {
         1 << 10 // 1KiB
     }
 
+    /// Returns address of the first page following both meta nodes.
+    pub fn data_start_address(page_size: u32) -> Address {
+        let page_size = page_size as u64;
+        page_size * (Self::page_size() * 2).div_ceil(page_size)
+    }
+
     pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut magic = [0u8; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::BadMagic.into());
+        }
+        let version = read_u16(reader)?;
+        if version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(version).into());
+        }
         let page_size = read_u32(reader)?;
         let root_node = read_u64(reader)? as Address;
         let free_list_node = read_u64(reader)? as Address;
         let transaction_id = read_u64(reader)? as TransactionId;
         let end_address = read_u64(reader)? as Address;
+        let entries = read_u64(reader)?;
         let checksum = read_u32(reader)?;
         let meta_node = Self {
             page_size,
@@ -181,33 +336,43 @@ impl MetaNode {
             free_list_node,
             transaction_id,
             end_address,
+            entries,
         };
 
         let expected_checksum = meta_node.checksum();
         if checksum != expected_checksum {
-            return Err(anyhow!("corrupted file"));
+            return Err(Error::Corruption(format!(
+                "meta node checksum mismatch: stored {checksum:#010x}, computed {expected_checksum:#010x}"
+            ))
+            .into());
         }
 
         Ok(meta_node)
     }
 
     pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(MAGIC)?;
+        write_u16(writer, FORMAT_VERSION)?;
         write_u32(writer, self.page_size)?;
         write_u64(writer, self.root_node)?;
         write_u64(writer, self.free_list_node)?;
         write_u64(writer, self.transaction_id)?;
         write_u64(writer, self.end_address)?;
+        write_u64(writer, self.entries)?;
         write_u32(writer, self.checksum())?;
         Ok(())
     }
 
     pub fn checksum(&self) -> u32 {
         let mut h = crc32fast::Hasher::new();
+        h.write(MAGIC);
+        h.write_u16(FORMAT_VERSION);
         h.write_u32(self.page_size);
         h.write_u64(self.root_node);
         h.write_u64(self.free_list_node);
         h.write_u64(self.transaction_id);
         h.write_u64(self.end_address);
+        h.write_u64(self.entries);
         h.finalize()
     }
 }
@@ -245,6 +410,21 @@ impl InternalNodes {
         self.len() == 0
     }
 
+    /// Checks that keys are strictly increasing.
+    pub fn validate(&self) -> Result<()> {
+        for index in 1..self.len() {
+            if self.key_at(index - 1) >= self.key_at(index) {
+                return Err(Error::Corruption(format!(
+                    "key {:?} at index {index} is not greater than previous key {:?}",
+                    String::from_utf8_lossy(self.key_at(index)),
+                    String::from_utf8_lossy(self.key_at(index - 1)),
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+
     pub fn first_child(&self) -> Option<&BranchInternalNode> {
         match self {
             Self::Branch(nodes) => nodes.first(),
@@ -263,11 +443,34 @@ impl InternalNodes {
         None
     }
 
-    pub fn remove_child_at(&mut self, index: usize) {
+    pub fn remove_child_at(&mut self, index: usize) -> Result<()> {
+        self.child_mut(index)?;
+        if let Self::Branch(nodes) = self {
+            nodes.remove(index);
+        }
+        Ok(())
+    }
+
+    /// Returns child of branch node failing with `Error::Corruption`
+    /// if node is a leaf or index is out of bounds.
+    pub fn child(&self, index: usize) -> Result<&BranchInternalNode> {
         let Self::Branch(nodes) = self else {
-            panic!("cannot remove child from leaf node");
+            return Err(Error::Corruption(format!("expected branch node to access child {index}")).into());
         };
-        nodes.remove(index);
+        let len = nodes.len();
+        nodes.get(index).ok_or_else(|| {
+            Error::Corruption(format!("child index {index} is out of bounds of {len} children")).into()
+        })
+    }
+
+    fn child_mut(&mut self, index: usize) -> Result<&mut BranchInternalNode> {
+        let Self::Branch(nodes) = self else {
+            return Err(Error::Corruption(format!("expected branch node to access child {index}")).into());
+        };
+        let len = nodes.len();
+        nodes.get_mut(index).ok_or_else(|| {
+            Error::Corruption(format!("child index {index} is out of bounds of {len} children")).into()
+        })
     }
 
     pub fn as_branch(&self) -> Option<&Vec<BranchInternalNode>> {
@@ -278,7 +481,7 @@ impl InternalNodes {
     }
 
     pub fn merge(&mut self, other: InternalNodes) {
-        match (self, other) {
+        match (&mut *self, other) {
             (Self::Branch(self_nodes), Self::Branch(mut other_nodes)) => {
                 self_nodes.append(&mut other_nodes);
             }
@@ -287,6 +490,7 @@ impl InternalNodes {
             }
             _ => panic!("incompatible nodes"),
         }
+        debug_assert!(self.validate().is_ok(), "merged node keys are out of order");
     }
 
     pub fn splice(&mut self, index: usize, child_nodes: Vec<BranchInternalNode>) {
@@ -301,37 +505,65 @@ impl InternalNodes {
         }
     }
 
-    pub fn split(self, threshold: u64) -> Vec<Self> {
-        match self {
-            Self::Branch(nodes) => Self::split_branch(nodes, threshold),
-            Self::Leaf(nodes) => Self::split_leaf(nodes, threshold),
-        }
+    /// Splits node into nodes of at most `threshold` bytes and `max_keys` entries.
+    /// `max_keys` must be at least twice `MIN_KEYS_PER_PAGE`.
+    /// Returns number of pages node is written to, including overflow pages.
+    pub fn pages(&self, page_size: u64) -> u64 {
+        self.header(page_size).overflow_len + 1
     }
 
-    pub fn set_page_address(&mut self, child_index: usize, child_page_address: Address) {
-        match self {
-            Self::Branch(nodes) => {
-                assert!(matches!(nodes[child_index].node_id, NodeId::Id(_)));
-                nodes[child_index].node_id = NodeId::Address(child_page_address);
-            }
-            Self::Leaf(_) => {
-                panic!("cannot update");
-            }
-        }
+    /// Returns whether node spans more pages than its largest entry would take alone,
+    /// so splitting it reduces pages of its nodes. Node taken up by a single entry
+    /// larger than page is not oversized, since that entry needs overflow pages anyway.
+    pub fn is_oversized(&self, page_size: u64) -> bool {
+        let largest_entry_size = match self {
+            Self::Branch(nodes) => nodes.iter().map(|node| node.size()).max(),
+            Self::Leaf(nodes) => nodes.iter().map(|node| node.size()).max(),
+        };
+        let min_pages = (NodeHeader::size() + largest_entry_size.unwrap_or(0))
+            .div_ceil(page_size)
+            .max(1);
+        self.pages(page_size) > min_pages
+    }
+
+    pub fn split(self, threshold: u64, max_keys: usize) -> Vec<Self> {
+        let nodes = match self {
+            Self::Branch(nodes) => Self::split_branch(nodes, threshold, max_keys),
+            Self::Leaf(nodes) => Self::split_leaf(nodes, threshold, max_keys),
+        };
+        debug_assert!(
+            nodes.iter().all(|node| node.validate().is_ok()),
+            "split node keys are out of order",
+        );
+        nodes
     }
 
-    pub fn set_child_key(&mut self, child_index: usize, child_key: Vec<u8>) {
+    pub fn set_page_address(&mut self, child_index: usize, child_page_address: Address) -> Result<()> {
+        let child = self.child_mut(child_index)?;
+        assert!(matches!(child.node_id, NodeId::Id(_)));
+        child.node_id = NodeId::Address(child_page_address);
+        Ok(())
+    }
+
+    pub fn set_child_key(&mut self, child_index: usize, child_key: Vec<u8>) -> Result<()> {
+        self.child_mut(child_index)?.key = child_key;
+        Ok(())
+    }
+
+    pub fn set_child_count(&mut self, child_index: usize, count: u64) -> Result<()> {
+        self.child_mut(child_index)?.count = count;
+        Ok(())
+    }
+
+    /// Returns number of entries in node subtree.
+    pub fn count(&self) -> u64 {
         match self {
-            Self::Branch(nodes) => {
-                nodes[child_index].key = child_key;
-            }
-            Self::Leaf(_) => {
-                panic!("cannot update");
-            }
+            Self::Branch(nodes) => nodes.iter().map(|node| node.count).sum(),
+            Self::Leaf(nodes) => nodes.len() as u64,
         }
     }
 
-    fn split_branch(mut internal_nodes: Vec<BranchInternalNode>, threshold: u64) -> Vec<Self> {
+    fn split_branch(mut internal_nodes: Vec<BranchInternalNode>, threshold: u64, max_keys: usize) -> Vec<Self> {
         if internal_nodes.len() <= MIN_KEYS_PER_PAGE {
             return vec![Self::Branch(internal_nodes)];
         }
@@ -340,7 +572,8 @@ impl InternalNodes {
         let mut new_node = Vec::new();
         let drain_len = internal_nodes.len() - MIN_KEYS_PER_PAGE;
         for internal_node in internal_nodes.drain(..drain_len) {
-            if size + internal_node.size() > threshold && new_node.len() >= MIN_KEYS_PER_PAGE {
+            let is_full = size + internal_node.size() > threshold || new_node.len() >= max_keys;
+            if is_full && new_node.len() >= MIN_KEYS_PER_PAGE {
                 result.push(Self::Branch(std::mem::take(&mut new_node)));
                 size = NodeHeader::size();
             }
@@ -348,12 +581,17 @@ impl InternalNodes {
             new_node.push(internal_node);
         }
 
-        new_node.append(&mut internal_nodes);
+        if new_node.len() + internal_nodes.len() > max_keys {
+            result.push(Self::Branch(new_node));
+            new_node = internal_nodes;
+        } else {
+            new_node.append(&mut internal_nodes);
+        }
         result.push(Self::Branch(new_node));
         result
     }
 
-    fn split_leaf(mut internal_nodes: Vec<LeafInternalNode>, threshold: u64) -> Vec<Self> {
+    fn split_leaf(mut internal_nodes: Vec<LeafInternalNode>, threshold: u64, max_keys: usize) -> Vec<Self> {
         if internal_nodes.len() <= MIN_KEYS_PER_PAGE {
             return vec![Self::Leaf(internal_nodes)];
         }
@@ -362,7 +600,8 @@ impl InternalNodes {
         let mut new_node = Vec::new();
         let drain_len = internal_nodes.len() - MIN_KEYS_PER_PAGE;
         for internal_node in internal_nodes.drain(..drain_len) {
-            if size + internal_node.size() > threshold && new_node.len() >= MIN_KEYS_PER_PAGE {
+            let is_full = size + internal_node.size() > threshold || new_node.len() >= max_keys;
+            if is_full && new_node.len() >= MIN_KEYS_PER_PAGE {
                 result.push(Self::Leaf(std::mem::take(&mut new_node)));
                 size = NodeHeader::size();
             }
@@ -370,7 +609,12 @@ impl InternalNodes {
             new_node.push(internal_node);
         }
 
-        new_node.append(&mut internal_nodes);
+        if new_node.len() + internal_nodes.len() > max_keys {
+            result.push(Self::Leaf(new_node));
+            new_node = internal_nodes;
+        } else {
+            new_node.append(&mut internal_nodes);
+        }
         result.push(Self::Leaf(new_node));
         result
     }
@@ -394,43 +638,20 @@ impl InternalNodes {
         Err(anyhow!("invalid node type {}", header.flags))
     }
 
-    pub fn read2<R: Read>(reader: &mut R, page_size: u64) -> Result<(NodeHeader, Self)> {
-        let mut buf = vec![0; page_size as usize];
-        reader.read_exact(&mut buf)?;
-
-        let mut c = std::io::Cursor::new(buf);
-        let header = NodeHeader::read(&mut c)?;
-
-        if header.overflow_len > 0 {
-            // read overflow pages
-            let pos = c.position();
-            let mut buf = c.into_inner();
-            let new_len = buf.len() + (header.overflow_len * page_size) as usize;
-            buf.resize(new_len, 0);
-            reader.read_exact(&mut buf[page_size as usize..])?;
-            c = std::io::Cursor::new(buf);
-            c.set_position(pos);
-        }
-
-        if header.flags == BRANCH_NODE {
-            let mut nodes = Vec::new();
-            for _ in 0..header.internal_nodes_len {
-                nodes.push(BranchInternalNode::read(&mut c)?);
-            }
-            return Ok((header, Self::Branch(nodes)));
-        }
-        if header.flags == LEAF_NODE {
-            let mut nodes = Vec::new();
-            for _ in 0..header.internal_nodes_len {
-                nodes.push(LeafInternalNode::read(&mut c)?);
-            }
-            return Ok((header, Self::Leaf(nodes)));
-        }
-        Err(anyhow!("invalid node type {}", header.flags))
+    /// Reads node pages, which must fit into `max_len` bytes, verifying
+    /// their checksum if `verify_checksum` is set.
+    pub fn read2<R: Read>(
+        reader: &mut R,
+        page_size: u64,
+        max_len: u64,
+        verify_checksum: bool,
+    ) -> Result<(NodeHeader, Self)> {
+        let buf = read_pages(reader, page_size, max_len, verify_checksum)?;
+        Self::read(&mut buf.as_slice())
     }
 
-    pub fn write<W: Write>(&self, writer: &mut W, page_size: u64) -> Result<NodeHeader> {
-        let node_header = self.write_header(writer, page_size)?;
+    pub fn write<W: Write>(&self, writer: &mut W, page_size: u64, transaction_id: TransactionId) -> Result<NodeHeader> {
+        let node_header = self.write_header(writer, page_size, transaction_id)?;
         match self {
             Self::Branch(nodes) => {
                 for node in nodes {
@@ -446,42 +667,30 @@ impl InternalNodes {
         Ok(node_header)
     }
 
-    pub fn write2<W: Write>(&self, writer: &mut W, page_size: u64) -> Result<NodeHeader> {
-        let buf = vec![0; page_size as usize];
-        let mut c = std::io::Cursor::new(buf);
-        let header = self.write_header(&mut c, page_size)?;
-
-        if header.overflow_len > 0 {
-            // read overflow pages
-            let pos = c.position();
-            let mut buf = c.into_inner();
-            let new_len = buf.len() + (header.overflow_len * page_size) as usize;
-            buf.resize(new_len, 0);
-            c = std::io::Cursor::new(buf);
-            c.set_position(pos);
-        }
-
-        match self {
-            Self::Branch(nodes) => {
-                for node in nodes {
-                    node.write(&mut c)?;
-                }
-            }
-            Self::Leaf(nodes) => {
-                for node in nodes {
-                    node.write(&mut c)?;
-                }
-            }
-        }
-
-        let buf = c.into_inner();
-        writer.write_all(&buf)?;
-
+    /// Writes node of transaction `transaction_id` as zero-filled pages with checksum.
+    pub fn write2<W: Write>(&self, writer: &mut W, page_size: u64, transaction_id: TransactionId) -> Result<NodeHeader> {
+        let mut buf = Vec::with_capacity(self.size() as usize);
+        let mut header = self.write(&mut buf, page_size, transaction_id)?;
+        header.checksum = write_pages(writer, buf, page_size)?;
         Ok(header)
     }
 
 
-    fn write_header<W: Write>(&self, writer: &mut W, page_size: u64) -> Result<NodeHeader> {
+    fn write_header<W: Write>(
+        &self,
+        writer: &mut W,
+        page_size: u64,
+        transaction_id: TransactionId,
+    ) -> Result<NodeHeader> {
+        let node_header = NodeHeader {
+            transaction_id,
+            ..self.header(page_size)
+        };
+        node_header.write(writer)?;
+        Ok(node_header)
+    }
+
+    pub fn header(&self, page_size: u64) -> NodeHeader {
         let (nodes_len, nodes_size, flags) = match self {
             Self::Branch(nodes) => {
                 let nodes_size: u64 = nodes.iter().map(|node| node.size()).sum();
@@ -502,20 +711,19 @@ impl InternalNodes {
             (data_size - page_size).div_ceil(page_size)
         };
 
-        let node_header = NodeHeader {
+        NodeHeader {
             flags,
             internal_nodes_len: nodes_len as u64,
             overflow_len,
-        };
-        node_header.write(writer)?;
-        Ok(node_header)
+            checksum: 0,
+            transaction_id: 0,
+        }
     }
 
+    /// Nodes produced by split have at least `MIN_KEYS_PER_PAGE` keys,
+    /// so anything below that is merged with a sibling.
     pub fn has_min_keys(&self) -> bool {
-        match self {
-            Self::Branch(nodes) => nodes.len() > 2,
-            Self::Leaf(nodes) => nodes.len() > 1,
-        }
+        self.len() >= MIN_KEYS_PER_PAGE
     }
 
     pub fn is_leaf(&self) -> bool {
@@ -525,6 +733,59 @@ impl InternalNodes {
 
 pub type Address = u64;
 
+/// Byte offset of a page within database file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteAddr(u64);
+
+/// Position of a page within database file, that is its byte offset divided by page size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageIndex(u64);
+
+impl ByteAddr {
+    pub fn new(address: Address) -> Self {
+        Self(address)
+    }
+
+    pub fn get(self) -> Address {
+        self.0
+    }
+
+    /// Returns index of page starting at this address or `None` if it is not page aligned.
+    pub fn to_page_index(self, page_size: u64) -> Option<PageIndex> {
+        self.0.is_multiple_of(page_size).then_some(PageIndex(self.0 / page_size))
+    }
+
+    /// Returns index of page starting at this address. Panics if it is not page aligned.
+    pub fn page_index(self, page_size: u64) -> PageIndex {
+        self.to_page_index(page_size)
+            .unwrap_or_else(|| panic!("invalid page address {}", self.0))
+    }
+}
+
+impl PageIndex {
+    pub fn new(index: u64) -> Self {
+        Self(index)
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+
+    pub fn to_byte_addr(self, page_size: u64) -> ByteAddr {
+        ByteAddr(self.0 * page_size)
+    }
+
+    /// Returns index of page `pages` pages after this one.
+    pub fn offset(self, pages: u64) -> Self {
+        Self(self.0 + pages)
+    }
+
+    /// Returns number of pages from `other` to this page.
+    pub fn pages_since(self, other: Self) -> u64 {
+        self.0 - other.0
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum NodeId {
     Address(Address),
@@ -581,6 +842,29 @@ impl AsRef<InternalNodes> for Node<'_> {
 
 pub trait NodeReader {
     fn read_node(&self, node_id: NodeId) -> Result<Node<'_>>;
+
+    /// Number of following sibling leaves cursor reads ahead when it descends to a leaf.
+    fn prefetch_leaves(&self) -> usize {
+        0
+    }
+
+    /// Reads nodes into cache ahead of use. Nodes which fail to read are skipped.
+    fn prefetch(&self, _page_addresses: &[Address]) {}
+
+    /// Number of pages read at once when cursor descends to a node which is not cached.
+    fn read_ahead_leaves(&self) -> usize {
+        0
+    }
+
+    /// Reads node at `page_address` together with following contiguous pages into cache.
+    fn read_ahead(&self, _page_address: Address, _leaf_addresses: &[Address]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Current time in milliseconds against which entry expiry is checked.
+    fn now(&self) -> u64 {
+        unix_time_millis()
+    }
 }
 
 pub struct NodeManager {
@@ -590,14 +874,20 @@ pub struct NodeManager {
     files_condvar: Condvar,
     page_size: u32,
     nodes_cache: moka::sync::Cache<Address, Arc<(NodeHeader, InternalNodes)>>,
+    metrics: Arc<Metrics>,
+    checksum_reads: bool,
+    read_only: bool,
+    #[cfg(test)]
+    pub(crate) fail_writes: std::sync::atomic::AtomicBool,
 }
 
 impl NodeManager {
-    pub fn new(
+    pub(crate) fn new(
         file_path: impl AsRef<Path>,
         max_files: usize,
         page_size: u32,
         cache_size: u64,
+        metrics: Arc<Metrics>,
     ) -> Self {
         Self {
             file_path: file_path.as_ref().to_path_buf(),
@@ -609,13 +899,36 @@ impl NodeManager {
                 .weigher(|_, node: &Arc<(NodeHeader, InternalNodes)>| node.as_ref().1.size() as u32)
                 .max_capacity(cache_size)
                 .build(),
+            metrics,
+            checksum_reads: true,
+            read_only: false,
+            #[cfg(test)]
+            fail_writes: std::sync::atomic::AtomicBool::new(false),
         }
     }
 
-    pub fn write_node(&self, page_address: Address, node: &InternalNodes) -> Result<()> {
+    /// Sets whether checksums of node and free list pages are verified on read.
+    pub(crate) fn with_checksum_reads(mut self, checksum_reads: bool) -> Self {
+        self.checksum_reads = checksum_reads;
+        self
+    }
+
+    /// Sets whether file is opened without write access.
+    pub(crate) fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn write_node(&self, page_address: Address, node: &InternalNodes, transaction_id: TransactionId) -> Result<()> {
+        #[cfg(test)]
+        if self.fail_writes.load(std::sync::atomic::Ordering::Relaxed) {
+            return Err(anyhow!("injected write failure"));
+        }
+        debug_assert!(page_address.is_multiple_of(self.page_size as u64), "misaligned node address");
         let mut file = self.get_file()?;
         file.seek(SeekFrom::Start(page_address))?;
-        node.write2(&mut file, self.page_size as u64)?;
+        node.write2(&mut file, self.page_size as u64, transaction_id)?;
+        self.metrics.add_bytes_written(file.stream_position()? - page_address);
         self.release_file(file);
         Ok(())
     }
@@ -624,19 +937,44 @@ impl NodeManager {
         &self,
         page_address: Address,
         free_list: &FreeList,
+        transaction_id: TransactionId,
     ) -> Result<NodeHeader> {
+        debug_assert!(page_address.is_multiple_of(self.page_size as u64), "misaligned free list address");
         let mut file = self.get_file()?;
         file.seek(SeekFrom::Start(page_address))?;
-        let node_header = free_list.write(&mut file, self.page_size)?;
+        let node_header = free_list.write2(&mut file, self.page_size, transaction_id)?;
+        self.metrics.add_bytes_written(file.stream_position()? - page_address);
         self.release_file(file);
         Ok(node_header)
     }
 
+    pub fn read_free_list(&self, page_address: Address) -> Result<(NodeHeader, FreeList)> {
+        let mut file = self.get_file()?;
+        let free_list = Self::len_from(&file, page_address).and_then(|max_len| {
+            file.seek(SeekFrom::Start(page_address))?;
+            FreeList::read2(&mut file, self.page_size, max_len, self.checksum_reads)
+        });
+        self.release_file(file);
+        free_list.with_context(|| format!("corruption at page {page_address}"))
+    }
+
+    /// Reads meta node from one of two meta pages, `slot` being 0 or 1.
+    pub fn read_meta(&self, slot: u64) -> Result<MetaNode> {
+        let mut file = self.get_file()?;
+        let meta_node = file
+            .seek(SeekFrom::Start(slot * MetaNode::page_size()))
+            .map_err(anyhow::Error::from)
+            .and_then(|_| MetaNode::read(&mut file));
+        self.release_file(file);
+        meta_node
+    }
+
     pub fn write_meta(&self, meta_node: &MetaNode) -> Result<()> {
         let mut file = self.get_file()?;
         let page_address = (meta_node.transaction_id % 2) * MetaNode::page_size();
         file.seek(SeekFrom::Start(page_address))?;
         meta_node.write(&mut file)?;
+        self.metrics.add_bytes_written(file.stream_position()? - page_address);
         file.flush()?;
         file.sync_data()?;
         self.release_file(file);
@@ -644,9 +982,34 @@ impl NodeManager {
     }
 
     pub fn read_node(&self, page_address: Address) -> Result<Arc<(NodeHeader, InternalNodes)>> {
-        self.nodes_cache
-            .try_get_with(page_address, || self.read_node_from_file(page_address))
-            .map_err(|e| anyhow!("{e:?}"))
+        // Address taken from corrupted branch must not be read as a node
+        if !page_address.is_multiple_of(self.page_size as u64) {
+            return Err(Error::Misaligned {
+                address: page_address,
+                page_size: self.page_size,
+            }
+            .into());
+        }
+        let mut hit = true;
+        let node = self
+            .nodes_cache
+            .try_get_with(page_address, || {
+                hit = false;
+                self.read_node_from_file(page_address)
+            })
+            .map_err(unshare_error)?;
+        self.metrics.add_cache_access(hit);
+        Ok(node)
+    }
+
+    /// Returns node if it is cached, without reading it or counting cache access.
+    #[cfg(debug_assertions)]
+    pub fn cached_node(&self, page_address: Address) -> Option<Arc<(NodeHeader, InternalNodes)>> {
+        self.nodes_cache.get(&page_address)
+    }
+
+    pub fn invalidate_all_nodes(&self) {
+        self.nodes_cache.invalidate_all();
     }
 
     pub fn invalidate_nodes_cache(&self, pages: Vec<Address>) {
@@ -659,6 +1022,10 @@ impl NodeManager {
         self.page_size
     }
 
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
     pub fn size(&self) -> Result<u64> {
         let file = self.get_file()?;
         let len = file.metadata()?.len();
@@ -670,6 +1037,7 @@ impl NodeManager {
         let file = self.get_file()?;
         file.set_len(size)?;
         self.release_file(file);
+        self.metrics.add_file_growth();
         Ok(())
     }
 
@@ -684,7 +1052,7 @@ impl NodeManager {
                 files.files_len += 1;
                 let file = OpenOptions::new()
                     .read(true)
-                    .write(true)
+                    .write(!self.read_only)
                     .open(self.file_path.clone())?;
                 return Ok(file);
             }
@@ -704,12 +1072,150 @@ impl NodeManager {
 
     fn read_node_from_file(&self, page_address: Address) -> Result<Arc<(NodeHeader, InternalNodes)>> {
         let mut file = self.get_file()?;
+        let node = self.read_node_at(&mut file, page_address);
+        self.release_file(file);
+        self.metrics.add_node_reads(1);
+        Ok(Arc::new(node?))
+    }
+
+    fn read_node_at(&self, file: &mut File, page_address: Address) -> Result<(NodeHeader, InternalNodes)> {
+        let max_len = Self::len_from(file, page_address)?;
         file.seek(SeekFrom::Start(page_address))?;
-        let node = InternalNodes::read2(&mut file, self.page_size as u64)?;
-        // let node = InternalNodes::read(&mut file)?;
+        InternalNodes::read2(file, self.page_size as u64, max_len, self.checksum_reads)
+            .with_context(|| format!("corruption at page {page_address}"))
+    }
+
+    /// Returns number of bytes in file from `page_address` to its end.
+    fn len_from(file: &File, page_address: Address) -> Result<u64> {
+        Ok(file.metadata()?.len().saturating_sub(page_address))
+    }
+
+    /// Reads nodes which are not cached yet into cache using a single file handle.
+    /// Prefetch is only a hint, so errors are dropped and nodes which fail to read
+    /// are left to be reported by `read_node` if they are actually used.
+    pub fn prefetch(&self, page_addresses: &[Address]) {
+        let page_addresses = page_addresses
+            .iter()
+            .filter(|page_address| !self.nodes_cache.contains_key(page_address))
+            .collect::<Vec<_>>();
+        if page_addresses.is_empty() {
+            return;
+        }
+        let Ok(mut file) = self.get_file() else {
+            return;
+        };
+        let nodes = page_addresses
+            .iter()
+            .filter_map(|page_address| {
+                let node = self.read_node_at(&mut file, **page_address).ok()?;
+                Some((**page_address, node))
+            })
+            .collect::<Vec<_>>();
         self.release_file(file);
-        Ok(Arc::new(node))
+        self.metrics.add_node_reads(nodes.len() as u64);
+        self.metrics.add_prefetch(nodes.len() as u64);
+        for (page_address, node) in nodes {
+            self.nodes_cache.insert(page_address, Arc::new(node));
+        }
+    }
+
+    /// Reads `pages` pages starting at `page_address` with a single read and caches
+    /// node found at `page_address`. If it is a leaf, following leaves whose addresses
+    /// are listed in `leaf_addresses` and which fit into read pages are cached as well.
+    /// Other pages may be free or belong to other nodes, so they are never interpreted.
+    /// Unreadable node at `page_address` is left to be reported by `read_node`.
+    pub fn read_ahead(&self, page_address: Address, pages: usize, leaf_addresses: &[Address]) -> Result<()> {
+        if self.nodes_cache.contains_key(&page_address) {
+            return Ok(());
+        }
+        let page_size = self.page_size as u64;
+        let mut buf = vec![0; pages.max(1) * page_size as usize];
+        let mut file = self.get_file()?;
+        let len = file
+            .seek(SeekFrom::Start(page_address))
+            .map_err(anyhow::Error::from)
+            .and_then(|_| read_up_to(&mut file, &mut buf));
+        self.release_file(file);
+        buf.truncate(len?);
+        self.metrics.add_node_reads(1);
+
+        let Some(node) = parse_node(&buf, &[LEAF_NODE, BRANCH_NODE], page_size, self.checksum_reads) else {
+            return Ok(());
+        };
+        let is_leaf = node.1.is_leaf();
+        let mut offset = (node.0.overflow_len + 1) * page_size;
+        self.nodes_cache.insert(page_address, Arc::new(node));
+        if !is_leaf {
+            return Ok(());
+        }
+        while offset < buf.len() as u64 {
+            let address = page_address + offset;
+            let node = leaf_addresses
+                .contains(&address)
+                .then(|| parse_node(&buf[offset as usize..], &[LEAF_NODE], page_size, self.checksum_reads))
+                .flatten();
+            let Some(node) = node else {
+                offset += page_size;
+                continue;
+            };
+            offset += (node.0.overflow_len + 1) * page_size;
+            if !self.nodes_cache.contains_key(&address) {
+                self.nodes_cache.insert(address, Arc::new(node));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rebuilds error shared by nodes cache loader with all its context, keeping type of
+/// its root cause, so that callers can still match on `Error` of corrupted nodes.
+fn unshare_error(error: Arc<anyhow::Error>) -> anyhow::Error {
+    let root = error.root_cause();
+    let mut unshared = if let Some(root) = root.downcast_ref::<Error>() {
+        anyhow::Error::new(root.clone())
+    } else if let Some(root) = root.downcast_ref::<std::io::Error>() {
+        anyhow::Error::new(std::io::Error::new(root.kind(), root.to_string()))
+    } else {
+        anyhow!("{root}")
+    };
+    let contexts = error.chain().map(|e| e.to_string()).collect::<Vec<_>>();
+    for context in contexts.into_iter().rev().skip(1) {
+        unshared = unshared.context(context);
+    }
+    unshared
+}
+
+/// Reads into `buf` until it is full or end of file is reached. Returns number of bytes read.
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
     }
+    Ok(len)
+}
+
+/// Parses node at the start of `buf` if its flags are one of `flags`,
+/// all its pages are in `buf` and its checksum matches, if it is verified.
+fn parse_node(
+    buf: &[u8],
+    flags: &[u16],
+    page_size: u64,
+    verify_checksum: bool,
+) -> Option<(NodeHeader, InternalNodes)> {
+    let header = NodeHeader::read(&mut &buf[..]).ok()?;
+    if !flags.contains(&header.flags) {
+        return None;
+    }
+    let len = header.overflow_len.checked_add(1)?.checked_mul(page_size)?;
+    if len > buf.len() as u64 {
+        return None;
+    }
+    InternalNodes::read2(&mut &buf[..len as usize], page_size, len, verify_checksum).ok()
 }
 
 #[cfg(test)]
@@ -725,13 +1231,17 @@ mod tests {
             0x00, 0x01, // flags
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, // internal nodes len
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // overflow len
+            0x00, 0x00, 0x00, 0x00, // checksum
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // transaction id
             // node content
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, // node address
-            0x00, 0x0A, // key len
+            0x0A, // key len
             0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, // key
+            0x05, // count
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, // node address
-            0x00, 0x09, // key len
+            0x09, // key len
             0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, // key
+            0xAC, 0x02, // count
         ];
 
         let mut cursor = Cursor::new(data);
@@ -746,13 +1256,15 @@ mod tests {
             vec![
                 BranchInternalNode {
                     // node_id: NodeId::Address(16),
-                    key: (1..=10).into_iter().collect::<Vec<u8>>(),
+                    key: (1..=10).collect::<Vec<u8>>(),
                     node_id: NodeId::Address(16),
+                    count: 5,
                 },
                 BranchInternalNode {
                     // node_id: NodeId::Address(33),
-                    key: (17..=25).into_iter().collect::<Vec<u8>>(),
+                    key: (17..=25).collect::<Vec<u8>>(),
                     node_id: NodeId::Address(33),
+                    count: 300,
                 }
             ]
         );
@@ -765,21 +1277,25 @@ mod tests {
             0x00, 0x02, // flags
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, // internal nodes len
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // overflow len
+            0x00, 0x00, 0x00, 0x00, // checksum
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // transaction id
             // node 1
-            0x00, 0x0A, // key len
+            0x0A, // key len
             0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, // key
-            0x00, 0x00, 0x00, 0x10, // value len
+            0x20, // value len = 16, no expiry
             0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, // value
             0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x10,
             // node 2
-            0x00, 0x09, // key len
+            0x09, // key len
             0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, // key
-            0x00, 0x00, 0x00, 0x02, // value len
+            0x05, // value len = 2, has expiry
+            0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // expires at
             0x11, 0x12, // value
         ];
 
         let mut cursor = Cursor::new(data);
-        let (_, node) = InternalNodes::read(&mut cursor).unwrap();
+        let (header, node) = InternalNodes::read(&mut cursor).unwrap();
+        assert_eq!(header.transaction_id, 1);
 
         let InternalNodes::Leaf(nodes) = node else {
             panic!("unexpected node type");
@@ -789,14 +1305,382 @@ mod tests {
             nodes,
             vec![
                 LeafInternalNode {
-                    key: (1..=10).into_iter().collect::<Vec<u8>>(),
-                    value: (1..=16).into_iter().collect::<Vec<u8>>()
+                    key: (1..=10).collect::<Vec<u8>>(),
+                    value: (1..=16).collect::<Vec<u8>>(),
+                    expires_at: None,
                 },
                 LeafInternalNode {
-                    key: (17..=25).into_iter().collect::<Vec<u8>>(),
+                    key: (17..=25).collect::<Vec<u8>>(),
                     value: vec![17, 18],
+                    expires_at: Some(1 << 40),
                 },
             ]
         );
     }
+
+    #[test]
+    fn writes_leaf_node_with_varint_lengths_and_expiry() {
+        let node = InternalNodes::Leaf(vec![
+            LeafInternalNode {
+                key: vec![0x01, 0x02],
+                value: vec![0xAA; 3],
+                expires_at: None,
+            },
+            LeafInternalNode {
+                key: vec![0x03],
+                value: vec![0xBB; 200],
+                expires_at: Some(0x0102),
+            },
+        ]);
+
+        let mut writer = Cursor::new(Vec::new());
+        node.write(&mut writer, 4096, 1).unwrap();
+        let data = writer.into_inner();
+        assert_eq!(data.len() as u64, node.size());
+
+        let header_size = NodeHeader::size() as usize;
+        assert_eq!(
+            &data[header_size..header_size + 7],
+            &[
+                0x02, // key len
+                0x01, 0x02, // key
+                0x06, // value len = 3, no expiry
+                0xAA, 0xAA, 0xAA, // value
+            ]
+        );
+        assert_eq!(
+            &data[header_size + 7..header_size + 19],
+            &[
+                0x01, // key len
+                0x03, // key
+                0x91, 0x03, // value len = 200, has expiry
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, // expires at
+            ]
+        );
+
+        let (_, read_node) = InternalNodes::read(&mut Cursor::new(data)).unwrap();
+        let (InternalNodes::Leaf(read_nodes), InternalNodes::Leaf(nodes)) = (read_node, node) else {
+            panic!("unexpected node type");
+        };
+        assert_eq!(read_nodes, nodes);
+    }
+
+    #[test]
+    fn checksums_zero_filled_pages() {
+        let page_size = 16384;
+        let node = leaf(2);
+        let mut writer = Cursor::new(Vec::new());
+        let header = node.write2(&mut writer, page_size, 1).unwrap();
+        let data = writer.into_inner();
+        assert_eq!(data.len() as u64, page_size);
+        assert!(data[node.size() as usize..].iter().all(|byte| *byte == 0));
+
+        let offset = NodeHeader::CHECKSUM_OFFSET;
+        let mut unchecked = data.clone();
+        unchecked[offset..offset + 4].fill(0);
+        assert_eq!(header.checksum, crc32fast::hash(&unchecked));
+        assert_eq!(&data[offset..offset + 4], &header.checksum.to_be_bytes());
+
+        let (read_header, read_node) = InternalNodes::read2(&mut Cursor::new(&data), page_size, page_size, true).unwrap();
+        assert_eq!(read_header, header);
+        assert_eq!(read_node.len(), 2);
+
+        // Flipped bit in padding is caught
+        let mut corrupted = data.clone();
+        corrupted[page_size as usize - 1] ^= 1;
+        let err = InternalNodes::read2(&mut Cursor::new(&corrupted), page_size, page_size, true).unwrap_err();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Corruption(_))), "{err}");
+    }
+
+    #[test]
+    fn checksums_overflow_pages() {
+        let page_size = 1024;
+        let node = leaf(40);
+        let mut writer = Cursor::new(Vec::new());
+        let header = node.write2(&mut writer, page_size, 1).unwrap();
+        let data = writer.into_inner();
+        assert!(header.overflow_len > 0);
+        assert_eq!(data.len() as u64, (header.overflow_len + 1) * page_size);
+        let len = data.len() as u64;
+        assert!(InternalNodes::read2(&mut Cursor::new(&data), page_size, len, true).is_ok());
+
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 0x80;
+        assert!(InternalNodes::read2(&mut Cursor::new(&corrupted), page_size, len, true).is_err());
+    }
+
+    #[test]
+    fn rejects_overflow_length_past_end_of_file() {
+        let page_size = 1024;
+        let mut writer = Cursor::new(Vec::new());
+        leaf(40).write2(&mut writer, page_size, 1).unwrap();
+        let data = writer.into_inner();
+        let len = data.len() as u64;
+
+        // Overflow length is stored right after flags and entries count
+        let offset = size_of::<u16>() + size_of::<u64>();
+        for overflow_len in [len / page_size, u64::MAX / page_size, u64::MAX] {
+            let mut corrupted = data.clone();
+            corrupted[offset..offset + 8].copy_from_slice(&overflow_len.to_be_bytes());
+            for verify_checksum in [true, false] {
+                let err = InternalNodes::read2(&mut Cursor::new(&corrupted), page_size, len, verify_checksum)
+                    .unwrap_err();
+                assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Corruption(_))), "{err}");
+                assert!(format!("{err}").contains("overflow length"), "{err}");
+            }
+        }
+    }
+
+    #[test]
+    fn converts_byte_addresses_to_page_indices() {
+        let page_size = 4096;
+        assert_eq!(ByteAddr::new(0).to_page_index(page_size), Some(PageIndex::new(0)));
+        assert_eq!(ByteAddr::new(3 * page_size).to_page_index(page_size), Some(PageIndex::new(3)));
+        assert_eq!(ByteAddr::new(3 * page_size + 1).to_page_index(page_size), None);
+        assert_eq!(ByteAddr::new(page_size - 1).to_page_index(page_size), None);
+        for index in [0, 1, 7, 1 << 40] {
+            let page = PageIndex::new(index);
+            assert_eq!(page.to_byte_addr(page_size).page_index(page_size), page);
+            assert_eq!(page.offset(2).pages_since(page), 2);
+        }
+        assert_eq!(PageIndex::new(5).to_byte_addr(1024), ByteAddr::new(5120));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid page address 100")]
+    fn rejects_unaligned_byte_address() {
+        ByteAddr::new(100).page_index(64);
+    }
+
+    #[test]
+    fn rejects_misaligned_node_address() {
+        let path = crate::test_utils::TempPath::new();
+        let page_size = 1024;
+        File::create(&path).unwrap().set_len(4 * page_size).unwrap();
+        let node_manager = NodeManager::new(&path, 1, page_size as u32, 1 << 20, Arc::default());
+        node_manager.write_node(page_size, &leaf(2), 1).unwrap();
+        assert_eq!(node_manager.read_node(page_size).unwrap().1.len(), 2);
+
+        let err = node_manager.read_node(page_size + 8).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::Misaligned {
+                address: page_size + 8,
+                page_size: page_size as u32,
+            })
+        );
+    }
+
+    #[test]
+    fn reads_ahead_only_listed_leaves() {
+        let path = crate::test_utils::TempPath::new();
+        let page_size = 1024;
+        File::create(&path).unwrap().set_len(16 * page_size).unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let node_manager = NodeManager::new(&path, 1, page_size as u32, 1 << 20, metrics.clone());
+
+        // leaf, branch, leaf with overflow, stale bytes, free list, leaf
+        node_manager.write_node(0, &leaf(2), 1).unwrap();
+        node_manager.write_node(page_size, &branch(2), 1).unwrap();
+        node_manager.write_node(2 * page_size, &leaf(20), 1).unwrap();
+        let overflow_pages = node_manager.read_node(2 * page_size).unwrap().0.overflow_len + 1;
+        let stale = 2 * page_size + overflow_pages * page_size;
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(stale)).unwrap();
+        file.write_all(&[0xFF; 64]).unwrap();
+        drop(file);
+        node_manager.write_free_list(stale + page_size, &FreeList::default(), 1).unwrap();
+        let last = stale + 2 * page_size;
+        node_manager.write_node(last, &leaf(3), 1).unwrap();
+        node_manager.nodes_cache.invalidate_all();
+
+        let reads = metrics.snapshot().node_reads;
+        let listed = [page_size, 2 * page_size, stale, stale + page_size, last];
+        node_manager.read_ahead(0, 16, &listed).unwrap();
+        assert_eq!(metrics.snapshot().node_reads, reads + 1);
+        for (page_address, cached) in [
+            (0, true),
+            (page_size, false),
+            (2 * page_size, true),
+            (stale, false),
+            (stale + page_size, false),
+            (last, true),
+        ] {
+            assert_eq!(node_manager.nodes_cache.contains_key(&page_address), cached, "page {page_address}");
+        }
+        assert_eq!(node_manager.read_node(last).unwrap().1.len(), 3);
+        assert_eq!(metrics.snapshot().node_reads, reads + 1);
+
+        // Only node itself is cached when it is not a leaf
+        node_manager.nodes_cache.invalidate_all();
+        node_manager.read_ahead(page_size, 16, &listed).unwrap();
+        assert!(node_manager.nodes_cache.contains_key(&page_size));
+        assert!(!node_manager.nodes_cache.contains_key(&(2 * page_size)));
+
+        // Reading ahead past end of file stops at its end
+        node_manager.read_ahead(last, 16, &[]).unwrap();
+        assert!(node_manager.nodes_cache.contains_key(&last));
+    }
+
+    fn branch(len: usize) -> InternalNodes {
+        InternalNodes::Branch(
+            (0..len)
+                .map(|i| BranchInternalNode {
+                    key: format!("key{i:03}").into_bytes(),
+                    node_id: NodeId::Address(i as Address * 1024),
+                    count: 1,
+                })
+                .collect(),
+        )
+    }
+
+    fn leaf(len: usize) -> InternalNodes {
+        InternalNodes::Leaf(
+            (0..len)
+                .map(|i| LeafInternalNode {
+                    key: format!("key{i:03}").into_bytes(),
+                    value: vec![0xAA; 64],
+                    expires_at: None,
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn checks_min_keys_of_branch_and_leaf_nodes() {
+        for len in 0..=MIN_KEYS_PER_PAGE * 3 {
+            let expected = len >= MIN_KEYS_PER_PAGE;
+            assert_eq!(branch(len).has_min_keys(), expected, "branch of {len} nodes");
+            assert_eq!(leaf(len).has_min_keys(), expected, "leaf of {len} nodes");
+        }
+    }
+
+    #[test]
+    fn split_nodes_have_min_keys() {
+        for len in MIN_KEYS_PER_PAGE * 2..64 {
+            for node in [branch(len), leaf(len)] {
+                let is_leaf = node.is_leaf();
+                let nodes = node.split(256, usize::MAX);
+                if len >= 32 {
+                    assert!(nodes.len() > 1, "node of {len} keys must be split");
+                }
+                assert_eq!(nodes.iter().map(|node| node.len()).sum::<usize>(), len);
+                for node in nodes.iter() {
+                    assert!(node.has_min_keys(), "split of {len} keys, leaf: {is_leaf}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn distinguishes_oversized_node_from_oversized_entry() {
+        // Without entries larger than page it is the same as exceeding page
+        for len in 0..40 {
+            for node in [branch(len), leaf(len)] {
+                assert_eq!(node.is_oversized(1024), node.size() > 1024, "{len} entries");
+            }
+        }
+
+        let InternalNodes::Leaf(mut nodes) = leaf(3) else {
+            unreachable!();
+        };
+        nodes[1].value = vec![0xBB; 2800];
+        let node = InternalNodes::Leaf(nodes);
+        assert_eq!(node.pages(1024), 3);
+        assert!(!node.is_oversized(1024), "small entries share pages of large one");
+
+        let InternalNodes::Leaf(mut nodes) = leaf(40) else {
+            unreachable!();
+        };
+        nodes[20].value = vec![0xBB; 2800];
+        let node = InternalNodes::Leaf(nodes);
+        assert!(node.is_oversized(1024));
+        let nodes = node.split(1024, usize::MAX);
+        assert!(nodes.iter().all(|node| !node.is_oversized(1024)));
+        assert_eq!(nodes.iter().map(|node| node.pages(1024)).max(), Some(3));
+    }
+
+    #[test]
+    fn split_nodes_have_at_most_max_keys() {
+        for max_keys in MIN_KEYS_PER_PAGE * 2..12 {
+            for len in 0..64 {
+                for node in [branch(len), leaf(len)] {
+                    let nodes = node.split(1 << 20, max_keys);
+                    assert_eq!(nodes.iter().map(|node| node.len()).sum::<usize>(), len);
+                    for node in nodes.iter() {
+                        assert!(node.len() <= max_keys, "split of {len} keys by {max_keys}");
+                        assert!(len < MIN_KEYS_PER_PAGE || node.has_min_keys());
+                    }
+                }
+            }
+        }
+    }
+
+    fn assert_corruption(result: Result<()>) {
+        let err = result.expect_err("out of bounds access must fail");
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Corruption(_))), "{err}");
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_child_index() {
+        let mut node = branch(2);
+        assert!(node.child(1).is_ok());
+        assert!(node.child(2).is_err());
+        assert_corruption(node.set_child_key(2, b"key".to_vec()));
+        assert_corruption(node.set_page_address(2, 1024));
+        assert_corruption(node.remove_child_at(2));
+        assert_eq!(node.len(), 2);
+
+        let mut node = leaf(2);
+        assert!(node.child(0).is_err());
+        assert_corruption(node.set_child_key(0, b"key".to_vec()));
+        assert_corruption(node.set_page_address(0, 1024));
+        assert_corruption(node.remove_child_at(0));
+        assert_eq!(node.len(), 2);
+    }
+
+    #[test]
+    fn sets_child_key_of_branch_node() {
+        let mut node = branch(3);
+        node.set_child_key(1, b"updated".to_vec()).unwrap();
+        assert_eq!(node.key_at(0), b"key000");
+        assert_eq!(node.key_at(1), b"updated");
+        assert_eq!(node.child(1).unwrap().node_id, NodeId::Address(1024));
+
+        let mut writer = Cursor::new(Vec::new());
+        node.write(&mut writer, 4096, 1).unwrap();
+        let (_, read_node) = InternalNodes::read(&mut Cursor::new(writer.into_inner())).unwrap();
+        assert_eq!(read_node.key_at(1), b"updated");
+    }
+
+    fn with_keys(node: InternalNodes, keys: &[&[u8]]) -> InternalNodes {
+        match node {
+            InternalNodes::Branch(mut nodes) => {
+                nodes.truncate(keys.len());
+                for (node, key) in nodes.iter_mut().zip(keys) {
+                    node.key = key.to_vec();
+                }
+                InternalNodes::Branch(nodes)
+            }
+            InternalNodes::Leaf(mut nodes) => {
+                nodes.truncate(keys.len());
+                for (node, key) in nodes.iter_mut().zip(keys) {
+                    node.key = key.to_vec();
+                }
+                InternalNodes::Leaf(nodes)
+            }
+        }
+    }
+
+    #[test]
+    fn validates_key_order() {
+        for node in [branch(3), leaf(3)] {
+            assert!(with_keys(node.clone(), &[]).validate().is_ok());
+            assert!(with_keys(node.clone(), &[b"a"]).validate().is_ok());
+            assert!(with_keys(node.clone(), &[b"a", b"ab", b"b"]).validate().is_ok());
+            assert!(with_keys(node.clone(), &[b"a", b"c", b"b"]).validate().is_err());
+            let err = with_keys(node, &[b"a", b"b", b"b"]).validate().unwrap_err();
+            assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Corruption(_))), "{err}");
+        }
+    }
 }
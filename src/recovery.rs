@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    free_list::FreeList,
+    node::{Address, InternalNodes, MetaNode, NodeHeader, BRANCH_NODE, FREELIST_NODE, LEAF_NODE},
+    tx::TransactionId,
+};
+
+/// Rebuilds meta nodes and free list of a database whose meta nodes are unreadable.
+///
+/// Of all nodes heading a consistent subtree, the one written by the newest transaction
+/// is chosen as root, preferring the deepest one with the most entries among nodes of
+/// that transaction. Free list is written after all nodes of a commit, so transactions
+/// newer than the latest readable free list are skipped, and nodes spilled by a transaction
+/// which did not get to commit are not mistaken for a root. Recovered tree may still
+/// be older than the latest commit if its nodes were damaged.
+pub(crate) fn recover(file_path: impl AsRef<Path>, page_size: u32) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(file_path.as_ref())?;
+    let mut scanner = Scanner {
+        page_size: page_size as u64,
+        nodes: HashMap::new(),
+        subtrees: HashMap::new(),
+        latest_free_list: None,
+    };
+    scanner.scan(&mut file)?;
+
+    let mut addresses = scanner.nodes.keys().copied().collect::<Vec<_>>();
+    addresses.sort_unstable();
+    let latest_free_list = scanner.latest_free_list;
+    let (root_transaction_id, _, entries, root_node) = addresses
+        .into_iter()
+        .filter_map(|address| {
+            let transaction_id = scanner.nodes[&address].0.transaction_id;
+            if latest_free_list.is_some_and(|latest| transaction_id > latest) {
+                return None;
+            }
+            let subtree = scanner.subtree(address)?;
+            Some((transaction_id, subtree.depth, subtree.count, address))
+        })
+        .max()
+        .ok_or_else(|| anyhow!("no valid root node found"))?;
+    // Meta nodes take the next ids, so that new transactions are newer than any node
+    let transaction_ids = [root_transaction_id + 1, root_transaction_id + 2];
+
+    let start_address = MetaNode::data_start_address(page_size);
+    let end_address = scanner.end_address(&file)?;
+    let mut free_list = FreeList {
+        free: (start_address..end_address)
+            .step_by(page_size as usize)
+            .collect(),
+        ..FreeList::default()
+    };
+    for page_address in scanner.pages(root_node) {
+        free_list.free.remove(&page_address);
+    }
+
+    // Free list is placed past the end, so it does not take any of its own pages
+    let free_list_node = end_address;
+    let free_list_size = NodeHeader::size() + free_list.size(page_size as u64) as u64;
+    let end_address = free_list_node + free_list_size.div_ceil(page_size as u64) * page_size as u64;
+    file.set_len(end_address)?;
+    file.seek(SeekFrom::Start(free_list_node))?;
+    free_list.write2(&mut file, page_size, transaction_ids[0])?;
+
+    for transaction_id in transaction_ids {
+        let meta_node = MetaNode {
+            page_size,
+            root_node,
+            free_list_node,
+            transaction_id,
+            end_address,
+            entries,
+        };
+        file.seek(SeekFrom::Start(transaction_id % 2 * MetaNode::page_size()))?;
+        meta_node.write(&mut file)?;
+    }
+    file.flush()?;
+    file.sync_data()?;
+    Ok(())
+}
+
+struct Subtree {
+    count: u64,
+    depth: usize,
+    first_key: Vec<u8>,
+    last_key: Vec<u8>,
+}
+
+struct Scanner {
+    page_size: u64,
+    nodes: HashMap<Address, (NodeHeader, InternalNodes)>,
+    /// Consistent subtrees by address of their root, `None` if subtree is broken.
+    subtrees: HashMap<Address, Option<Subtree>>,
+    /// Newest transaction which wrote a readable free list.
+    latest_free_list: Option<TransactionId>,
+}
+
+impl Scanner {
+    /// Reads every page which holds a well-formed branch or leaf node,
+    /// and notes transaction of the newest free list.
+    fn scan(&mut self, file: &mut File) -> Result<()> {
+        let start_address = MetaNode::data_start_address(self.page_size as u32);
+        let end_address = self.end_address(file)?;
+        for page_address in (start_address..end_address).step_by(self.page_size as usize) {
+            file.seek(SeekFrom::Start(page_address))?;
+            let Ok(header) = NodeHeader::read(file) else {
+                continue;
+            };
+            let pages = header.overflow_len.saturating_add(1);
+            if pages > (end_address - page_address) / self.page_size {
+                continue;
+            }
+            file.seek(SeekFrom::Start(page_address))?;
+            let max_len = end_address - page_address;
+            if header.flags == FREELIST_NODE {
+                if FreeList::read2(file, self.page_size as u32, max_len, true).is_ok() {
+                    self.latest_free_list = self.latest_free_list.max(Some(header.transaction_id));
+                }
+                continue;
+            }
+            if header.flags != BRANCH_NODE && header.flags != LEAF_NODE {
+                continue;
+            }
+            let Ok((header, node)) = InternalNodes::read2(file, self.page_size, max_len, true) else {
+                continue;
+            };
+            if node.validate().is_ok() {
+                self.nodes.insert(page_address, (header, node));
+            }
+        }
+        Ok(())
+    }
+
+    fn end_address(&self, file: &File) -> Result<Address> {
+        let len = file.metadata()?.len();
+        Ok(len - len % self.page_size)
+    }
+
+    fn subtree(&mut self, address: Address) -> Option<&Subtree> {
+        if !self.subtrees.contains_key(&address) {
+            // Marked broken while visited, so cycles are rejected
+            self.subtrees.insert(address, None);
+            let subtree = self.check_subtree(address);
+            self.subtrees.insert(address, subtree);
+        }
+        self.subtrees.get(&address).and_then(Option::as_ref)
+    }
+
+    fn check_subtree(&mut self, address: Address) -> Option<Subtree> {
+        let (header, node) = self.nodes.get(&address)?;
+        let transaction_id = header.transaction_id;
+        let children = match node {
+            InternalNodes::Leaf(nodes) => {
+                return Some(Subtree {
+                    count: nodes.len() as u64,
+                    depth: 0,
+                    first_key: nodes.first().map(|node| node.key.clone()).unwrap_or_default(),
+                    last_key: nodes.last().map(|node| node.key.clone()).unwrap_or_default(),
+                });
+            }
+            InternalNodes::Branch(nodes) if nodes.is_empty() => return None,
+            InternalNodes::Branch(nodes) => nodes.clone(),
+        };
+
+        let mut result: Option<Subtree> = None;
+        for (index, child) in children.iter().enumerate() {
+            let next_key = children.get(index + 1).map(|next| next.key.as_slice());
+            let child_address = child.node_id.node_address();
+            // Node refers only to nodes written before it or by the same transaction
+            if self.nodes.get(&child_address)?.0.transaction_id > transaction_id {
+                return None;
+            }
+            let subtree = self.subtree(child_address)?;
+            let in_bounds = subtree.first_key >= child.key
+                && next_key.is_none_or(|next_key| subtree.last_key.as_slice() < next_key);
+            if subtree.count == 0 || subtree.count != child.count || !in_bounds {
+                return None;
+            }
+            match &mut result {
+                None => {
+                    result = Some(Subtree {
+                        count: subtree.count,
+                        depth: subtree.depth + 1,
+                        first_key: subtree.first_key.clone(),
+                        last_key: subtree.last_key.clone(),
+                    });
+                }
+                Some(result) => {
+                    if result.depth != subtree.depth + 1 {
+                        return None;
+                    }
+                    result.count += subtree.count;
+                    result.last_key = subtree.last_key.clone();
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns all pages of subtree including overflow pages.
+    fn pages(&self, address: Address) -> Vec<Address> {
+        let mut pages = Vec::new();
+        let mut stack = vec![address];
+        while let Some(address) = stack.pop() {
+            let (header, node) = &self.nodes[&address];
+            pages.extend((0..=header.overflow_len).map(|i| address + i * self.page_size));
+            if let InternalNodes::Branch(nodes) = node {
+                stack.extend(nodes.iter().map(|node| node.node_id.node_address()));
+            }
+        }
+        pages
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::Result;
+
+use crate::{
+    node::{Address, InternalNodes, MetaNode, Node, NodeId, NodeManager, NodeReader},
+    tx::{ReadTransaction, TransactionId},
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub page_size: u32,
+    pub transaction_id: TransactionId,
+    pub end_address: Address,
+    pub depth: usize,
+    pub branch_nodes: u64,
+    pub leaf_nodes: u64,
+    pub overflow_pages: u64,
+    pub keys: u64,
+    pub free_pages: u64,
+}
+
+/// Physical layout of database file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileStats {
+    pub file_len: u64,
+    pub page_size: u32,
+    pub end_address: Address,
+    /// Pages in free list including ones pending to be released.
+    pub free_pages: u64,
+    /// Pages below end address taken by tree and free list nodes.
+    pub used_pages: u64,
+    /// Pages taken by both meta nodes at the start of the file.
+    pub meta_pages: u64,
+    /// Transaction id and root node address stored in each meta page,
+    /// `None` if meta page is unreadable.
+    pub meta: [Option<(TransactionId, Address)>; 2],
+}
+
+pub(crate) fn collect_file(meta_node: &MetaNode, node_manager: &NodeManager) -> Result<FileStats> {
+    let (_, free_list) = node_manager.read_free_list(meta_node.free_list_node)?;
+    let page_size = meta_node.page_size as u64;
+    let meta_pages = MetaNode::data_start_address(meta_node.page_size) / page_size;
+    let free_pages = free_list.pages_len() as u64;
+    let meta = [0, 1].map(|slot| {
+        node_manager
+            .read_meta(slot)
+            .ok()
+            .map(|meta_node| (meta_node.transaction_id, meta_node.root_node))
+    });
+    Ok(FileStats {
+        file_len: node_manager.size()?,
+        page_size: meta_node.page_size,
+        end_address: meta_node.end_address,
+        free_pages,
+        used_pages: meta_node.end_address / page_size - meta_pages - free_pages,
+        meta_pages,
+        meta,
+    })
+}
+
+pub(crate) fn collect(
+    tx: &ReadTransaction,
+    meta_node: &MetaNode,
+    node_manager: &NodeManager,
+) -> Result<Stats> {
+    let (_, free_list) = node_manager.read_free_list(meta_node.free_list_node)?;
+    let mut stats = Stats {
+        page_size: meta_node.page_size,
+        transaction_id: meta_node.transaction_id,
+        end_address: meta_node.end_address,
+        free_pages: free_list.free.len() as u64,
+        ..Stats::default()
+    };
+    collect_node(tx, tx.root_node_id(), 1, &mut stats)?;
+    Ok(stats)
+}
+
+fn collect_node(
+    tx: &ReadTransaction,
+    node_id: NodeId,
+    depth: usize,
+    stats: &mut Stats,
+) -> Result<()> {
+    let Node::ReadOnly(node) = tx.read_node(node_id)? else {
+        unreachable!("read transaction must not have dirty nodes");
+    };
+    let (header, node) = node.as_ref();
+    stats.depth = stats.depth.max(depth);
+    stats.overflow_pages += header.overflow_len;
+    match node {
+        InternalNodes::Branch(nodes) => {
+            stats.branch_nodes += 1;
+            for child in nodes {
+                collect_node(tx, child.node_id, depth + 1, stats)?;
+            }
+        }
+        InternalNodes::Leaf(nodes) => {
+            stats.leaf_nodes += 1;
+            stats.keys += nodes.len() as u64;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::{Clock, Database, GrowthPolicy, Options};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Unique database path in the system temp directory which is removed on drop.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new() -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let file_name = format!("bonsai-db-test-{}-{id}.db", std::process::id());
+        let path = std::env::temp_dir().join(file_name);
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+pub fn options() -> Options {
+    Options {
+        max_files: 4,
+        page_size: 1 << 10,
+        cache_size: 1 << 20,
+        spill_threshold: None,
+        paranoid: false,
+        on_commit: None,
+        merge_operator: None,
+        prefetch_leaves: 0,
+        read_ahead_leaves: 0,
+        initial_file_size: None,
+        growth_policy: GrowthPolicy::Double,
+        max_keys_per_node: None,
+        trace: None,
+        auto_compact: None,
+        checksum_reads: true,
+        read_only: false,
+        clock: None,
+    }
+}
+
+pub fn open_database(path: &TempPath) -> Database {
+    Database::open_or_create(path, options()).expect("open database")
+}
+
+/// Clock starting at `now` which only moves when returned time is changed.
+pub fn manual_clock(now: u64) -> (Clock, Arc<AtomicU64>) {
+    let time = Arc::new(AtomicU64::new(now));
+    let clock_time = time.clone();
+    (Arc::new(move || clock_time.load(Ordering::Relaxed)), time)
+}
+
+pub fn key(i: usize) -> Vec<u8> {
+    format!("key{i}").into_bytes()
+}
+
+pub fn value(i: usize) -> Vec<u8> {
+    format!("value{i}").into_bytes()
+}
+
+pub fn collect(cursor: &mut crate::cursor::Cursor<'_>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut entries = Vec::new();
+    while cursor.is_valid() {
+        entries.push((cursor.key().to_vec(), cursor.value().to_vec()));
+        cursor.next_entry().expect("next entry");
+    }
+    entries
+}
+
+/// Deterministic xorshift generator for randomized tests.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
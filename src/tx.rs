@@ -1,15 +1,53 @@
-use crate::cursor::Cursor;
+use crate::cursor::{Cursor, Iter, SeekBias};
 use crate::node::{
-    Address, BranchInternalNode, InternalNodes, LeafInternalNode, Node, NodeHeader, NodeId,
-    NodeReader, MIN_KEYS_PER_PAGE,
+    Address, BranchInternalNode, ByteAddr, InternalNodes, LeafInternalNode, MetaNode, Node, NodeHeader, NodeId,
+    NodeReader, MAX_KEY_SIZE, MAX_VALUE_SIZE, MIN_KEYS_PER_PAGE,
 };
-use crate::{DatabaseInternal, WriteState};
-use anyhow::{anyhow, Result};
+use crate::{DatabaseInternal, Error, WriteState};
+use anyhow::{anyhow, Context, Result};
 use std::collections::HashMap;
-use std::ops::Not;
+use std::io::Write;
+use std::ops::{Deref, Not};
 use std::sync::Arc;
+use std::time::Duration;
 
 pub type TransactionId = u64;
+pub type SavepointId = usize;
+
+/// Called by commit after every written node with number of nodes written so far
+/// and total number of dirty nodes.
+pub type CommitProgress = Box<dyn FnMut(usize, usize) + Send>;
+
+/// Stands for "no open readers" in page reclamation, so it is never given to a commit
+/// and committing fails with `Error::TransactionIdsExhausted` instead. It takes
+/// ~584 thousand years to reach at a million commits per second.
+pub const MAX_TRANSACTION_ID: TransactionId = TransactionId::MAX;
+
+/// Operation of a batch applied by `WriteTransaction::apply`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    /// Deletes keys in range `[start, end)`.
+    DeleteRange(Vec<u8>, Vec<u8>),
+}
+
+/// Logical change made by committed transaction, passed to `Options::on_commit` hook.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoggedOp {
+    Put(Vec<u8>, Vec<u8>),
+    /// Put of entry expiring at given unix time in milliseconds.
+    PutExpiring(Vec<u8>, Vec<u8>, u64),
+    Delete(Vec<u8>),
+}
+
+// Transactions may be created on one thread and used or committed on another
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<ReadTransaction>();
+    assert_send::<WriteTransaction>();
+    assert_send::<Snapshot>();
+};
 
 pub struct ReadTransaction {
     database: Arc<DatabaseInternal>,
@@ -44,6 +82,210 @@ impl ReadTransaction {
     pub fn cursor(&self) -> Result<Cursor<'_>> {
         Cursor::new(self.root_node_id, self)
     }
+
+    /// Creates cursor positioned at the last entry for descending iteration.
+    pub fn cursor_last(&self) -> Result<Cursor<'_>> {
+        let mut cursor = Cursor::new(self.root_node_id, self)?;
+        cursor.last()?;
+        Ok(cursor)
+    }
+
+    pub(crate) fn root_node_id(&self) -> NodeId {
+        self.root_node_id
+    }
+
+    /// Number of entries written by a single `export_resumable` call.
+    pub const EXPORT_CHUNK_ENTRIES: usize = 10_000;
+
+    /// Writes all live entries to `w` as JSON lines `{"key":"..","value":".."}`
+    /// with hex encoded keys and values.
+    pub fn export<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut after_key = None;
+        while let Some(last_key) = self.export_chunk(w, after_key.as_deref(), usize::MAX)? {
+            after_key = Some(last_key);
+        }
+        Ok(())
+    }
+
+    /// Writes up to `EXPORT_CHUNK_ENTRIES` entries following `after_key`, or from
+    /// the first entry if it is `None`, in the format of `export`. Returns last
+    /// written key to resume from, or `None` once there are no entries left.
+    pub fn export_resumable<W: Write>(&self, w: &mut W, after_key: Option<&[u8]>) -> Result<Option<Vec<u8>>> {
+        self.export_chunk(w, after_key, Self::EXPORT_CHUNK_ENTRIES)
+    }
+
+    /// Same as `export_resumable` with chunk of at most `limit` entries, which must not be zero.
+    pub fn export_chunk<W: Write>(
+        &self,
+        w: &mut W,
+        after_key: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut cursor = Cursor::new(self.root_node_id, self)?;
+        match after_key {
+            Some(after_key) => {
+                cursor.seek(after_key)?;
+                if cursor.is_valid() && cursor.key() == after_key {
+                    cursor.next_entry()?;
+                }
+            }
+            None => cursor.first()?,
+        }
+        let mut last_key = Vec::new();
+        let mut exported = 0;
+        while exported < limit && cursor.is_valid() {
+            write_export_entry(w, cursor.key(), cursor.value())?;
+            last_key.clear();
+            last_key.extend_from_slice(cursor.key());
+            exported += 1;
+            cursor.next_entry()?;
+        }
+        // Chunk which exported the last entry completes export
+        Ok((exported > 0 && cursor.is_valid()).then_some(last_key))
+    }
+
+    /// Returns number of keys starting with given prefix using subtree counts of branch
+    /// nodes. Empty prefix counts all keys. Expired entries which were not evicted yet
+    /// are counted, although `get` and cursors skip them, so count may exceed number of
+    /// keys a cursor yields for the prefix until `WriteTransaction::evict_expired` runs.
+    pub fn count_prefix(&self, prefix: &[u8]) -> Result<u64> {
+        let start = self.rank(prefix)?;
+        let end = match prefix_end(prefix) {
+            Some(end) => self.rank(&end)?,
+            None => self.read_node(self.root_node_id)?.as_ref().count(),
+        };
+        Ok(end - start)
+    }
+
+    /// Returns smallest key or `None` if tree is empty.
+    pub fn first_key(&self) -> Result<Option<Vec<u8>>> {
+        let mut cursor = Cursor::new(self.root_node_id, self)?;
+        cursor.first()?;
+        Ok(cursor.is_valid().then(|| cursor.key().to_vec()))
+    }
+
+    /// Returns largest key or `None` if tree is empty.
+    pub fn last_key(&self) -> Result<Option<Vec<u8>>> {
+        let mut cursor = Cursor::new(self.root_node_id, self)?;
+        cursor.last()?;
+        Ok(cursor.is_valid().then(|| cursor.key().to_vec()))
+    }
+
+    /// Returns entry with the smallest key strictly greater than `key`.
+    pub fn get_next(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.get_neighbor(key, SeekBias::Greater)
+    }
+
+    /// Returns entry with the largest key strictly less than `key`.
+    pub fn get_prev(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.get_neighbor(key, SeekBias::Less)
+    }
+
+    fn get_neighbor(&self, key: &[u8], bias: SeekBias) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut cursor = Cursor::new(self.root_node_id, self)?;
+        cursor.seek_biased(key, bias)?;
+        Ok(cursor
+            .is_valid()
+            .then(|| (cursor.key().to_vec(), cursor.value().to_vec())))
+    }
+
+    /// Calls `f` with every live entry in key order without copying keys or values.
+    /// Stops early once `f` returns `Ok(false)`.
+    pub fn for_each<F: FnMut(&[u8], &[u8]) -> Result<bool>>(&self, mut f: F) -> Result<()> {
+        self.for_each_in(self.root_node_id, self.database.now(), &mut f)?;
+        Ok(())
+    }
+
+    fn for_each_in<F: FnMut(&[u8], &[u8]) -> Result<bool>>(
+        &self,
+        node_id: NodeId,
+        now: u64,
+        f: &mut F,
+    ) -> Result<bool> {
+        let node = self.read_node(node_id)?;
+        match node.as_ref() {
+            InternalNodes::Branch(nodes) => {
+                for node in nodes {
+                    if !self.for_each_in(node.node_id, now, f)? {
+                        return Ok(false);
+                    }
+                }
+            }
+            InternalNodes::Leaf(nodes) => {
+                for node in nodes.iter().filter(|node| !node.is_expired(now)) {
+                    if !f(&node.key, &node.value)? {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns number of keys less than `key` using subtree counts of branch nodes.
+    /// Expired entries which were not evicted yet are counted.
+    pub fn rank(&self, key: &[u8]) -> Result<u64> {
+        let mut rank = 0;
+        let mut node_id = self.root_node_id;
+        loop {
+            let node = self.read_node(node_id)?;
+            match node.as_ref() {
+                InternalNodes::Branch(nodes) => {
+                    let index = nodes
+                        .partition_point(|node| node.key.as_slice() <= key)
+                        .saturating_sub(1);
+                    rank += nodes[..index].iter().map(|node| node.count).sum::<u64>();
+                    node_id = node.child(index)?.node_id;
+                }
+                InternalNodes::Leaf(nodes) => {
+                    rank += nodes.partition_point(|node| node.key.as_slice() < key) as u64;
+                    return Ok(rank);
+                }
+            }
+        }
+    }
+
+    /// Returns key at position `n` in key order or `None` if there are not enough keys.
+    /// Expired entries which were not evicted yet are counted, and if key at `n` is
+    /// expired, the next live key is returned, same as by `Cursor::seek_to_nth`.
+    pub fn select(&self, n: u64) -> Result<Option<Vec<u8>>> {
+        let mut cursor = Cursor::new(self.root_node_id, self)?;
+        Ok(cursor.seek_to_nth(n)?.then(|| cursor.key().to_vec()))
+    }
+}
+
+impl<'a> IntoIterator for &'a ReadTransaction {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self.cursor())
+    }
+}
+
+/// Clonable read view which keeps its transaction pinned
+/// until the last clone is dropped.
+#[derive(Clone)]
+pub struct Snapshot {
+    tx: Arc<ReadTransaction>,
+}
+
+impl Snapshot {
+    pub fn new(tx: ReadTransaction) -> Self {
+        Self { tx: Arc::new(tx) }
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        self.tx.transaction_id
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.tx.get(key)
+    }
+
+    pub fn cursor(&self) -> Result<Cursor<'_>> {
+        self.tx.cursor()
+    }
 }
 
 impl NodeReader for ReadTransaction {
@@ -58,17 +300,174 @@ impl NodeReader for ReadTransaction {
         };
         Ok(node)
     }
+
+    fn prefetch_leaves(&self) -> usize {
+        self.database.options.prefetch_leaves
+    }
+
+    fn prefetch(&self, page_addresses: &[Address]) {
+        self.database.node_manager.prefetch(page_addresses)
+    }
+
+    fn read_ahead_leaves(&self) -> usize {
+        self.database.options.read_ahead_leaves
+    }
+
+    fn read_ahead(&self, page_address: Address, leaf_addresses: &[Address]) -> Result<()> {
+        let pages = self.database.options.read_ahead_leaves;
+        self.database.node_manager.read_ahead(page_address, pages, leaf_addresses)
+    }
+
+    fn now(&self) -> u64 {
+        self.database.now()
+    }
+}
+
+/// Tree structure returned by `WriteTransaction::tree_debug`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeDump {
+    Branch {
+        node_id: NodeId,
+        /// Children with their separator keys.
+        children: Vec<(Vec<u8>, TreeDump)>,
+    },
+    Leaf {
+        node_id: NodeId,
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+}
+
+impl TreeDump {
+    /// Returns number of levels of tree rooted at this node.
+    pub fn depth(&self) -> usize {
+        match self {
+            Self::Branch { children, .. } => {
+                1 + children.iter().map(|(_, child)| child.depth()).max().unwrap_or(0)
+            }
+            Self::Leaf { .. } => 1,
+        }
+    }
+
+    /// Returns entries of all leaves in tree order.
+    pub fn leaves(&self) -> Vec<&Vec<(Vec<u8>, Vec<u8>)>> {
+        match self {
+            Self::Branch { children, .. } => children.iter().flat_map(|(_, child)| child.leaves()).collect(),
+            Self::Leaf { entries, .. } => vec![entries],
+        }
+    }
 }
 
 pub struct WriteTransaction {
     database: Arc<DatabaseInternal>,
     next_node_id: u64,
-    nodes: HashMap<u64, InternalNodes>,
-    parent: HashMap<u64, u64>,
+    nodes: SavedMap<InternalNodes>,
+    parent: SavedMap<u64>,
     root_node_id: NodeId,
     pending_free_pages: Vec<(Address, NodeHeader)>,
     writer: Option<WriteState>,
     transaction_id: TransactionId,
+    initial_end_address: Address,
+    savepoints: Vec<Savepoint>,
+    /// Pages written by spilling while savepoints exist, freed by `rollback_to`.
+    spilled_pages: Vec<(Address, NodeHeader)>,
+    /// Whether transaction was committed or rolled back and not changed since,
+    /// so that drop doesn't need to roll it back.
+    finished: bool,
+    /// Operations reported to commit hook. Recorded only when hook is set.
+    log: Vec<LoggedOp>,
+    /// Free pages cut off the end of file by commit, returned to free list on rollback.
+    trimmed_pages: Vec<Address>,
+    commit_progress: Option<CommitProgress>,
+    /// Nodes written and total dirty nodes while commit writes the tree.
+    nodes_written: Option<(usize, usize)>,
+}
+
+/// Transaction state restored by `rollback_to`. Dirty nodes are updated in place,
+/// so ones changed after savepoint are restored from copies kept by `SavedMap`.
+struct Savepoint {
+    next_node_id: u64,
+    root_node_id: NodeId,
+    pending_free_len: usize,
+    spilled_len: usize,
+    log_len: usize,
+}
+
+/// Map of dirty nodes or their parents by node id which can be rolled back to savepoints.
+/// Value which existed when the latest savepoint was taken is copied on its first change
+/// after it, while ones added after it are dropped on rollback by their id.
+struct SavedMap<V> {
+    values: HashMap<u64, V>,
+    /// First id allocated after each savepoint and previous values changed since.
+    saved: Vec<(u64, HashMap<u64, Option<V>>)>,
+}
+
+impl<V: Clone> SavedMap<V> {
+    fn new() -> Self {
+        Self {
+            values: HashMap::new(),
+            saved: Vec::new(),
+        }
+    }
+
+    fn get_mut(&mut self, id: &u64) -> Option<&mut V> {
+        self.save(*id);
+        self.values.get_mut(id)
+    }
+
+    fn insert(&mut self, id: u64, value: V) -> Option<V> {
+        self.save(id);
+        self.values.insert(id, value)
+    }
+
+    fn remove(&mut self, id: &u64) -> Option<V> {
+        self.save(*id);
+        self.values.remove(id)
+    }
+
+    fn clear(&mut self) {
+        self.values.clear();
+        self.saved.clear();
+    }
+
+    fn save(&mut self, id: u64) {
+        let Some((next_id, saved)) = self.saved.last_mut() else {
+            return;
+        };
+        if id < *next_id {
+            saved.entry(id).or_insert_with(|| self.values.get(&id).cloned());
+        }
+    }
+
+    fn savepoint(&mut self, next_id: u64) {
+        self.saved.push((next_id, HashMap::new()));
+    }
+
+    /// Restores values as of savepoint at `index`, which stays valid, and drops later ones.
+    fn rollback_to(&mut self, index: usize) {
+        let next_id = self.saved[index].0;
+        for (_, saved) in self.saved.drain(index..).rev() {
+            for (id, value) in saved {
+                match value {
+                    Some(value) => self.values.insert(id, value),
+                    None => self.values.remove(&id),
+                };
+            }
+        }
+        self.values.retain(|id, _| *id < next_id);
+        self.saved.push((next_id, HashMap::new()));
+    }
+
+    fn release_savepoints(&mut self) {
+        self.saved.clear();
+    }
+}
+
+impl<V> Deref for SavedMap<V> {
+    type Target = HashMap<u64, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.values
+    }
 }
 
 impl NodeReader for WriteTransaction {
@@ -84,21 +483,52 @@ impl NodeReader for WriteTransaction {
         };
         Ok(node)
     }
+
+    fn prefetch_leaves(&self) -> usize {
+        self.database.options.prefetch_leaves
+    }
+
+    fn prefetch(&self, page_addresses: &[Address]) {
+        self.database.node_manager.prefetch(page_addresses)
+    }
+
+    fn read_ahead_leaves(&self) -> usize {
+        self.database.options.read_ahead_leaves
+    }
+
+    fn read_ahead(&self, page_address: Address, leaf_addresses: &[Address]) -> Result<()> {
+        let pages = self.database.options.read_ahead_leaves;
+        self.database.node_manager.read_ahead(page_address, pages, leaf_addresses)
+    }
+
+    fn now(&self) -> u64 {
+        self.database.now()
+    }
 }
 
 impl WriteTransaction {
     pub fn new(database: Arc<DatabaseInternal>, writer: WriteState) -> Self {
-        let transaction_id = writer.meta().transaction_id + 1;
+        // Corrupted meta node may hold the sentinel, which is rejected on commit
+        let transaction_id = writer.meta().transaction_id.saturating_add(1);
         let root_node_address = writer.meta().root_node;
+        let initial_end_address = writer.meta().end_address;
         Self {
             database,
             next_node_id: 1,
-            nodes: HashMap::new(),
-            parent: HashMap::new(),
+            nodes: SavedMap::new(),
+            parent: SavedMap::new(),
             root_node_id: NodeId::Address(root_node_address),
             pending_free_pages: Vec::new(),
             writer: Some(writer),
             transaction_id,
+            initial_end_address,
+            savepoints: Vec::new(),
+            spilled_pages: Vec::new(),
+            finished: true,
+            log: Vec::new(),
+            trimmed_pages: Vec::new(),
+            commit_progress: None,
+            nodes_written: None,
         }
     }
 
@@ -111,212 +541,790 @@ impl WriteTransaction {
         Ok(Some(cursor.value().to_vec()))
     }
 
+    /// Puts entry which never expires, clearing expiry of existing entry.
     pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
-        self.update(Update::Put(key.to_vec(), value.to_vec()))
+        self.update(key, Expiry::Set(None), |_| Some(value.to_vec()))
+    }
+
+    /// Puts entry which is no longer visible to reads once `ttl` elapses.
+    /// Expired entries are removed when their leaf is rewritten or by `evict_expired`.
+    pub fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let expires_at = self.database.now().saturating_add(ttl.as_millis() as u64);
+        self.update(key, Expiry::Set(Some(expires_at)), |_| Some(value.to_vec()))
+    }
+
+    pub(crate) fn put_expiring(&mut self, key: &[u8], value: &[u8], expires_at: Option<u64>) -> Result<()> {
+        self.update(key, Expiry::Set(expires_at), |_| Some(value.to_vec()))
+    }
+
+    /// Removes all expired entries and returns their number.
+    pub fn evict_expired(&mut self) -> Result<u64> {
+        let now = self.database.now();
+        let mut keys = Vec::new();
+        self.collect_expired(self.root_node_id, now, &mut keys)?;
+        for key in keys.iter() {
+            self.update(key, Expiry::Keep, |_| None)?;
+        }
+        Ok(keys.len() as u64)
+    }
+
+    fn collect_expired(&self, node_id: NodeId, now: u64, keys: &mut Vec<Vec<u8>>) -> Result<()> {
+        let node = self.read_node_detached(node_id)?;
+        match &node.1 {
+            InternalNodes::Branch(nodes) => {
+                for node in nodes.iter() {
+                    self.collect_expired(node.node_id, now, keys)?;
+                }
+            }
+            InternalNodes::Leaf(nodes) => {
+                keys.extend(
+                    nodes
+                        .iter()
+                        .filter(|node| node.is_expired(now))
+                        .map(|node| node.key.clone()),
+                );
+            }
+        }
+        Ok(())
     }
 
     pub fn remove(&mut self, key: &[u8]) -> Result<()> {
-        self.update(Update::Delete(key.to_vec()))
+        self.update(key, Expiry::Keep, |_| None)
+    }
+
+    /// Removes key and returns its value or `None` if key did not exist.
+    pub fn remove_returning(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut removed = None;
+        self.update(key, Expiry::Keep, |current| {
+            removed = current.map(<[u8]>::to_vec);
+            None
+        })?;
+        Ok(removed)
+    }
+
+    /// Removes keys in range `[start, end)` and returns their number.
+    pub fn remove_range(&mut self, start: &[u8], end: &[u8]) -> Result<u64> {
+        let mut keys = Vec::new();
+        let mut cursor = self.cursor()?;
+        cursor.seek(start)?;
+        while cursor.is_valid() && cursor.key() < end {
+            keys.push(cursor.key().to_vec());
+            cursor.next_entry()?;
+        }
+        drop(cursor);
+        for key in keys.iter() {
+            self.update(key, Expiry::Keep, |_| None)?;
+        }
+        Ok(keys.len() as u64)
+    }
+
+    /// Applies operations in order. If an operation fails, preceding ones stay
+    /// applied and transaction should be rolled back.
+    pub fn apply(&mut self, ops: &[Op]) -> Result<()> {
+        for op in ops {
+            match op {
+                Op::Put(key, value) => self.put(key, value)?,
+                Op::Delete(key) => self.remove(key)?,
+                Op::DeleteRange(start, end) => {
+                    self.remove_range(start, end)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies operations logged by another database.
+    pub(crate) fn apply_logged(&mut self, ops: &[LoggedOp]) -> Result<()> {
+        for op in ops {
+            match op {
+                LoggedOp::Put(key, value) => self.put(key, value)?,
+                LoggedOp::PutExpiring(key, value, expires_at) => {
+                    self.put_expiring(key, value, Some(*expires_at))?
+                }
+                LoggedOp::Delete(key) => self.remove(key)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Combines current value of the key with `operand` using `Options::merge_operator`
+    /// and stores the result keeping expiry of the key. Fails if merge operator is not set.
+    pub fn put_merge(&mut self, key: &[u8], operand: &[u8]) -> Result<()> {
+        let merge_operator = self
+            .database
+            .options
+            .merge_operator
+            .clone()
+            .ok_or_else(|| anyhow!("merge operator is not set"))?;
+        self.update(key, Expiry::Keep, |current| Some(merge_operator(key, current, operand)))
+    }
+
+    /// Looks up key and returns entry to read and modify it without looking it up again.
+    pub fn entry(&mut self, key: &[u8]) -> Result<Entry<'_>> {
+        let position = self.locate(key)?;
+        let value = position.current(self).map(|value| value.to_vec());
+        Ok(Entry {
+            tx: self,
+            key: key.to_vec(),
+            value,
+            position: Some(position),
+        })
+    }
+
+    /// Calls `f` with current value of the key, or `None` if it does not exist,
+    /// and stores returned value keeping expiry of the key. Returning `None` removes the key.
+    pub fn upsert_with<F>(&mut self, key: &[u8], f: F) -> Result<()>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        self.update(key, Expiry::Keep, f)
     }
 
     pub fn cursor(&self) -> Result<Cursor<'_>> {
         Cursor::new(self.root_node_id, self)
     }
 
+    /// Creates cursor positioned at the last entry for descending iteration.
+    pub fn cursor_last(&self) -> Result<Cursor<'_>> {
+        let mut cursor = Cursor::new(self.root_node_id, self)?;
+        cursor.last()?;
+        Ok(cursor)
+    }
+
+    /// Returns id the transaction is going to be committed with.
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    /// Returns estimated memory held by dirty nodes and pages
+    /// which are going to be freed on commit.
+    pub fn dirty_bytes(&self) -> u64 {
+        let pending_free_size =
+            self.pending_free_pages.len() * std::mem::size_of::<(Address, NodeHeader)>();
+        self.dirty_nodes_bytes() + pending_free_size as u64
+    }
+
+    fn dirty_nodes_bytes(&self) -> u64 {
+        self.nodes.values().map(|node| node.size()).sum()
+    }
+
+    /// Creates cursor which can modify entries it points to.
+    pub fn cursor_mut(&mut self) -> Result<Cursor<'_>> {
+        Cursor::new_writer(self)
+    }
+
+    pub(crate) fn root_node_id(&self) -> NodeId {
+        self.root_node_id
+    }
+
+    pub(crate) fn read_node_detached(&self, node_id: NodeId) -> Result<Arc<(NodeHeader, InternalNodes)>> {
+        match self.read_node(node_id)? {
+            Node::ReadOnly(node) => Ok(node),
+            Node::Dirty(node) => {
+                let page_size = self.database.page_size as u64;
+                Ok(Arc::new((node.header(page_size), node.clone())))
+            }
+        }
+    }
+
+    /// Returns whether transaction has no changes to commit. Removing missing keys
+    /// leaves it empty, as does rolling back all its changes.
+    pub fn is_empty(&self) -> bool {
+        matches!(self.root_node_id, NodeId::Address(_))
+    }
+
+    /// Sets callback reporting progress of writing dirty nodes on commit,
+    /// e.g. to show progress of committing a large batch. Only tree nodes are
+    /// counted: once the last one is reported, commit still writes free list,
+    /// syncs file and writes meta node, none of which is reported.
+    pub fn on_commit_progress(&mut self, callback: CommitProgress) {
+        self.commit_progress = Some(callback);
+    }
+
+    /// Commits changes and releases writer. Committing empty transaction
+    /// writes nothing, so next transaction gets the same id.
     pub fn commit(mut self) -> Result<()> {
+        // Committed tree becomes visible to readers once writer is released on drop
+        self.commit_and_notify()
+    }
+
+    /// Commits transaction and begins the next one without releasing writer,
+    /// so no other writer can run in between.
+    pub fn commit_and_begin(mut self) -> Result<WriteTransaction> {
+        self.commit_and_notify()?;
+        let writer = self.writer.take().expect("writer");
+        Ok(self.database.begin_chained_write(writer))
+    }
+
+    fn commit_and_notify(&mut self) -> Result<()> {
         if let Err(e) = self.commit_internal() {
             self.rollback()?;
             return Err(e);
         }
+        self.database.metrics.add_commit();
+        if let Some(on_commit) = &self.database.options.on_commit {
+            if !self.log.is_empty() {
+                on_commit(self.transaction_id, &self.log);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remembers current transaction state so it can be restored by `rollback_to`.
+    /// Dirty nodes are copied only once they are changed after savepoint.
+    pub fn savepoint(&mut self) -> SavepointId {
+        self.savepoints.push(Savepoint {
+            next_node_id: self.next_node_id,
+            root_node_id: self.root_node_id,
+            pending_free_len: self.pending_free_pages.len(),
+            spilled_len: self.spilled_pages.len(),
+            log_len: self.log.len(),
+        });
+        self.nodes.savepoint(self.next_node_id);
+        self.parent.savepoint(self.next_node_id);
+        self.savepoints.len() - 1
+    }
+
+    /// Discards changes made after the savepoint. The savepoint stays valid,
+    /// while savepoints created after it are released.
+    pub fn rollback_to(&mut self, savepoint_id: SavepointId) -> Result<()> {
+        let savepoint = self
+            .savepoints
+            .get(savepoint_id)
+            .ok_or_else(|| anyhow!("unknown savepoint {savepoint_id}"))?;
+        self.next_node_id = savepoint.next_node_id;
+        self.root_node_id = savepoint.root_node_id;
+        self.pending_free_pages.truncate(savepoint.pending_free_len);
+        // Restored tree doesn't reference pages spilled since, so they are freed on commit
+        let spilled = self.spilled_pages.drain(savepoint.spilled_len..);
+        self.pending_free_pages.extend(spilled);
+        self.log.truncate(savepoint.log_len);
+        self.nodes.rollback_to(savepoint_id);
+        self.parent.rollback_to(savepoint_id);
+        self.savepoints.truncate(savepoint_id + 1);
         Ok(())
     }
 
+    fn release_savepoints(&mut self) {
+        self.savepoints.clear();
+        self.spilled_pages.clear();
+        self.nodes.release_savepoints();
+        self.parent.release_savepoints();
+    }
+
+    /// Discards all changes made by transaction.
+    /// Transaction is left in the same state as it was right after it began.
     pub fn rollback(&mut self) -> Result<()> {
         let writer = self.writer.as_mut().expect("writer");
+        // Pages written by transaction may have been read into nodes cache
+        let allocated_pages = writer
+            .free_list
+            .allocated_by
+            .iter()
+            .filter(|(_, transaction_id)| **transaction_id == self.transaction_id)
+            .map(|(page_address, _)| *page_address)
+            .collect();
+        self.database.node_manager.invalidate_nodes_cache(allocated_pages);
         writer.free_list.rollback(self.transaction_id);
+        writer.free_list.free.extend(self.trimmed_pages.drain(..));
+        writer.meta_mut().end_address = self.initial_end_address;
+        self.root_node_id = NodeId::Address(writer.meta().root_node);
+        self.next_node_id = 1;
+        self.nodes.clear();
+        self.parent.clear();
+        self.pending_free_pages.clear();
+        self.release_savepoints();
+        self.log.clear();
+        self.finished = true;
+        self.database.metrics.add_rollback();
         Ok(())
     }
 
     fn commit_internal(&mut self) -> Result<()> {
+        #[cfg(debug_assertions)]
+        self.check_dirty_nodes()?;
+        // Nodes written by commit are not copied for savepoints, which can't be used after it
+        self.release_savepoints();
+        if self.is_empty() {
+            // Rolling back to savepoint leaves root clean, but not pages spilled since
+            if !self.finished {
+                self.rollback()?;
+            }
+            return Ok(());
+        }
+        if self.transaction_id == MAX_TRANSACTION_ID {
+            return Err(Error::TransactionIdsExhausted.into());
+        }
+        self.relocate_tail_nodes()?;
         self.merge()?;
         self.split()?;
         let NodeId::Id(node_id) = self.root_node_id else {
+            self.finished = true;
             return Ok(());
         };
-        let (root_node_address, _) = self.traverse_write(node_id)?;
+        self.nodes_written = Some((0, self.nodes.len()));
+        let written = self.traverse_write(node_id);
+        self.nodes_written = None;
+        let (root_node_address, _, entries) = written?;
         self.root_node_id = NodeId::Address(root_node_address);
-        self.write_meta_node()?;
-        // self.database.node_manager.inv
+        self.trim_file_tail();
+        self.write_meta_node(entries)?;
+        self.shrink_file();
+        self.finished = true;
         Ok(())
     }
 
-    fn write_free_list(&mut self) -> Result<(Address, NodeHeader)> {
-        let free_list_size = {
-            let writer = self.writer.as_mut().expect("writer");
-            let size = NodeHeader::size() + writer.free_list.size() as u64;
-            let page_size = self.database.page_size as u64;
-            let pages = size.div_ceil(page_size);
-            assert!(pages > 0);
-            writer.free_list.free(
-                self.transaction_id,
-                writer.free_list_node_address,
-                writer.free_list_header.overflow_len,
-                page_size,
-            );
+    /// Makes nodes stored past the address where live pages of fully compacted file
+    /// would end dirty, so commit rewrites them into free pages closer to file start.
+    /// Their old pages are freed and later cut off the file by `trim_file_tail`.
+    /// Only branch nodes are read to find them, and at most
+    /// `AutoCompactConfig::pages_per_commit` tail pages are relocated.
+    fn relocate_tail_nodes(&mut self) -> Result<()> {
+        let Some(config) = self.database.options.auto_compact else {
+            return Ok(());
+        };
+        let page_size = self.database.page_size as u64;
+        let writer = self.writer.as_ref().expect("writer");
+        let end_address = writer.meta().end_address;
+        let free_pages = writer.free_list.pages_len() as u64;
+        let fill = 1.0 - free_pages as f64 / (end_address / page_size) as f64;
+        if fill >= config.target_fill {
+            return Ok(());
+        }
+        let live_end = end_address.saturating_sub(free_pages * page_size);
 
-            for (page_address, header) in self.pending_free_pages.drain(..) {
-                writer.free_list.free(
+        let mut paths = Vec::new();
+        let limit = config.pages_per_commit as usize;
+        let depth = self.depth()?;
+        self.find_tail_nodes(self.root_node_id, depth, live_end, limit, &mut Vec::new(), &mut paths)?;
+
+        let mut relocated = 0;
+        for path in paths {
+            if relocated >= config.pages_per_commit {
+                break;
+            }
+            let freed = self.pending_free_pages.len();
+            let mut node_id = self.dirty_root()?;
+            for child_index in path {
+                node_id = self.get_child_at_index(node_id, child_index)?;
+            }
+            relocated += self.pending_free_pages[freed..]
+                .iter()
+                .filter(|(page_address, _)| *page_address >= live_end)
+                .map(|(_, header)| header.overflow_len + 1)
+                .sum::<u64>();
+        }
+        if relocated > 0 {
+            self.database
+                .trace(|| format!("relocating {relocated} pages past address {live_end}"));
+        }
+        Ok(())
+    }
+
+    /// Collects child index paths of up to `limit` stored nodes at or past `live_end`.
+    /// Nodes at `depth` zero are leaves and are not read.
+    fn find_tail_nodes(
+        &self,
+        node_id: NodeId,
+        depth: usize,
+        live_end: Address,
+        limit: usize,
+        path: &mut Vec<usize>,
+        paths: &mut Vec<Vec<usize>>,
+    ) -> Result<()> {
+        if paths.len() >= limit {
+            return Ok(());
+        }
+        if let NodeId::Address(page_address) = node_id {
+            if page_address >= live_end {
+                paths.push(path.clone());
+            }
+        }
+        if depth == 0 {
+            return Ok(());
+        }
+        let node = self.read_node(node_id)?;
+        let InternalNodes::Branch(children) = node.as_ref() else {
+            return Err(anyhow!("expected branch node {node_id:?}"));
+        };
+        for (child_index, child) in children.iter().enumerate() {
+            path.push(child_index);
+            self.find_tail_nodes(child.node_id, depth - 1, live_end, limit, path, paths)?;
+            path.pop();
+        }
+        Ok(())
+    }
+
+    /// Returns number of branch levels above leaves.
+    fn depth(&self) -> Result<usize> {
+        let mut depth = 0;
+        let mut node = self.read_node(self.root_node_id)?;
+        while let InternalNodes::Branch(children) = node.as_ref() {
+            let child = children.first().ok_or_else(|| anyhow!("empty branch node"))?;
+            node = self.read_node(child.node_id)?;
+            depth += 1;
+        }
+        Ok(depth)
+    }
+
+    /// Returns id of root node, making it dirty first if it is stored.
+    fn dirty_root(&mut self) -> Result<u64> {
+        let NodeId::Address(page_address) = self.root_node_id else {
+            return Ok(self.root_node_id.id());
+        };
+        let (header, node) = self
+            .database
+            .node_manager
+            .read_node(page_address)?
+            .as_ref()
+            .clone();
+        let node_id = self.insert_new(node);
+        self.pending_free_pages.push((page_address, header));
+        self.root_node_id = NodeId::Id(node_id);
+        Ok(node_id)
+    }
+
+    /// Lowers end address past free pages at the end of file when auto compaction is on.
+    fn trim_file_tail(&mut self) {
+        if self.database.options.auto_compact.is_none() {
+            return;
+        }
+        let page_size = self.database.page_size as u64;
+        let writer = self.writer.as_mut().expect("writer");
+        let end_address = writer.meta().end_address;
+        let end = ByteAddr::new(end_address).page_index(page_size);
+        let trimmed = writer.free_list.trim_tail(end, page_size);
+        if trimmed.is_empty() {
+            return;
+        }
+        writer.meta_mut().end_address = end_address - trimmed.len() as u64 * page_size;
+        self.database
+            .trace(|| format!("trimmed {} free pages at end of file", trimmed.len()));
+        self.trimmed_pages.extend(trimmed);
+    }
+
+    /// Shrinks file after commit trimmed its tail. File still covers end address of
+    /// the previous meta node, so database can be opened with it if the latest
+    /// one turns out to be corrupted. Transaction is already durable, so failure
+    /// only leaves unused space at the end of file.
+    fn shrink_file(&mut self) {
+        if self.trimmed_pages.is_empty() {
+            return;
+        }
+        self.trimmed_pages.clear();
+        let writer = self.writer.as_ref().expect("writer");
+        let end_address = writer.meta().end_address.max(self.initial_end_address);
+        let node_manager = &self.database.node_manager;
+        let result = node_manager.size().and_then(|file_size| {
+            if file_size > end_address {
+                node_manager.set_size(end_address)?;
+            }
+            Ok(file_size)
+        });
+        match result {
+            Ok(file_size) if file_size > end_address => self
+                .database
+                .trace(|| format!("shrank file from {file_size} to {end_address} bytes")),
+            Ok(_) => {}
+            Err(e) => self.database.trace(|| format!("failed to shrink file: {e:#}")),
+        }
+    }
+
+    fn write_free_list(&mut self) -> Result<(Address, NodeHeader)> {
+        let free_list_size = {
+            let writer = self.writer.as_mut().expect("writer");
+            let page_size = self.database.page_size as u64;
+            let size = NodeHeader::size() + writer.free_list.size(page_size) as u64;
+            let pages = size.div_ceil(page_size);
+            assert!(pages > 0);
+            writer.free_list.free(
+                self.transaction_id,
+                ByteAddr::new(writer.free_list_node_address).page_index(page_size),
+                writer.free_list_header.overflow_len,
+                page_size,
+            );
+            let mut freed_pages = writer.free_list_header.overflow_len + 1;
+
+            for (page_address, header) in self.pending_free_pages.drain(..) {
+                writer.free_list.free(
                     self.transaction_id,
-                    page_address,
+                    ByteAddr::new(page_address).page_index(page_size),
                     header.overflow_len,
                     page_size,
                 );
+                freed_pages += header.overflow_len + 1;
             }
+            self.database.metrics.add_pages_freed(freed_pages);
 
-            writer.free_list.size() as u64 + NodeHeader::size()
+            writer.free_list.size(page_size) as u64 + NodeHeader::size()
         };
 
         let page_address = self.allocate(free_list_size)?;
-        // println!("FREE LIST SIZE FOR ALLOCATION: {free_list_size:?}, ADDRESS: {page_address:?}");
+        self.database
+            .trace(|| format!("writing free list of {free_list_size} bytes at page {page_address}"));
         let writer = self.writer.as_ref().expect("writer");
         let node_header = self
             .database
             .node_manager
-            .write_free_list(page_address, &writer.free_list)?;
+            .write_free_list(page_address, &writer.free_list, self.transaction_id)?;
         Ok((page_address, node_header))
     }
 
-    fn traverse_write(&mut self, node_id: u64) -> Result<(Address, Vec<u8>)> {
+    /// Writes dirty node with all its dirty descendants and removes them
+    /// from transaction. Returns page address, first key and entries count of written node.
+    fn traverse_write(&mut self, node_id: u64) -> Result<(Address, Vec<u8>, u64)> {
+        self.write_dirty_children(node_id)?;
+
+        let node_size = { self.nodes.get(&node_id).expect("tx node").size() };
+        let page_address = self.allocate(node_size)?;
+        let node = self.nodes.get(&node_id).expect("tx node");
+        let key = if node.is_empty() {
+            // When database is empty, return empty first key on root level
+            assert!(!self.parent.contains_key(&node_id));
+            Vec::new()
+        } else {
+            node.key_at(0).to_vec()
+        };
+        let count = node.count();
+        #[cfg(debug_assertions)]
+        self.debug_assert_separators(node);
+        self.database.node_manager.write_node(page_address, node, self.transaction_id)?;
+        if !self.savepoints.is_empty() {
+            let header = node.header(self.database.page_size as u64);
+            self.spilled_pages.push((page_address, header));
+        }
+        self.nodes.remove(&node_id);
+        self.parent.remove(&node_id);
+        if let (Some((written, total)), Some(callback)) = (&mut self.nodes_written, &mut self.commit_progress) {
+            *written += 1;
+            callback(*written, *total);
+        }
+        Ok((page_address, key, count))
+    }
+
+    /// Asserts that separators of branch being written equal first keys of its children.
+    /// Clean children are checked only if they are cached, so that check does no reads,
+    /// while `Options::paranoid` verifies the whole tree.
+    #[cfg(debug_assertions)]
+    fn debug_assert_separators(&self, node: &InternalNodes) {
+        let InternalNodes::Branch(children) = node else {
+            return;
+        };
+        for (index, child) in children.iter().enumerate() {
+            let node_manager = &self.database.node_manager;
+            let Some(child_node) = node_manager.cached_node(child.node_id.node_address()) else {
+                continue;
+            };
+            let child_node = &child_node.1;
+            if !child_node.is_empty() {
+                debug_assert_eq!(
+                    child.key.as_slice(),
+                    child_node.key_at(0),
+                    "separator at index {index} differs from first key of child"
+                );
+            }
+        }
+    }
+
+    fn write_dirty_children(&mut self, node_id: u64) -> Result<()> {
         let mut child_ref = {
             let node = self.nodes.get(&node_id).expect("node");
             node.next_dirty_child(0)
         };
 
         while let Some((child_node_id, child_node_index)) = child_ref {
-            let (child_page_address, child_key) = self.traverse_write(child_node_id)?;
+            let (child_page_address, child_key, child_count) = self.traverse_write(child_node_id)?;
             child_ref = {
                 let node = self.nodes.get_mut(&node_id).expect("node");
-                node.set_page_address(child_node_index, child_page_address);
-                node.set_child_key(child_node_index, child_key);
+                node.set_page_address(child_node_index, child_page_address)?;
+                node.set_child_key(child_node_index, child_key)?;
+                node.set_child_count(child_node_index, child_count)?;
                 node.next_dirty_child(child_node_index + 1)
             };
         }
+        Ok(())
+    }
+
+    /// Writes dirty nodes below root to disk when their size exceeds spill threshold.
+    /// Root stays dirty, so written pages are referenced only by this transaction
+    /// and are returned to free list on rollback.
+    fn spill_if_needed(&mut self) -> Result<()> {
+        let Some(spill_threshold) = self.database.options.spill_threshold else {
+            return Ok(());
+        };
+        if self.dirty_nodes_bytes() <= spill_threshold {
+            return Ok(());
+        }
 
-        let node_size = { self.nodes.get(&node_id).expect("tx node").size() };
-        let page_address = self.allocate(node_size)?;
-        let node = self.nodes.get(&node_id).expect("tx node");
-        let key = if node.is_empty() {
-            // When database is empty, return empty first key on root level
-            assert!(!self.parent.contains_key(&node_id));
-            Vec::new()
-        } else {
-            node.key_at(0).to_vec()
+        // Written nodes must be balanced since they are not revisited on commit
+        self.rebalance()?;
+        let NodeId::Id(root_node_id) = self.root_node_id else {
+            return Ok(());
         };
-        self.database.node_manager.write_node(page_address, node)?;
-        Ok((page_address, key))
+        self.write_dirty_children(root_node_id)
     }
 
-    fn write_meta_node(&mut self) -> Result<()> {
+    fn write_meta_node(&mut self, entries: u64) -> Result<()> {
         let (free_list_node_address, free_list_header) = self.write_free_list()?;
-        let writer = self.writer.as_mut().expect("writer");
-        // println!("COMMITTING FREE LIST: {:?}", writer.free_list.summary());
-        let mut meta = writer.meta().clone();
+        let mut meta = self.writer.as_ref().expect("writer").meta().clone();
         meta.transaction_id = self.transaction_id;
         meta.root_node = self.root_node_id.node_address();
         meta.free_list_node = free_list_node_address;
+        meta.entries = entries;
+        verify_if_paranoid(self, &meta)?;
+        let writer = self.writer.as_mut().expect("writer");
         self.database.node_manager.write_meta(&meta)?;
         *writer.meta_mut() = meta;
         writer.free_list_header = free_list_header;
         writer.free_list_node_address = free_list_node_address;
         writer.free_list.commit_allocations();
-        // println!("COMMITTED FREE LIST: {:?}", writer.free_list.summary());
+        self.database.trace(|| {
+            format!(
+                "committed transaction {}: root {}, {entries} entries",
+                self.transaction_id,
+                self.root_node_id.node_address()
+            )
+        });
         Ok(())
     }
 
-    pub fn traverse(&mut self) {
-        self.traverse_inner(self.root_node_id);
+    /// Writes tree to trace sink. It only reads nodes and is safe to call at any point.
+    pub fn traverse(&self) {
+        self.database
+            .trace(|| format!("{:#?}", self.tree_debug().expect("tree dump")));
     }
 
     fn allocate(&mut self, required_size: u64) -> Result<Address> {
+        if self.database.options.read_only {
+            return Err(Error::ReadOnly.into());
+        }
+        self.finished = false;
         let page_size = self.database.page_size as u64;
         let required_pages = required_size.div_ceil(page_size);
         let writer = self
             .writer
             .as_mut()
             .expect("tx writer");
-        let page_address = writer
+        let mut first_page = writer
             .free_list
             .allocate(required_pages, page_size);
-        if let Some(page_address) = page_address {
-            writer.free_list.register_allocation(page_address, self.transaction_id);
+        // Pages freed by this transaction are pending once free list is being written,
+        // and must not be released since they are still in tree seen by new readers
+        let is_releasable = !writer.free_list.pending_free.contains_key(&self.transaction_id);
+        if first_page.is_none() && is_releasable && writer.free_list.pending_pages_len() > 0 {
+            // Readers closed since transaction began may have unpinned pages which
+            // join free ones into a long enough run, so reclaim them before growing
+            self.database.release_pages(writer);
+            if writer.free_list.largest_free_run(page_size) >= required_pages {
+                first_page = writer.free_list.allocate(required_pages, page_size);
+            }
+        }
+        if let Some(first_page) = first_page {
+            writer
+                .free_list
+                .register_allocation(first_page, required_pages, page_size, self.transaction_id);
+            let page_address = first_page.to_byte_addr(page_size).get();
+            self.database.metrics.add_pages_allocated(required_pages);
+            self.database
+                .trace(|| format!("allocated {required_pages} free pages at {page_address}"));
             return Ok(page_address);
         }
         let file_size = self.database.node_manager.size()?;
-        let align = file_size % page_size;
-        assert!(align == 0);
+        assert!(ByteAddr::new(file_size).to_page_index(page_size).is_some());
 
         let page_address = writer.meta().end_address;
-        let next_end_address = writer.meta().end_address + required_pages * page_size;
+        let first_page = ByteAddr::new(page_address).page_index(page_size);
+        let next_end_address = first_page.offset(required_pages).to_byte_addr(page_size).get();
         if next_end_address > file_size {
-            // grow
-            const GB: u64 = 1 << 30;
-            let new_file_size = if file_size >= GB {
-                GB.div_ceil(page_size) * page_size
-            } else {
-                file_size << 1
-            };
-            let new_file_size = std::cmp::max(new_file_size, next_end_address);
+            let new_file_size = self.database.options.growth_policy.next_file_size(
+                file_size,
+                next_end_address,
+                page_size,
+            );
             self.database.node_manager.set_size(new_file_size)?;
+            self.database
+                .trace(|| format!("grew file from {file_size} to {new_file_size} bytes"));
         };
         writer.meta_mut().end_address = next_end_address;
-        writer.free_list.register_allocation(page_address, self.transaction_id);
+        writer
+            .free_list
+            .register_allocation(first_page, required_pages, page_size, self.transaction_id);
+        self.database.metrics.add_pages_allocated(required_pages);
+        self.database
+            .trace(|| format!("allocated {required_pages} pages at end address {page_address}"));
         Ok(page_address)
     }
 
-    fn traverse_inner(&self, node_id: NodeId) {
-        //let node = self.nodes.get(&node_id).expect("node");
-        let node = self.read_node(node_id).expect("read node");
-        let node = node.as_ref();
-        match node {
-            InternalNodes::Branch(nodes) => {
-                println!("Branch = {node_id:?}, Size={:?}", node.size());
-                let mut children_ids = Vec::new();
-                for n in nodes {
-                    println!(
-                        "\t{key:?} ->\t{child_node_id:?}",
-                        key = String::from_utf8_lossy(&n.key),
-                        child_node_id = n.node_id,
-                    );
-
-                    children_ids.push(n.node_id);
-                }
-
-                // let children_ids = children.remove(&node_id).expect("children");
-                for c in children_ids {
-                    self.traverse_inner(c);
-                }
-            }
-            InternalNodes::Leaf(nodes) => {
-                println!("Leaf = {node_id:?}, Size={:?}", node.size());
-                for n in nodes {
-                    println!(
-                        "\t{key:?} ->\t{value:?}",
-                        key = String::from_utf8_lossy(&n.key),
-                        value = String::from_utf8_lossy(&n.value),
-                    );
-                }
+    /// Returns approximate number of entries including expired ones not evicted yet.
+    /// Only leftmost, middle and rightmost paths from root are read, so it takes
+    /// O(depth) and assumes other nodes have similar fan-out. Use
+    /// `ReadTransaction::count_prefix` with empty prefix for exact count.
+    pub fn len_estimate(&self) -> Result<u64> {
+        // Sampled nodes of the current level, leftmost, middle and rightmost
+        let mut node_ids = [self.root_node_id; 3];
+        let mut estimate = 1.0;
+        loop {
+            let nodes = node_ids
+                .iter()
+                .map(|node_id| self.read_node(*node_id))
+                .collect::<Result<Vec<_>>>()?;
+            let fan_out = nodes.iter().map(|node| node.len()).sum::<usize>();
+            estimate *= fan_out as f64 / nodes.len() as f64;
+            if nodes[0].is_leaf() {
+                return Ok(estimate.round() as u64);
             }
+            let [left, middle, right] = [&nodes[0], &nodes[1], &nodes[2]].map(|node| node.as_ref());
+            node_ids = [
+                left.child(0)?.node_id,
+                middle.child(middle.len() / 2)?.node_id,
+                right.child(right.len().saturating_sub(1))?.node_id,
+            ];
         }
     }
 
-    pub fn merge(&mut self) -> Result<()> {
+    /// Returns tree structure with keys and values of all entries.
+    /// It only reads nodes and is safe to call at any point.
+    pub fn tree_debug(&self) -> Result<TreeDump> {
+        self.tree_dump(self.root_node_id)
+    }
+
+    fn tree_dump(&self, node_id: NodeId) -> Result<TreeDump> {
+        let node = self.read_node(node_id)?;
+        let dump = match node.as_ref() {
+            InternalNodes::Branch(nodes) => TreeDump::Branch {
+                node_id,
+                children: nodes
+                    .iter()
+                    .map(|node| Ok((node.key.clone(), self.tree_dump(node.node_id)?)))
+                    .collect::<Result<_>>()?,
+            },
+            InternalNodes::Leaf(nodes) => TreeDump::Leaf {
+                node_id,
+                entries: nodes
+                    .iter()
+                    .map(|node| (node.key.clone(), node.value.clone()))
+                    .collect(),
+            },
+        };
+        Ok(dump)
+    }
+
+    /// Merges underfilled nodes and then splits overfilled ones.
+    /// Commit does the same, so calling it is only needed to restructure
+    /// tree in the middle of a long transaction.
+    pub fn rebalance(&mut self) -> Result<()> {
+        self.merge()?;
+        self.split()
+    }
+
+    fn merge(&mut self) -> Result<()> {
         if let NodeId::Id(node_id) = self.root_node_id {
             self.traverse_merge(node_id, 0)?;
         }
         Ok(())
     }
 
-    pub fn split(&mut self) -> Result<()> {
+    fn split(&mut self) -> Result<()> {
         let NodeId::Id(_) = self.root_node_id else {
             return Ok(());
         };
@@ -331,7 +1339,7 @@ impl WriteTransaction {
         Ok(())
     }
 
-    pub fn traverse_merge(&mut self, node_id: u64, node_index: usize) -> Result<bool> {
+    fn traverse_merge(&mut self, node_id: u64, node_index: usize) -> Result<bool> {
         let mut child_ref = {
             let node = self.nodes.get(&node_id).expect("node");
             node.next_dirty_child(0)
@@ -360,33 +1368,68 @@ impl WriteTransaction {
         }
     }
 
-    fn update(&mut self, update: Update) -> Result<()> {
-        // Find node for update
+    fn update<F>(&mut self, key: &[u8], expiry: Expiry, f: F) -> Result<()>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let position = self.locate(key)?;
+        let value = f(position.current(self));
+        self.update_at(key, expiry, position, value)
+    }
+
+    /// Finds position of key for update.
+    fn locate(&self, key: &[u8]) -> Result<Position> {
         let mut cursor = Cursor::new(self.root_node_id, self)?;
-        cursor.seek_internal(update.key())?;
+        cursor.seek_internal(key)?;
+        let exists = cursor.is_valid() && cursor.key() == key;
+        let now = cursor.now();
+        let path = cursor
+            .stack
+            .into_iter()
+            .map(|node_ref| {
+                let node = match node_ref.node {
+                    Node::ReadOnly(node) => PathNode::ReadOnly(node_ref.node_id.node_address(), node),
+                    Node::Dirty(_) => PathNode::Dirty(node_ref.node_id.id()),
+                };
+                (node_ref.index, node)
+            })
+            .collect();
+        Ok(Position {
+            path,
+            exists,
+            now,
+        })
+    }
 
-        // Fast check if deleted key does not exist
-        if let Update::Delete(key) = &update {
-            if !cursor.is_valid() || cursor.key() != key {
-                return Ok(());
+    /// Stores `value` of key at `position` found by `locate` or removes key if it is `None`.
+    fn update_at(
+        &mut self,
+        key: &[u8],
+        expiry: Expiry,
+        position: Position,
+        value: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let Position { mut path, exists, now } = position;
+        let update = match value {
+            Some(value) => {
+                check_entry_size(key.len(), value.len())?;
+                Update::Put(value)
             }
-        }
+            None if exists => Update::Delete,
+            // Fast exit if deleted key does not exist
+            None => return Ok(()),
+        };
 
-        let mut stack = cursor.stack;
         // Collect new dirty nodes
         let mut new_dirty_nodes = Vec::new();
         let mut existing_dirty_node = None;
-        while let Some(node_ref) = stack.pop() {
-            match &node_ref.node {
-                Node::ReadOnly(node) => {
-                    new_dirty_nodes.push((
-                        node_ref.index,
-                        node_ref.node_id.node_address(),
-                        node.as_ref().clone(),
-                    ));
+        while let Some((index, node)) = path.pop() {
+            match node {
+                PathNode::ReadOnly(node_address, node) => {
+                    new_dirty_nodes.push((index, node_address, node.as_ref().clone()));
                 }
-                Node::Dirty(_) => {
-                    existing_dirty_node = Some((node_ref.index, node_ref.node_id.id()));
+                PathNode::Dirty(node_id) => {
+                    existing_dirty_node = Some((index, node_id));
                     break;
                 }
             }
@@ -420,26 +1463,48 @@ impl WriteTransaction {
             (index, nodes, node_id)
         };
 
-        match &update {
-            Update::Put(key, value) => {
-                if index < nodes.len() && key == &nodes[index].key {
-                    nodes[index].value = value.to_vec();
+        let logged_op = match update {
+            Update::Put(value) => {
+                if exists {
+                    let entry = &mut nodes[index];
+                    entry.value = value;
+                    match expiry {
+                        Expiry::Set(expires_at) => entry.expires_at = expires_at,
+                        // Expired entry is not visible, so it is replaced as if it was absent
+                        Expiry::Keep if entry.is_expired(now) => entry.expires_at = None,
+                        Expiry::Keep => {}
+                    }
                 } else {
+                    let expires_at = match expiry {
+                        Expiry::Set(expires_at) => expires_at,
+                        Expiry::Keep => None,
+                    };
                     nodes.insert(
                         index,
                         LeafInternalNode {
                             key: key.to_vec(),
-                            value: value.to_vec(),
+                            value,
+                            expires_at,
                         },
                     );
                 }
+                // Logged with resolved expiry, so that replica keeps the same one
+                let entry = &nodes[index];
+                self.database.options.on_commit.is_some().then(|| match entry.expires_at {
+                    Some(expires_at) => LoggedOp::PutExpiring(key.to_vec(), entry.value.clone(), expires_at),
+                    None => LoggedOp::Put(key.to_vec(), entry.value.clone()),
+                })
             }
-            Update::Delete(_) => {
+            Update::Delete => {
                 // No need to check index boundary because it was done in
                 // fast check earlier.
                 nodes.remove(index);
+                self.database.options.on_commit.is_some().then(|| LoggedOp::Delete(key.to_vec()))
             }
         };
+        // Leaf is rewritten anyway, so drop its expired entries
+        nodes.retain(|node| !node.is_expired(now));
+        let leaf_node_id = last_node_id;
 
         while let Some((index, _node_address, mut node)) = new_dirty_nodes.pop() {
             let InternalNodes::Branch(ref mut nodes) = node.1 else {
@@ -465,7 +1530,42 @@ impl WriteTransaction {
         if existing_dirty_node.is_none() {
             self.root_node_id = NodeId::Id(last_node_id);
         }
+        self.update_separators(leaf_node_id)?;
+
+        if let Some(logged_op) = logged_op {
+            self.log.push(logged_op);
+        }
+        self.spill_if_needed()
+    }
 
+    /// Sets separators of dirty ancestors to first key of leaf, so that they stay
+    /// equal to it before commit rewrites them. Separator of a first child is the
+    /// separator of its parent too, so it is propagated up while child is first.
+    fn update_separators(&mut self, leaf_node_id: u64) -> Result<()> {
+        let leaf = self.nodes.get(&leaf_node_id).expect("leaf node");
+        if leaf.is_empty() {
+            return Ok(());
+        }
+        let first_key = leaf.key_at(0).to_vec();
+        let mut node_id = leaf_node_id;
+        while let Some(&parent_id) = self.parent.get(&node_id) {
+            let parent = self.nodes.get_mut(&parent_id).expect("parent node");
+            let InternalNodes::Branch(children) = &*parent else {
+                unreachable!("parent is branch node");
+            };
+            let child_index = children
+                .iter()
+                .position(|child| child.node_id == NodeId::Id(node_id))
+                .ok_or_else(|| anyhow!("node {node_id} is missing in parent {parent_id}"))?;
+            if children[child_index].key == first_key {
+                break;
+            }
+            parent.set_child_key(child_index, first_key.clone())?;
+            if child_index > 0 {
+                break;
+            }
+            node_id = parent_id;
+        }
         Ok(())
     }
 
@@ -490,6 +1590,8 @@ impl WriteTransaction {
                 .map(|nodes| nodes[0].node_id.id());
 
             if let Some(new_root_id) = new_root_id {
+                self.database
+                    .trace(|| format!("collapsed root node {node_id} into its child {new_root_id}"));
                 self.root_node_id = NodeId::Id(new_root_id);
                 let parent = self.parent.remove(&new_root_id);
                 assert_eq!(parent, Some(node_id));
@@ -511,7 +1613,7 @@ impl WriteTransaction {
                 self.nodes
                     .get_mut(&parent_id)
                     .expect("parent")
-                    .remove_child_at(node_index);
+                    .remove_child_at(node_index)?;
                 return Ok(true);
             }
         }
@@ -531,6 +1633,8 @@ impl WriteTransaction {
             self.get_child_at_index(parent_id, node_index - 1)?
         };
 
+        self.database
+            .trace(|| format!("merging node {node_id} with sibling {sibling_node_id}"));
         if node_index == 0 {
             // merge with next sibling
             let next_sibling = self.nodes.remove(&sibling_node_id).expect("next sibling");
@@ -554,7 +1658,7 @@ impl WriteTransaction {
             self.nodes
                 .get_mut(&parent_id)
                 .expect("node")
-                .remove_child_at(node_index + 1);
+                .remove_child_at(node_index + 1)?;
 
             let removed = self.parent.remove(&sibling_node_id).is_some();
             assert!(removed, "parent node must be removed");
@@ -581,7 +1685,7 @@ impl WriteTransaction {
             self.nodes
                 .get_mut(&parent_id)
                 .expect("node")
-                .remove_child_at(node_index);
+                .remove_child_at(node_index)?;
 
             let removed = self.parent.remove(&node_id).is_some();
             assert!(removed, "parent node must be removed");
@@ -607,9 +1711,11 @@ impl WriteTransaction {
 
         let node = self.nodes.get(&node_id).expect("tx node");
         let page_size = self.database.node_manager.page_size() as u64;
-        let node_size = node.size();
         let node_len = node.len();
-        if node_size > page_size && node_len >= (MIN_KEYS_PER_PAGE * 2) {
+        // Entry larger than page takes overflow pages wherever it is,
+        // so only nodes taking more pages than it needs are split
+        let is_full = node.is_oversized(page_size) || node_len > self.max_keys_per_node();
+        if is_full && node_len >= (MIN_KEYS_PER_PAGE * 2) {
             let split_nodes_count = self.split_node(node_id, node_index)?;
             Ok(split_nodes_count)
         } else {
@@ -623,7 +1729,7 @@ impl WriteTransaction {
             .nodes
             .remove(&node_id)
             .expect("split node")
-            .split(page_size as u64);
+            .split(page_size as u64, self.max_keys_per_node());
 
         if nodes.len() == 1 {
             // If node was not split just re-insert it back
@@ -653,6 +1759,8 @@ impl WriteTransaction {
                 BranchInternalNode {
                     node_id: NodeId::Id(child_id),
                     key,
+                    // Updated once child is written
+                    count: 0,
                 }
             })
             .collect::<Vec<_>>();
@@ -679,18 +1787,27 @@ impl WriteTransaction {
         }
 
         let nodes_len = nodes.len();
+        self.database
+            .trace(|| format!("split node {node_id} into {nodes_len} nodes"));
         let parent = self.nodes.get_mut(&parent_id).expect("parent");
         parent.splice(node_index, nodes);
         Ok(nodes_len)
     }
 
+    fn max_keys_per_node(&self) -> usize {
+        self.database
+            .options
+            .max_keys_per_node
+            .map_or(usize::MAX, |max_keys| max_keys.max(MIN_KEYS_PER_PAGE * 2))
+    }
+
     fn get_child_at_index(&mut self, node_id: u64, child_index: usize) -> Result<u64> {
         let node = self.nodes.get(&node_id).expect("node must exist");
-        let InternalNodes::Branch(nodes) = node else {
-            panic!("expect branch node");
-        };
+        let child = node
+            .child(child_index)
+            .with_context(|| format!("failed to get child of node {node_id}"))?;
 
-        match nodes[child_index].node_id {
+        match child.node_id {
             NodeId::Id(child_node_id) => Ok(child_node_id),
             NodeId::Address(page_address) => {
                 let (header, node) = self
@@ -706,7 +1823,7 @@ impl WriteTransaction {
 
                 let node = self.nodes.get_mut(&node_id).expect("node must exist");
                 let InternalNodes::Branch(nodes) = node else {
-                    panic!("expect branch node");
+                    unreachable!("child was found in branch node");
                 };
                 nodes[child_index].node_id = NodeId::Id(child_node_id);
 
@@ -715,7 +1832,55 @@ impl WriteTransaction {
         }
     }
 
+    /// Checks that every dirty node is reachable from root and is linked to its parent,
+    /// so none of them is left out of `traverse_write`.
+    #[cfg(debug_assertions)]
+    fn check_dirty_nodes(&self) -> Result<()> {
+        let mut reachable = std::collections::HashSet::new();
+        let mut stack = match self.root_node_id {
+            NodeId::Id(node_id) => vec![node_id],
+            NodeId::Address(_) => vec![],
+        };
+        while let Some(node_id) = stack.pop() {
+            reachable.insert(node_id);
+            let node = self
+                .nodes
+                .get(&node_id)
+                .ok_or_else(|| anyhow!("dirty node {node_id} is linked but missing"))?;
+            let InternalNodes::Branch(nodes) = node else {
+                continue;
+            };
+            for child in nodes {
+                let NodeId::Id(child_id) = child.node_id else {
+                    continue;
+                };
+                let parent_id = self.parent.get(&child_id);
+                if parent_id != Some(&node_id) {
+                    return Err(anyhow!(
+                        "dirty node {child_id} is child of {node_id} but its parent is {parent_id:?}"
+                    ));
+                }
+                stack.push(child_id);
+            }
+        }
+        if reachable.len() != self.nodes.len() {
+            let mut orphaned = self
+                .nodes
+                .keys()
+                .filter(|node_id| !reachable.contains(node_id))
+                .collect::<Vec<_>>();
+            orphaned.sort_unstable();
+            return Err(anyhow!(
+                "dirty nodes {orphaned:?} are not reachable from root {:?}",
+                self.root_node_id
+            ));
+        }
+        Ok(())
+    }
+
     fn insert_new(&mut self, node: InternalNodes) -> u64 {
+        // Every change of tree starts with making a stored node dirty
+        self.finished = false;
         let id = self.next_node_id;
         self.next_node_id += 1;
         let added = self.nodes.insert(id, node).is_none();
@@ -729,23 +1894,1813 @@ impl WriteTransaction {
     }
 }
 
+/// Verifies written tree before its meta node is, so that commit fails
+/// and is rolled back instead of publishing corrupted tree.
+fn verify_if_paranoid(tx: &WriteTransaction, meta_node: &MetaNode) -> Result<()> {
+    if !tx.database.options.paranoid {
+        return Ok(());
+    }
+    let writer = tx.writer.as_ref().expect("writer");
+    let violations = crate::verify::verify(tx, meta_node, &writer.free_list)?;
+    if !violations.is_empty() {
+        let violations = violations
+            .iter()
+            .map(|violation| violation.to_string())
+            .collect::<Vec<_>>();
+        return Err(Error::Corruption(violations.join("; ")).into());
+    }
+    Ok(())
+}
+
 impl Drop for WriteTransaction {
     fn drop(&mut self) {
-        self.database
-            .release_writer(self.writer.take().expect("writer tx must own writer token"));
+        // Also runs on panic, so pages allocated by transaction are not left pending
+        if !self.finished {
+            self.rollback().expect("rollback of dropped transaction");
+        }
+        // Writer token is handed over to the next transaction by `commit_and_begin`
+        if let Some(writer) = self.writer.take() {
+            self.database.release_writer(writer);
+        }
+    }
+}
+
+fn write_export_entry<W: Write>(w: &mut W, key: &[u8], value: &[u8]) -> Result<()> {
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
     }
+    writeln!(w, r#"{{"key":"{}","value":"{}"}}"#, hex(key), hex(value))?;
+    Ok(())
+}
+
+fn check_entry_size(key_len: usize, value_len: usize) -> Result<()> {
+    if key_len > MAX_KEY_SIZE {
+        return Err(Error::KeyTooLarge(key_len).into());
+    }
+    if value_len > MAX_VALUE_SIZE {
+        return Err(Error::ValueTooLarge(value_len).into());
+    }
+    Ok(())
+}
+
+/// Returns smallest key greater than all keys starting with `prefix`,
+/// or `None` if there is no such key.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
 }
 
 enum Update {
-    Put(Vec<u8>, Vec<u8>),
-    Delete(Vec<u8>),
+    Put(Vec<u8>),
+    Delete,
 }
 
-impl Update {
-    fn key(&self) -> &[u8] {
-        match &self {
-            Self::Put(key, _) => key,
-            Self::Delete(key) => key,
+/// Expiry of entry stored by `WriteTransaction::update`.
+enum Expiry {
+    /// Keeps expiry of existing entry. New entry never expires.
+    Keep,
+    Set(Option<u64>),
+}
+
+/// Location of key in tree resolved by `WriteTransaction::locate`.
+/// It is valid only until transaction is modified.
+struct Position {
+    /// Nodes from root to leaf with index of child or entry in them.
+    path: Vec<(usize, PathNode)>,
+    /// Whether key is in leaf, expired or not.
+    exists: bool,
+    now: u64,
+}
+
+enum PathNode {
+    ReadOnly(Address, Arc<(NodeHeader, InternalNodes)>),
+    Dirty(u64),
+}
+
+impl Position {
+    /// Returns value of key unless it does not exist or has expired.
+    /// Expired entry is replaced or removed same as live one, but callers don't see it.
+    fn current<'a>(&'a self, tx: &'a WriteTransaction) -> Option<&'a [u8]> {
+        if !self.exists {
+            return None;
+        }
+        let (index, node) = self.path.last().expect("leaf");
+        let node = match node {
+            PathNode::ReadOnly(_, node) => &node.1,
+            PathNode::Dirty(node_id) => tx.nodes.get(node_id).expect("tx nodes"),
+        };
+        let InternalNodes::Leaf(nodes) = node else {
+            panic!("expected leaf node");
+        };
+        let entry = &nodes[*index];
+        (!entry.is_expired(self.now)).then_some(entry.value.as_slice())
+    }
+}
+
+/// View of a single key in write transaction returned by `WriteTransaction::entry`.
+/// Key is looked up once, so the first modification reuses found position.
+pub struct Entry<'a> {
+    tx: &'a mut WriteTransaction,
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    /// Position of key, taken by modification after which key is looked up again.
+    position: Option<Position>,
+}
+
+impl Entry<'_> {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Returns current value or `None` if key does not exist.
+    pub fn get(&self) -> Option<&[u8]> {
+        self.value.as_deref()
+    }
+
+    /// Puts `value` if key does not exist. Returns value of the key.
+    pub fn or_insert(mut self, value: &[u8]) -> Result<Vec<u8>> {
+        if self.value.is_none() {
+            self.store(Some(value.to_vec()))?;
+        }
+        Ok(self.value.expect("entry value"))
+    }
+
+    /// Calls `f` with value of existing key and stores modified value.
+    pub fn and_modify<F: FnOnce(&mut Vec<u8>)>(mut self, f: F) -> Result<Self> {
+        if let Some(mut value) = self.value.take() {
+            f(&mut value);
+            self.store(Some(value))?;
+        }
+        Ok(self)
+    }
+
+    /// Removes key returning its value or `None` if it did not exist.
+    pub fn remove(mut self) -> Result<Option<Vec<u8>>> {
+        let value = self.value.take();
+        if value.is_some() {
+            self.store(None)?;
+        }
+        Ok(value)
+    }
+
+    fn store(&mut self, value: Option<Vec<u8>>) -> Result<()> {
+        let position = match self.position.take() {
+            Some(position) => position,
+            None => self.tx.locate(&self.key)?,
+        };
+        self.tx.update_at(&self.key, Expiry::Keep, position, value.clone())?;
+        self.value = value;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::{collections::BTreeMap, sync::Mutex};
+
+    use super::*;
+    use crate::test_utils::{collect, key, open_database, value, Rng, TempPath};
+    use crate::{test_utils, Database, Options};
+
+    #[test]
+    fn counts_keys_by_prefix() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        let keys: &[&[u8]] = &[
+            b"a", b"ab", b"abc", b"abc1", b"abd", b"abd2", b"abe", b"b", b"ba", b"c", b"c\xff", b"c\xff\xff", b"d",
+        ];
+        for key in keys {
+            tx.put(key, b"value").unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        assert_eq!(tx.count_prefix(b"").unwrap(), keys.len() as u64);
+        assert_eq!(tx.count_prefix(b"a").unwrap(), 7);
+        assert_eq!(tx.count_prefix(b"ab").unwrap(), 6);
+        assert_eq!(tx.count_prefix(b"abc").unwrap(), 2);
+        assert_eq!(tx.count_prefix(b"abd").unwrap(), 2);
+        assert_eq!(tx.count_prefix(b"b").unwrap(), 2);
+        assert_eq!(tx.count_prefix(b"c").unwrap(), 3);
+        assert_eq!(tx.count_prefix(b"c\xff").unwrap(), 2);
+        assert_eq!(tx.count_prefix(b"c\xff\xff").unwrap(), 1);
+        assert_eq!(tx.count_prefix(b"abf").unwrap(), 0);
+        assert_eq!(tx.count_prefix(b"d").unwrap(), 1);
+        assert_eq!(tx.count_prefix(b"e").unwrap(), 0);
+        assert_eq!(tx.count_prefix(b"0").unwrap(), 0);
+    }
+
+    #[test]
+    fn counts_expired_keys_by_prefix_until_evicted() {
+        const TTL: Duration = Duration::from_millis(100);
+        let path = TempPath::new();
+        let (clock, time) = test_utils::manual_clock(1_000);
+        let db = Database::create(&path, test_utils::options().clock(clock)).unwrap();
+        let mut tx = db.begin_write();
+        for i in 0..500 {
+            let key = format!("key{i:03}");
+            if i % 5 == 0 {
+                tx.put_with_ttl(key.as_bytes(), b"value", TTL).unwrap();
+            } else {
+                tx.put(key.as_bytes(), b"value").unwrap();
+            }
+        }
+        tx.commit().unwrap();
+        time.fetch_add(TTL.as_millis() as u64, Ordering::Relaxed);
+
+        let count_live = |tx: &ReadTransaction, prefix: &[u8]| {
+            let mut cursor = tx.cursor().unwrap();
+            cursor.seek(prefix).unwrap();
+            let mut count = 0;
+            while cursor.is_valid() && cursor.key().starts_with(prefix) {
+                count += 1;
+                cursor.next_entry().unwrap();
+            }
+            count
+        };
+        let tx = db.begin_read();
+        assert_eq!(tx.count_prefix(b"key1").unwrap(), 100);
+        assert_eq!(count_live(&tx, b"key1"), 80);
+        assert_eq!(tx.count_prefix(b"key12").unwrap(), 10);
+        assert_eq!(count_live(&tx, b"key12"), 8);
+        assert_eq!(tx.rank(b"key100").unwrap(), 100);
+        drop(tx);
+
+        let mut tx = db.begin_write();
+        assert_eq!(tx.evict_expired().unwrap(), 100);
+        tx.commit().unwrap();
+        let tx = db.begin_read();
+        assert_eq!(tx.count_prefix(b"key1").unwrap(), 80);
+        assert_eq!(count_live(&tx, b"key1"), 80);
+        assert_eq!(tx.count_prefix(b"key12").unwrap(), 8);
+        assert_eq!(tx.rank(b"key100").unwrap(), 80);
+    }
+
+    #[test]
+    fn counts_keys_by_prefix_across_leaves() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..500 {
+            tx.put(format!("key{i:03}").as_bytes(), b"value").unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        assert_eq!(tx.count_prefix(b"key").unwrap(), 500);
+        assert_eq!(tx.count_prefix(b"key1").unwrap(), 100);
+        assert_eq!(tx.count_prefix(b"key49").unwrap(), 10);
+        assert_eq!(tx.count_prefix(b"key5").unwrap(), 0);
+    }
+
+    #[test]
+    fn counts_keys_by_prefix_in_empty_tree() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let tx = db.begin_read();
+        assert_eq!(tx.count_prefix(b"").unwrap(), 0);
+        assert_eq!(tx.count_prefix(b"a").unwrap(), 0);
+    }
+
+    #[test]
+    fn returns_no_first_and_last_key_in_empty_tree() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let tx = db.begin_read();
+        assert_eq!(tx.first_key().unwrap(), None);
+        assert_eq!(tx.last_key().unwrap(), None);
+    }
+
+    #[test]
+    fn returns_first_and_last_key() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 100..1000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        assert_eq!(tx.first_key().unwrap(), Some(key(100)));
+        assert_eq!(tx.last_key().unwrap(), Some(key(999)));
+    }
+
+    #[test]
+    fn gets_next_and_prev_entries() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in (10..30).step_by(2) {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let entry = |i| Some((key(i), value(i)));
+        // Present keys
+        assert_eq!(tx.get_next(&key(14)).unwrap(), entry(16));
+        assert_eq!(tx.get_prev(&key(14)).unwrap(), entry(12));
+        // Absent keys
+        assert_eq!(tx.get_next(&key(15)).unwrap(), entry(16));
+        assert_eq!(tx.get_prev(&key(15)).unwrap(), entry(14));
+        assert_eq!(tx.get_next(&key(0)).unwrap(), entry(10));
+        assert_eq!(tx.get_prev(&key(9)).unwrap(), entry(28));
+        // Boundary keys
+        assert_eq!(tx.get_next(&key(10)).unwrap(), entry(12));
+        assert_eq!(tx.get_prev(&key(10)).unwrap(), None);
+        assert_eq!(tx.get_next(&key(28)).unwrap(), None);
+        assert_eq!(tx.get_prev(&key(28)).unwrap(), entry(26));
+        assert_eq!(tx.get_next(&key(9)).unwrap(), None);
+        assert_eq!(tx.get_prev(&key(0)).unwrap(), None);
+    }
+
+    #[test]
+    fn fails_to_get_out_of_bounds_child() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        let leaf_id = tx.insert_new(InternalNodes::Leaf(Vec::new()));
+        let branch_id = tx.insert_new(InternalNodes::Branch(vec![BranchInternalNode {
+            key: key(0),
+            node_id: NodeId::Id(leaf_id),
+            count: 0,
+        }]));
+
+        assert_eq!(tx.get_child_at_index(branch_id, 0).unwrap(), leaf_id);
+        for (node_id, index) in [(branch_id, 1), (leaf_id, 0)] {
+            let err = tx.get_child_at_index(node_id, index).unwrap_err();
+            assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Corruption(_))), "{err}");
+        }
+    }
+
+    #[test]
+    fn updates_separator_to_first_key_of_child() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 100..300 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        tx.remove(&key(100)).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let Node::ReadOnly(root) = tx.read_node(tx.root_node_id()).unwrap() else {
+            panic!("read transaction must not have dirty nodes");
+        };
+        let InternalNodes::Branch(children) = &root.1 else {
+            panic!("expected branch root");
+        };
+        for child in children.iter() {
+            let Node::ReadOnly(child_node) = tx.read_node(child.node_id).unwrap() else {
+                panic!("read transaction must not have dirty nodes");
+            };
+            assert_eq!(child.key.as_slice(), child_node.1.key_at(0));
+        }
+        assert_eq!(tx.first_key().unwrap(), Some(key(101)));
+    }
+
+    fn assert_separators_match_first_keys(dump: &TreeDump) {
+        let TreeDump::Branch { children, .. } = dump else {
+            return;
+        };
+        for (separator, child) in children {
+            let first_key = &child.leaves().into_iter().flatten().next().expect("entry").0;
+            assert_eq!(separator, first_key);
+            assert_separators_match_first_keys(child);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "separator at index")]
+    fn asserts_separators_of_written_branch() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 100..400 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        // Caches all leaves, so that clean children are checked
+        assert_eq!(collect(&mut db.begin_read().cursor().unwrap()).len(), 300);
+
+        let mut tx = db.begin_write();
+        tx.put(&key(100), &value(0)).unwrap();
+        let NodeId::Id(root_id) = tx.root_node_id else {
+            panic!("expected dirty root");
+        };
+        let Some(InternalNodes::Branch(children)) = tx.nodes.get_mut(&root_id) else {
+            panic!("expected branch root");
+        };
+        children.last_mut().unwrap().key.push(b'0');
+        let _ = tx.commit();
+    }
+
+    #[test]
+    fn updates_separators_before_commit() {
+        let path = TempPath::new();
+        let options = test_utils::options().max_keys_per_node(Some(4));
+        let db = Database::create(&path, options).unwrap();
+        let mut tx = db.begin_write();
+        for i in (100..300).step_by(2) {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        let dump = tx.tree_debug().unwrap();
+        assert!(dump.depth() > 2);
+        let TreeDump::Branch { children, .. } = &dump else {
+            panic!("expected branch root");
+        };
+        // Key sorting right before first key of a non-leftmost child
+        let (separator, _) = &children[1];
+        let mut before_separator = separator.clone();
+        *before_separator.last_mut().unwrap() -= 1;
+        let inserted = [key(10), before_separator, key(1000)];
+        for inserted_key in &inserted {
+            tx.put(inserted_key, b"inserted").unwrap();
+            assert_separators_match_first_keys(&tx.tree_debug().unwrap());
+        }
+        // Removing first keys of leaves moves separators forward
+        for i in [100, 102, 104, 106] {
+            tx.remove(&key(i)).unwrap();
+            assert_separators_match_first_keys(&tx.tree_debug().unwrap());
+        }
+        for inserted_key in &inserted {
+            assert_eq!(tx.get(inserted_key).unwrap().as_deref(), Some(&b"inserted"[..]));
+        }
+        assert_eq!(tx.cursor().unwrap().key(), key(10));
+        tx.commit().unwrap();
+        assert_eq!(db.verify().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn commits_value_larger_than_page_among_small_keys() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let large = vec![0xAB; 5000];
+        let mut tx = db.begin_write();
+        for i in 100..200 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.put(&key(150), &large).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        assert_eq!(tx.get(&key(150)).unwrap(), Some(large.clone()));
+        for i in (100..200).filter(|i| *i != 150) {
+            assert_eq!(tx.get(&key(i)).unwrap(), Some(value(i)));
+        }
+        // Leaf with large value takes only pages it needs, while the rest fit in a page
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.overflow_pages, 5000 / 1024);
+        assert_eq!(db.verify().unwrap(), vec![]);
+
+        // Small keys keep being inserted next to large value
+        let mut tx = db.begin_write();
+        for i in 1500..1600 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        assert_eq!(db.begin_read().get(&key(150)).unwrap(), Some(large));
+        assert_eq!(db.stats().unwrap().overflow_pages, 5000 / 1024);
+        assert_eq!(db.verify().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn skips_empty_commit() {
+        let path = TempPath::new();
+        let options = test_utils::options().auto_compact(Some(Default::default()));
+        let db = Database::create(&path, options).unwrap();
+        let mut tx = db.begin_write();
+        for i in 0..500 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        assert!(!tx.is_empty());
+        tx.commit().unwrap();
+        let mut tx = db.begin_write();
+        for i in 0..400 {
+            tx.remove(&key(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        let meta = db.internal.begin_read_with_meta().1;
+
+        let tx = db.begin_write();
+        assert!(tx.is_empty());
+        tx.commit().unwrap();
+        let mut tx = db.begin_write();
+        tx.remove(b"missing").unwrap();
+        assert!(tx.is_empty());
+        tx.commit().unwrap();
+        let mut tx = db.begin_write();
+        tx.put(&key(1), &value(1)).unwrap();
+        assert!(!tx.is_empty());
+        tx.rollback().unwrap();
+        assert!(tx.is_empty());
+        tx.commit().unwrap();
+        // Sparse file is not compacted either
+        let meta_after = db.internal.begin_read_with_meta().1;
+        assert_eq!(meta_after.transaction_id, meta.transaction_id);
+        assert_eq!(meta_after.root_node, meta.root_node);
+        assert_eq!(meta_after.end_address, meta.end_address);
+        assert_eq!(db.begin_write().transaction_id(), meta.transaction_id + 1);
+    }
+
+    #[test]
+    fn tracks_dirty_bytes() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        assert_eq!(tx.dirty_bytes(), 0);
+        let mut dirty_bytes = 0;
+        for i in 0..100 {
+            tx.put(&key(i), &value(i)).unwrap();
+            assert!(tx.dirty_bytes() > dirty_bytes);
+            dirty_bytes = tx.dirty_bytes();
+        }
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        assert_eq!(tx.dirty_bytes(), 0);
+        tx.put(&key(0), &value(1)).unwrap();
+        assert!(tx.dirty_bytes() > 0);
+    }
+
+    fn open_spilling_database(path: &TempPath, spill_threshold: u64) -> Database {
+        let options = Options {
+            spill_threshold: Some(spill_threshold),
+            ..test_utils::options()
+        };
+        Database::create(path, options).unwrap()
+    }
+
+    #[test]
+    fn reports_commit_progress() {
+        fn count_nodes(dump: &TreeDump) -> usize {
+            match dump {
+                TreeDump::Branch { children, .. } => {
+                    1 + children.iter().map(|(_, child)| count_nodes(child)).sum::<usize>()
+                }
+                TreeDump::Leaf { .. } => 1,
+            }
+        }
+        let path = TempPath::new();
+        let db = Database::create(&path, test_utils::options().max_keys_per_node(Some(8))).unwrap();
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let mut tx = db.begin_write();
+        tx.on_commit_progress(Box::new({
+            let progress = progress.clone();
+            move |written, total| progress.lock().unwrap().push((written, total))
+        }));
+        for i in 0..500 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        // Every node of new tree is dirty
+        let nodes = count_nodes(&db.begin_write().tree_debug().unwrap());
+        assert!(nodes > 60, "{nodes}");
+        let expected = (1..=nodes).map(|written| (written, nodes)).collect::<Vec<_>>();
+        assert_eq!(*progress.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn spills_dirty_nodes_of_large_transaction() {
+        const SPILL_THRESHOLD: u64 = 16 << 10;
+        let path = TempPath::new();
+        let db = open_spilling_database(&path, SPILL_THRESHOLD);
+        let mut rng = Rng::new(42);
+        let mut expected = BTreeMap::new();
+        let mut tx = db.begin_write();
+        for _ in 0..20_000 {
+            let i = rng.below(5_000) as usize;
+            if rng.below(4) == 0 {
+                tx.remove(&key(i)).unwrap();
+                expected.remove(&key(i));
+            } else {
+                tx.put(&key(i), &value(i)).unwrap();
+                expected.insert(key(i), value(i));
+            }
+            assert!(tx.dirty_nodes_bytes() <= SPILL_THRESHOLD);
+        }
+        assert_eq!(collect(&mut tx.cursor().unwrap()).len(), expected.len());
+        tx.commit().unwrap();
+
+        assert!(db.verify().unwrap().is_empty());
+        let tx = db.begin_read();
+        let entries = collect(&mut tx.cursor().unwrap());
+        assert_eq!(entries, expected.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn rolls_back_spilled_transaction() {
+        let path = TempPath::new();
+        let db = open_spilling_database(&path, 4 << 10);
+        let mut tx = db.begin_write();
+        for i in 0..100 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        for i in 100..5_000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.rollback().unwrap();
+        tx.put(&key(0), &value(1)).unwrap();
+        tx.commit().unwrap();
+
+        assert!(db.verify().unwrap().is_empty());
+        let tx = db.begin_read();
+        let entries = collect(&mut tx.cursor().unwrap());
+        assert_eq!(entries.len(), 100);
+        assert_eq!(tx.get(&key(0)).unwrap(), Some(value(1)));
+        assert_eq!(tx.get(&key(100)).unwrap(), None);
+    }
+
+    #[test]
+    fn rolls_back_spilled_nodes_to_savepoint() {
+        let path = TempPath::new();
+        let db = open_spilling_database(&path, 4 << 10);
+        let mut tx = db.begin_write();
+        for i in 0..1000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        let savepoint = tx.savepoint();
+        // Spills new nodes and changes ones spilled before savepoint
+        for i in 1000..5000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        for i in 0..500 {
+            tx.remove(&key(i)).unwrap();
+        }
+        assert!(!tx.spilled_pages.is_empty());
+        assert!(tx.dirty_nodes_bytes() <= 4 << 10);
+        tx.rollback_to(savepoint).unwrap();
+        tx.commit().unwrap();
+
+        assert!(db.verify().unwrap().is_empty());
+        let tx = db.begin_read();
+        let mut expected = (0..1000).map(|i| (key(i), value(i))).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(collect(&mut tx.cursor().unwrap()), expected);
+        drop(tx);
+
+        // Pages spilled after savepoint are freed rather than leaked
+        let stats = db.stats().unwrap();
+        let meta = db.internal.begin_read_with_meta().1;
+        let (free_list_header, _) = db.internal.node_manager.read_free_list(meta.free_list_node).unwrap();
+        let tree_pages = stats.branch_nodes + stats.leaf_nodes + stats.overflow_pages;
+        assert_eq!(
+            db.file_stats().unwrap().used_pages,
+            tree_pages + free_list_header.overflow_len + 1
+        );
+    }
+
+    #[test]
+    fn creates_cursor_at_last_entry() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        assert!(!db.begin_read().cursor_last().unwrap().is_valid());
+        assert!(!db.begin_write().cursor_last().unwrap().is_valid());
+
+        let mut tx = db.begin_write();
+        for i in 100..1000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        let cursor = tx.cursor_last().unwrap();
+        assert_eq!(cursor.key(), key(999));
+        drop(cursor);
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor_last().unwrap();
+        let mut keys = Vec::new();
+        while cursor.is_valid() {
+            keys.push(cursor.key().to_vec());
+            cursor.prev_entry().unwrap();
+        }
+        let mut expected = (100..1000).map(key).collect::<Vec<_>>();
+        expected.reverse();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn rejects_too_large_entries() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        assert_eq!(db.max_key_size(), u16::MAX as usize);
+        assert_eq!(db.max_value_size(), u32::MAX as usize);
+
+        let mut tx = db.begin_write();
+        let max_key = vec![b'k'; db.max_key_size()];
+        tx.put(&max_key, &value(0)).unwrap();
+        let too_large_key = vec![b'k'; db.max_key_size() + 1];
+        let err = tx.put(&too_large_key, &value(0)).unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::KeyTooLarge(db.max_key_size() + 1)));
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        assert_eq!(tx.get(&max_key).unwrap(), Some(value(0)));
+        assert_eq!(tx.get(&too_large_key).unwrap(), None);
+
+        // Values are not allocated since limit does not fit into memory of a test
+        assert!(check_entry_size(1, MAX_VALUE_SIZE).is_ok());
+        let err = check_entry_size(1, MAX_VALUE_SIZE + 1).unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::ValueTooLarge(MAX_VALUE_SIZE + 1)));
+    }
+
+    #[test]
+    fn paranoid_commit_reports_violations() {
+        let path = TempPath::new();
+        let options = Options {
+            paranoid: true,
+            ..test_utils::options()
+        };
+        let db = Database::create(&path, options).unwrap();
+        let mut tx = db.begin_write();
+        for i in 0..10 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        let NodeId::Id(root_node_id) = tx.root_node_id else {
+            panic!("root must be dirty");
+        };
+        let InternalNodes::Leaf(nodes) = tx.nodes.get_mut(&root_node_id).unwrap() else {
+            panic!("root must be a leaf");
+        };
+        nodes.swap(0, 1);
+
+        let err = tx.commit().unwrap_err();
+        let Some(Error::Corruption(description)) = err.downcast_ref::<Error>() else {
+            panic!("unexpected error: {err}");
+        };
+        assert!(description.contains("out of order"), "{description}");
+
+        // Commit failed before meta node was written, so corrupted tree is not visible
+        assert_eq!(db.key_count(), 0);
+        assert_eq!(db.begin_read().first_key().unwrap(), None);
+        drop(db);
+        let db = Database::open(&path, test_utils::options()).unwrap();
+        assert_eq!(db.key_count(), 0);
+        assert_eq!(db.verify().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn removes_key_returning_value() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..100 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        assert_eq!(tx.remove_returning(&key(42)).unwrap(), Some(value(42)));
+        assert_eq!(tx.remove_returning(&key(42)).unwrap(), None);
+        assert_eq!(tx.remove_returning(&key(100)).unwrap(), None);
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        assert_eq!(tx.get(&key(42)).unwrap(), None);
+        assert_eq!(tx.count_prefix(b"").unwrap(), 99);
+    }
+
+    #[test]
+    fn exports_in_resumable_chunks() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 1000..1250 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.put_with_ttl(&key(1100), b"expired", Duration::ZERO).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let mut full = Vec::new();
+        tx.export(&mut full).unwrap();
+        let full = String::from_utf8(full).unwrap();
+        assert_eq!(full.lines().count(), 249);
+        assert_eq!(
+            full.lines().next().unwrap(),
+            r#"{"key":"6b657931303030","value":"76616c756531303030"}"#
+        );
+
+        let mut chunked = Vec::new();
+        let mut after_key = None;
+        let mut chunks = 0;
+        loop {
+            let last_key = tx.export_chunk(&mut chunked, after_key.as_deref(), 100).unwrap();
+            chunks += 1;
+            let Some(last_key) = last_key else {
+                break;
+            };
+            after_key = Some(last_key);
+        }
+        assert_eq!(chunks, 3);
+        assert_eq!(String::from_utf8(chunked).unwrap(), full);
+
+        // Resumes after key which no longer exists
+        let mut rest = Vec::new();
+        assert_eq!(tx.export_chunk(&mut rest, Some(&key(1100)), 1).unwrap(), Some(key(1101)));
+        assert_eq!(tx.export_resumable(&mut rest, Some(&key(1248))).unwrap(), None);
+        let mut expected = Vec::new();
+        write_export_entry(&mut expected, &key(1101), &value(1101)).unwrap();
+        write_export_entry(&mut expected, &key(1249), &value(1249)).unwrap();
+        assert_eq!(rest, expected);
+    }
+
+    #[test]
+    fn scans_entries_with_callback() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..2000 {
+            tx.put(&key(i), &value(i).repeat(i % 7 + 1)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let mut cursor = tx.cursor().unwrap();
+        let mut expected = 0;
+        while cursor.is_valid() {
+            expected += cursor.value().len();
+            cursor.next_entry().unwrap();
+        }
+        let mut total = 0;
+        tx.for_each(|_, value| {
+            total += value.len();
+            Ok(true)
+        })
+        .unwrap();
+        assert_eq!(total, expected);
+    }
+
+    #[test]
+    fn iterates_over_read_transaction() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in (100..1000).rev() {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        let mut entries = Vec::new();
+        for entry in &tx {
+            entries.push(entry.unwrap());
+        }
+        let expected = (100..1000).map(|i| (key(i), value(i))).collect::<Vec<_>>();
+        assert_eq!(entries, expected);
+        assert_eq!((&tx).into_iter().collect::<Result<Vec<_>>>().unwrap(), expected);
+
+        let mut cursor = tx.cursor().unwrap();
+        cursor.seek(&key(990)).unwrap();
+        assert_eq!(cursor.into_iter().count(), 10);
+    }
+
+    #[test]
+    fn stops_scan_early() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 100..600 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut keys = Vec::new();
+        db.begin_read()
+            .for_each(|key, _| {
+                keys.push(key.to_vec());
+                Ok(keys.len() < 10)
+            })
+            .unwrap();
+        assert_eq!(keys, (100..110).map(key).collect::<Vec<_>>());
+
+        let result = db.begin_read().for_each(|_, _| Err(anyhow!("stop")));
+        assert_eq!(result.unwrap_err().to_string(), "stop");
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn detects_orphaned_dirty_nodes() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 100..200 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        let orphan_id = tx.insert_new(InternalNodes::Leaf(vec![LeafInternalNode {
+            key: key(1),
+            value: value(1),
+            expires_at: None,
+        }]));
+        let err = tx.commit().unwrap_err();
+        assert!(
+            err.to_string().contains(&format!("dirty nodes [{orphan_id}] are not reachable")),
+            "{err}"
+        );
+        assert_eq!(db.begin_read().count_prefix(b"").unwrap(), 0);
+    }
+
+    #[test]
+    fn hides_expired_entries() {
+        const TTL: Duration = Duration::from_millis(200);
+        let path = TempPath::new();
+        let (clock, time) = test_utils::manual_clock(1_000);
+        let db = Database::open_or_create(&path, test_utils::options().clock(clock)).unwrap();
+        let mut tx = db.begin_write();
+        for i in 100..300 {
+            if i % 2 == 0 {
+                tx.put(&key(i), &value(i)).unwrap();
+            } else {
+                tx.put_with_ttl(&key(i), &value(i), TTL).unwrap();
+            }
+        }
+        tx.put_with_ttl(&key(300), &value(300), TTL).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(db.begin_read().count_prefix(b"").unwrap(), 201);
+
+        time.fetch_add(TTL.as_millis() as u64 - 1, Ordering::Relaxed);
+        assert_eq!(db.begin_read().get(&key(101)).unwrap(), Some(value(101)));
+        time.fetch_add(1, Ordering::Relaxed);
+        let tx = db.begin_read();
+        assert_eq!(tx.get(&key(101)).unwrap(), None);
+        assert_eq!(tx.get(&key(102)).unwrap(), Some(value(102)));
+        let entries = collect(&mut tx.cursor().unwrap());
+        let expected = (100..300).step_by(2).map(|i| (key(i), value(i))).collect::<Vec<_>>();
+        assert_eq!(entries, expected);
+        assert_eq!(tx.last_key().unwrap(), Some(key(298)));
+
+        let mut cursor = tx.cursor_last().unwrap();
+        let mut keys = Vec::new();
+        while cursor.is_valid() {
+            keys.push(cursor.key().to_vec());
+            cursor.prev_entry().unwrap();
+        }
+        assert_eq!(keys.len(), 100);
+
+        // Expired entry is seen as absent and replaced without its expiry
+        let mut tx = db.begin_write();
+        tx.upsert_with(&key(101), |current| {
+            assert_eq!(current, None);
+            Some(value(0))
+        })
+        .unwrap();
+        tx.commit().unwrap();
+        time.fetch_add(TTL.as_millis() as u64, Ordering::Relaxed);
+        assert_eq!(db.begin_read().get(&key(101)).unwrap(), Some(value(0)));
+    }
+
+    #[test]
+    fn evicts_expired_entries() {
+        const TTL: Duration = Duration::from_millis(100);
+        let path = TempPath::new();
+        let (clock, time) = test_utils::manual_clock(1_000);
+        let db = Database::open_or_create(&path, test_utils::options().clock(clock)).unwrap();
+        let mut tx = db.begin_write();
+        for i in 1000..2000 {
+            tx.put_with_ttl(&key(i), &value(i), TTL).unwrap();
+        }
+        for i in 2000..2010 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        let stats_before = db.stats().unwrap();
+
+        let mut tx = db.begin_write();
+        assert_eq!(tx.evict_expired().unwrap(), 0);
+        drop(tx);
+        time.fetch_add(TTL.as_millis() as u64, Ordering::Relaxed);
+        let mut tx = db.begin_write();
+        assert_eq!(tx.evict_expired().unwrap(), 1000);
+        assert_eq!(tx.evict_expired().unwrap(), 0);
+        tx.commit().unwrap();
+        drop(db.begin_write());
+
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.keys, 10);
+        assert!(stats.leaf_nodes < stats_before.leaf_nodes);
+        assert!(stats.free_pages > stats_before.free_pages);
+        assert!(db.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_expiry_on_read_modify_write() {
+        const TTL: Duration = Duration::from_millis(100);
+        let path = TempPath::new();
+        let (clock, time) = test_utils::manual_clock(1_000);
+        let options = test_utils::options()
+            .clock(clock)
+            .merge_operator(Arc::new(|_key, current, operand| [current.unwrap_or_default(), operand].concat()));
+        let db = Database::open_or_create(&path, options).unwrap();
+        let mut tx = db.begin_write();
+        for i in 0..6 {
+            tx.put_with_ttl(&key(i), &value(i), TTL).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        tx.upsert_with(&key(0), |_| Some(value(10))).unwrap();
+        tx.put_merge(&key(1), b"+").unwrap();
+        tx.entry(&key(2)).unwrap().and_modify(|value| value.push(b'+')).unwrap();
+        let mut cursor = tx.cursor_mut().unwrap();
+        cursor.seek(&key(3)).unwrap();
+        cursor.put(&value(13)).unwrap();
+        drop(cursor);
+        // Plain puts set expiry explicitly
+        tx.put(&key(4), &value(14)).unwrap();
+        tx.put_with_ttl(&key(5), &value(15), TTL * 2).unwrap();
+        // New entry written by read-modify-write never expires
+        tx.upsert_with(&key(6), |_| Some(value(16))).unwrap();
+        tx.commit().unwrap();
+
+        time.fetch_add(TTL.as_millis() as u64, Ordering::Relaxed);
+        let tx = db.begin_read();
+        for i in 0..4 {
+            assert_eq!(tx.get(&key(i)).unwrap(), None, "{i}");
+        }
+        assert_eq!(tx.get(&key(4)).unwrap(), Some(value(14)));
+        assert_eq!(tx.get(&key(5)).unwrap(), Some(value(15)));
+        assert_eq!(tx.get(&key(6)).unwrap(), Some(value(16)));
+    }
+
+    #[test]
+    fn applies_batch_of_operations() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 100..200 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        tx.apply(&[
+            Op::Put(key(200), value(200)),
+            Op::Delete(key(100)),
+            Op::DeleteRange(key(110), key(190)),
+            Op::Put(key(150), value(0)),
+        ])
+        .unwrap();
+        tx.commit().unwrap();
+
+        let mut expected = (101..110).chain(190..201).map(|i| (key(i), value(i))).collect::<Vec<_>>();
+        expected.insert(9, (key(150), value(0)));
+        let tx = db.begin_read();
+        assert_eq!(collect(&mut tx.cursor().unwrap()), expected);
+    }
+
+    #[test]
+    fn rolls_back_partially_applied_batch() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 100..200 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        let err = tx
+            .apply(&[
+                Op::DeleteRange(key(100), key(150)),
+                Op::Put(vec![0; MAX_KEY_SIZE + 1], value(0)),
+                Op::Delete(key(199)),
+            ])
+            .err()
+            .unwrap();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::KeyTooLarge(MAX_KEY_SIZE + 1)));
+        assert_eq!(tx.get(&key(100)).unwrap(), None);
+        assert_eq!(tx.get(&key(199)).unwrap(), Some(value(199)));
+
+        tx.rollback().unwrap();
+        tx.commit().unwrap();
+        let expected = (100..200).map(|i| (key(i), value(i))).collect::<Vec<_>>();
+        assert_eq!(collect(&mut db.begin_read().cursor().unwrap()), expected);
+    }
+
+    #[test]
+    fn replays_logged_operations() {
+        type Log = Vec<(TransactionId, Vec<LoggedOp>)>;
+        let log = Arc::new(Mutex::new(Log::new()));
+        let leader_path = TempPath::new();
+        let options = test_utils::options().on_commit({
+            let log = log.clone();
+            Arc::new(move |transaction_id, ops| {
+                log.lock().unwrap().push((transaction_id, ops.to_vec()));
+            })
+        });
+        let leader = Database::create(&leader_path, options).unwrap();
+
+        let mut rng = Rng::new(7);
+        for round in 0..20 {
+            let mut tx = leader.begin_write();
+            for _ in 0..50 {
+                let i = rng.below(300) as usize;
+                match rng.below(4) {
+                    0 => tx.remove(&key(i)).unwrap(),
+                    1 => tx.put_with_ttl(&key(i), &value(round), Duration::from_secs(3600)).unwrap(),
+                    _ => tx.put(&key(i), &value(round)).unwrap(),
+                }
+            }
+            let savepoint = tx.savepoint();
+            tx.remove_range(&key(100), &key(200)).unwrap();
+            tx.rollback_to(savepoint).unwrap();
+            if round % 5 == 4 {
+                tx.rollback().unwrap();
+            }
+            tx.commit().unwrap();
+        }
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 16);
+        let follower_path = TempPath::new();
+        let follower = open_database(&follower_path);
+        for (transaction_id, ops) in log.iter() {
+            follower.apply_log(*transaction_id, ops).unwrap();
+        }
+
+        let leader_tx = leader.begin_read();
+        let follower_tx = follower.begin_read();
+        let mut leader_cursor = leader_tx.cursor().unwrap();
+        let mut follower_cursor = follower_tx.cursor().unwrap();
+        while leader_cursor.is_valid() {
+            assert!(follower_cursor.is_valid());
+            assert_eq!(leader_cursor.key(), follower_cursor.key());
+            assert_eq!(leader_cursor.value(), follower_cursor.value());
+            assert_eq!(leader_cursor.expires_at(), follower_cursor.expires_at());
+            leader_cursor.next_entry().unwrap();
+            follower_cursor.next_entry().unwrap();
+        }
+        assert!(!follower_cursor.is_valid());
+        assert!(leader.verify().unwrap().is_empty());
+        assert!(follower.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn ranks_and_selects_keys() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in (10_000..30_000).step_by(2) {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        assert!(db.stats().unwrap().depth > 2);
+        let keys = collect(&mut tx.cursor().unwrap())
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        for (n, key) in keys.iter().enumerate() {
+            assert_eq!(tx.select(n as u64).unwrap().as_ref(), Some(key));
+            assert_eq!(tx.rank(key).unwrap(), n as u64);
+        }
+        assert_eq!(tx.select(keys.len() as u64).unwrap(), None);
+        assert_eq!(tx.rank(b"").unwrap(), 0);
+        assert_eq!(tx.rank(&key(10_001)).unwrap(), 1);
+        assert_eq!(tx.rank(b"z").unwrap(), keys.len() as u64);
+    }
+
+    #[test]
+    fn keeps_subtree_counts_during_churn() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut rng = Rng::new(11);
+        let mut expected = BTreeMap::new();
+        for _ in 0..30 {
+            let mut tx = db.begin_write();
+            for _ in 0..200 {
+                let i = rng.below(2_000) as usize;
+                if rng.below(3) == 0 {
+                    tx.remove(&key(i)).unwrap();
+                    expected.remove(&key(i));
+                } else {
+                    tx.put(&key(i), &value(i)).unwrap();
+                    expected.insert(key(i), value(i));
+                }
+            }
+            tx.commit().unwrap();
+            assert!(db.verify().unwrap().is_empty());
+
+            let tx = db.begin_read();
+            assert_eq!(tx.rank(b"z").unwrap(), expected.len() as u64);
+            let n = rng.below(expected.len() as u64);
+            let key = expected.keys().nth(n as usize);
+            assert_eq!(tx.select(n).unwrap().as_ref(), key);
+        }
+    }
+
+    #[test]
+    fn caps_keys_per_node() {
+        let path = TempPath::new();
+        let options = test_utils::options().max_keys_per_node(Some(8));
+        let db = Database::create(&path, options).unwrap();
+        let mut tx = db.begin_write();
+        for i in 0..=255u8 {
+            tx.put(&[i], &[]).unwrap();
+        }
+        tx.commit().unwrap();
+        assert!(db.verify().unwrap().is_empty());
+
+        let tx = db.begin_read();
+        let mut stack = vec![tx.root_node_id()];
+        let mut leaves = 0;
+        while let Some(node_id) = stack.pop() {
+            let node = tx.read_node(node_id).unwrap();
+            assert!(node.len() <= 8, "node has {} keys", node.len());
+            match node.as_ref() {
+                InternalNodes::Branch(nodes) => stack.extend(nodes.iter().map(|node| node.node_id)),
+                InternalNodes::Leaf(_) => leaves += 1,
+            }
+        }
+        assert!(leaves >= 256 / 8);
+        assert_eq!(tx.count_prefix(b"").unwrap(), 256);
+    }
+
+    #[test]
+    fn chains_transactions_without_releasing_writer() {
+        let chained_path = TempPath::new();
+        let chained_db = open_database(&chained_path);
+        let path = TempPath::new();
+        let db = open_database(&path);
+
+        let mut chained_tx = chained_db.begin_write();
+        for round in 0..20 {
+            let mut tx = db.begin_write();
+            for i in 0..100 {
+                let i = 1000 + (round * 37 + i * 13) % 900;
+                chained_tx.put(&key(i), &value(round)).unwrap();
+                tx.put(&key(i), &value(round)).unwrap();
+            }
+            chained_tx.remove(&key(1000 + round)).unwrap();
+            tx.remove(&key(1000 + round)).unwrap();
+            tx.commit().unwrap();
+
+            chained_tx = chained_tx.commit_and_begin().unwrap();
+            assert!(chained_db.try_begin_write().is_none());
+            assert_eq!(
+                collect(&mut chained_db.begin_read().cursor().unwrap()),
+                collect(&mut db.begin_read().cursor().unwrap())
+            );
+        }
+        drop(chained_tx);
+        assert!(chained_db.try_begin_write().is_some());
+        assert_eq!(chained_db.key_count(), db.key_count());
+        assert!(chained_db.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn dumps_tree() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        tx.put(b"a", b"1").unwrap();
+        tx.put(b"b", b"2").unwrap();
+        let dump = tx.tree_debug().unwrap();
+        assert!(matches!(dump, TreeDump::Leaf { node_id: NodeId::Id(_), .. }));
+        assert_eq!(dump.depth(), 1);
+        assert_eq!(dump.leaves(), vec![&vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"2".to_vec())]]);
+
+        for i in 1000..3000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+        let tx = db.begin_write();
+        let dump = tx.tree_debug().unwrap();
+        let TreeDump::Branch { node_id, ref children } = dump else {
+            panic!("root must be a branch");
+        };
+        assert!(matches!(node_id, NodeId::Address(_)));
+        assert!(children.len() > 1);
+        assert!(dump.depth() > 1);
+        assert_eq!(dump.depth(), db.stats().unwrap().depth);
+        let entries = dump.leaves().into_iter().flatten().cloned().collect::<Vec<_>>();
+        let mut expected = vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"2".to_vec())];
+        expected.extend((1000..3000).map(|i| (key(i), value(i))));
+        expected.sort();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn estimates_number_of_entries() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let tx = db.begin_write();
+        assert_eq!(tx.len_estimate().unwrap(), 0);
+        drop(tx);
+
+        let mut rng = Rng::new(7);
+        let mut tx = db.begin_write();
+        for i in 0..10 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        assert_eq!(tx.len_estimate().unwrap(), 10);
+        for _ in 0..20_000 {
+            let i = 100_000 + rng.below(900_000);
+            tx.put(&key(i as usize), &value(0)).unwrap();
+        }
+        // Estimate of dirty tree
+        let count = collect(&mut tx.cursor().unwrap()).len() as u64;
+        let estimate = tx.len_estimate().unwrap();
+        assert!(estimate > count / 2 && estimate < count * 2, "estimate {estimate}, count {count}");
+        tx.commit().unwrap();
+
+        let tx = db.begin_write();
+        let estimate = tx.len_estimate().unwrap();
+        assert!(estimate > count / 2 && estimate < count * 2, "estimate {estimate}, count {count}");
+        assert_eq!(db.key_count(), count);
+    }
+
+    fn assert_unique_entries(dump: &TreeDump, expected: &[(Vec<u8>, Vec<u8>)]) {
+        let entries = dump.leaves().into_iter().flatten().cloned().collect::<Vec<_>>();
+        assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0), "duplicate or unordered keys");
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn overwrites_keys_without_duplicates() {
+        let path = TempPath::new();
+        let options = test_utils::options().max_keys_per_node(Some(8));
+        let db = Database::create(&path, options).unwrap();
+        let mut expected = BTreeMap::new();
+
+        let mut tx = db.begin_write();
+        for i in 100..300 {
+            tx.put(&key(i), &value(0)).unwrap();
+            tx.put(&key(i), &value(1)).unwrap();
+            expected.insert(key(i), value(1));
+        }
+        tx.commit().unwrap();
+
+        // Overwrite keys at split boundaries across transactions
+        for round in 2..6 {
+            let mut tx = db.begin_write();
+            let TreeDump::Branch { ref children, .. } = tx.tree_debug().unwrap() else {
+                panic!("root must be a branch");
+            };
+            let boundaries = children.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+            for key in &boundaries {
+                tx.put(key, &value(round)).unwrap();
+                expected.insert(key.clone(), value(round));
+            }
+            for i in (100..300).filter(|i| i % round == 0) {
+                tx.put(&key(i), &value(round)).unwrap();
+                tx.put(&key(i), &value(round + 1)).unwrap();
+                expected.insert(key(i), value(round + 1));
+            }
+            tx.commit().unwrap();
+
+            let tx = db.begin_write();
+            assert_unique_entries(&tx.tree_debug().unwrap(), &expected.clone().into_iter().collect::<Vec<_>>());
+        }
+        assert_eq!(db.key_count(), 200);
+        assert!(db.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_single_entry_for_overwritten_key_through_merges() {
+        let path = TempPath::new();
+        let options = test_utils::options().max_keys_per_node(Some(8));
+        let db = Database::create(&path, options).unwrap();
+        let mut tx = db.begin_write();
+        for i in 100..300 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        // Overwrite every key while removing its neighbours so that nodes merge
+        let mut tx = db.begin_write();
+        for i in 100..300 {
+            if i % 10 == 0 {
+                tx.put(&key(i), &value(0)).unwrap();
+            } else {
+                tx.remove(&key(i)).unwrap();
+            }
+        }
+        for i in (100..300).step_by(10) {
+            tx.put(&key(i), &value(1)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_write();
+        let expected = (100..300).step_by(10).map(|i| (key(i), value(1))).collect::<Vec<_>>();
+        assert_unique_entries(&tx.tree_debug().unwrap(), &expected);
+        assert_eq!(db.key_count(), 20);
+        assert!(db.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn merges_values() {
+        let path = TempPath::new();
+        let options = test_utils::options().merge_operator(Arc::new(|_key, current, operand| {
+            match current {
+                Some(current) => [current, b",", operand].concat(),
+                None => operand.to_vec(),
+            }
+        }));
+        let db = Database::create(&path, options).unwrap();
+        let mut tx = db.begin_write();
+        tx.put_merge(b"list", b"a").unwrap();
+        tx.put_merge(b"list", b"b").unwrap();
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        tx.put_merge(b"list", b"c").unwrap();
+        assert_eq!(tx.get(b"list").unwrap(), Some(b"a,b,c".to_vec()));
+        tx.commit().unwrap();
+        assert_eq!(db.begin_read().get(b"list").unwrap(), Some(b"a,b,c".to_vec()));
+
+        let path = TempPath::new();
+        let db = open_database(&path);
+        assert!(db.begin_write().put_merge(b"list", b"a").is_err());
+    }
+
+    #[test]
+    fn reader_keeps_snapshot_across_commits() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut rng = Rng::new(3);
+        let mut tx = db.begin_write();
+        for i in 0..1000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let reader = db.begin_read();
+        let snapshot = collect(&mut reader.cursor().unwrap());
+        let mut reversed = Vec::new();
+        let mut cursor = reader.cursor_last().unwrap();
+        while cursor.is_valid() {
+            reversed.push((cursor.key().to_vec(), cursor.value().to_vec()));
+            cursor.prev_entry().unwrap();
+        }
+        reversed.reverse();
+        assert_eq!(reversed, snapshot);
+
+        for round in 0..20 {
+            let mut tx = db.begin_write();
+            for _ in 0..200 {
+                let i = rng.below(2000) as usize;
+                if rng.below(3) == 0 {
+                    tx.remove(&key(i)).unwrap();
+                } else {
+                    tx.put(&key(i), &value(round)).unwrap();
+                }
+            }
+            tx.commit().unwrap();
+
+            assert_eq!(collect(&mut reader.cursor().unwrap()), snapshot);
+            assert_eq!(reader.rank(b"z").unwrap(), snapshot.len() as u64);
+            for _ in 0..20 {
+                let i = rng.below(2000) as usize;
+                let expected = (i < 1000).then(|| value(i));
+                assert_eq!(reader.get(&key(i)).unwrap(), expected);
+            }
+        }
+        assert_ne!(collect(&mut db.begin_read().cursor().unwrap()), snapshot);
+    }
+
+    #[test]
+    fn upserts_counter() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for _ in 0..100 {
+            tx.upsert_with(b"counter", |current| {
+                let count = current.map_or(0, |value| {
+                    u64::from_be_bytes(value.try_into().expect("counter value"))
+                });
+                Some((count + 1).to_be_bytes().to_vec())
+            })
+            .unwrap();
+        }
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        assert_eq!(tx.get(b"counter").unwrap(), Some(100u64.to_be_bytes().to_vec()));
+    }
+
+    #[test]
+    fn keeps_empty_values_distinct_from_missing_keys() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        tx.put(b"empty", b"").unwrap();
+        assert_eq!(tx.get(b"empty").unwrap(), Some(Vec::new()));
+        assert_eq!(tx.get(b"missing").unwrap(), None);
+        // Splits leaves of empty values
+        for i in 1000..3000 {
+            let value = if i % 2 == 0 { Vec::new() } else { value(i) };
+            tx.put(&key(i), &value).unwrap();
+        }
+        tx.commit().unwrap();
+
+        // Merges them back
+        let mut tx = db.begin_write();
+        for i in (1000..3000).filter(|i| i % 10 != 0 && i % 10 != 5) {
+            tx.remove(&key(i)).unwrap();
+        }
+        let mut seen = None;
+        tx.upsert_with(&key(1000), |current| {
+            seen = Some(current.map(|value| value.to_vec()));
+            current.map(|value| value.to_vec())
+        })
+        .unwrap();
+        assert_eq!(seen, Some(Some(Vec::new())));
+        assert_eq!(tx.entry(&key(1010)).unwrap().or_insert(b"inserted").unwrap(), b"");
+        tx.commit().unwrap();
+        drop(db);
+
+        let db = open_database(&path);
+        assert!(db.verify().unwrap().is_empty());
+        let tx = db.begin_read();
+        assert_eq!(tx.get(b"empty").unwrap(), Some(Vec::new()));
+        assert_eq!(tx.get(&key(1010)).unwrap(), Some(Vec::new()));
+        assert_eq!(tx.get(&key(1015)).unwrap(), Some(value(1015)));
+        assert_eq!(tx.get(&key(1011)).unwrap(), None);
+        let entries = collect(&mut tx.cursor().unwrap());
+        let mut expected = vec![(b"empty".to_vec(), Vec::new())];
+        expected.extend(
+            (1000..3000)
+                .filter(|i| i % 10 == 0 || i % 10 == 5)
+                .map(|i| (key(i), if i % 2 == 0 { Vec::new() } else { value(i) })),
+        );
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn inserts_entry_only_if_absent() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 100..300 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        let entry = tx.entry(&key(50)).unwrap();
+        assert_eq!(entry.key(), key(50));
+        assert_eq!(entry.get(), None);
+        assert_eq!(entry.or_insert(&value(0)).unwrap(), value(0));
+        let entry = tx.entry(&key(150)).unwrap();
+        assert_eq!(entry.get(), Some(value(150).as_slice()));
+        assert_eq!(entry.or_insert(&value(0)).unwrap(), value(150));
+        assert_eq!(tx.get(&key(50)).unwrap(), Some(value(0)));
+        assert_eq!(tx.get(&key(150)).unwrap(), Some(value(150)));
+
+        // Expired entry is treated as absent
+        tx.put_with_ttl(b"expired", b"old", Duration::ZERO).unwrap();
+        assert_eq!(tx.entry(b"expired").unwrap().or_insert(b"new").unwrap(), b"new");
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        assert_eq!(tx.get(&key(50)).unwrap(), Some(value(0)));
+        assert_eq!(tx.get(b"expired").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.key_count(), 202);
+    }
+
+    #[test]
+    fn modifies_or_inserts_entry() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        let increment = |value: &mut Vec<u8>| {
+            let count = u64::from_be_bytes(value.as_slice().try_into().expect("counter value"));
+            *value = (count + 1).to_be_bytes().to_vec();
+        };
+        for i in 0..1000 {
+            tx.entry(&key(i % 100))
+                .unwrap()
+                .and_modify(increment)
+                .unwrap()
+                .or_insert(&1u64.to_be_bytes())
+                .unwrap();
+        }
+        // Modifications after the first one look key up again
+        let value = tx
+            .entry(&key(0))
+            .unwrap()
+            .and_modify(increment)
+            .unwrap()
+            .and_modify(increment)
+            .unwrap()
+            .or_insert(&[])
+            .unwrap();
+        assert_eq!(value, 12u64.to_be_bytes());
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        for i in 1..100 {
+            assert_eq!(tx.get(&key(i)).unwrap(), Some(10u64.to_be_bytes().to_vec()));
+        }
+        assert!(db.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn removes_entry() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        tx.put(b"a", b"1").unwrap();
+        assert_eq!(tx.entry(b"a").unwrap().remove().unwrap(), Some(b"1".to_vec()));
+        assert_eq!(tx.entry(b"a").unwrap().remove().unwrap(), None);
+        assert_eq!(tx.get(b"a").unwrap(), None);
+
+        // Removes entry modified through the same entry
+        tx.put(b"b", b"2").unwrap();
+        let entry = tx.entry(b"b").unwrap().and_modify(|value| value.push(b'3')).unwrap();
+        assert_eq!(entry.get(), Some(b"23".as_slice()));
+        assert_eq!(entry.remove().unwrap(), Some(b"23".to_vec()));
+        assert_eq!(tx.get(b"b").unwrap(), None);
+        tx.commit().unwrap();
+        assert_eq!(db.key_count(), 0);
+    }
+
+    #[test]
+    fn upsert_removes_key_when_closure_returns_none() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        tx.put(&key(0), &value(0)).unwrap();
+        tx.upsert_with(&key(0), |current| {
+            assert_eq!(current, Some(value(0).as_slice()));
+            None
+        })
+        .unwrap();
+        tx.upsert_with(&key(1), |current| {
+            assert_eq!(current, None);
+            None
+        })
+        .unwrap();
+        assert_eq!(tx.get(&key(0)).unwrap(), None);
+        assert_eq!(tx.get(&key(1)).unwrap(), None);
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        assert!(collect(&mut tx.cursor().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn rollback_resets_transaction() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        tx.put(&key(0), &value(0)).unwrap();
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        for i in 1..200 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.remove(&key(0)).unwrap();
+        tx.rollback().unwrap();
+        assert_eq!(collect(&mut tx.cursor().unwrap()), vec![(key(0), value(0))]);
+
+        tx.put(&key(1), &value(1)).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        assert_eq!(
+            collect(&mut tx.cursor().unwrap()),
+            vec![(key(0), value(0)), (key(1), value(1))],
+        );
+    }
+
+    #[test]
+    fn rolls_back_to_savepoint() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        tx.put(b"A", b"a").unwrap();
+        let savepoint = tx.savepoint();
+        tx.put(b"B", b"b").unwrap();
+        tx.put(b"C", b"c").unwrap();
+        tx.put(b"A", b"changed").unwrap();
+        tx.rollback_to(savepoint).unwrap();
+        tx.commit().unwrap();
+
+        let tx = db.begin_read();
+        assert_eq!(
+            collect(&mut tx.cursor().unwrap()),
+            vec![(b"A".to_vec(), b"a".to_vec())],
+        );
+    }
+
+    #[test]
+    fn rolls_back_nested_savepoints() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..100 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let mut tx = db.begin_write();
+        let first = tx.savepoint();
+        for i in 0..50 {
+            tx.remove(&key(i)).unwrap();
+        }
+        let second = tx.savepoint();
+        for i in 100..300 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.rebalance().unwrap();
+        tx.rollback_to(second).unwrap();
+        let mut expected = (50..100).map(|i| (key(i), value(i))).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(collect(&mut tx.cursor().unwrap()), expected);
+
+        tx.rollback_to(first).unwrap();
+        assert!(tx.rollback_to(second).is_err());
+        tx.put(&key(500), &value(500)).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(db.verify().unwrap(), Vec::new());
+
+        let tx = db.begin_read();
+        for i in 0..100 {
+            assert_eq!(tx.get(&key(i)).unwrap(), Some(value(i)));
+        }
+        assert_eq!(tx.get(&key(500)).unwrap(), Some(value(500)));
+        assert_eq!(tx.get(&key(150)).unwrap(), None);
+    }
+
+    #[test]
+    fn copies_only_nodes_changed_after_savepoint() {
+        let path = TempPath::new();
+        let db = open_database(&path);
+        let mut tx = db.begin_write();
+        for i in 0..2000 {
+            tx.put(&key(i), &value(i)).unwrap();
+        }
+        tx.rebalance().unwrap();
+        let dirty_nodes = tx.nodes.len();
+        let savepoint = tx.savepoint();
+        tx.put(&key(0), &value(1)).unwrap();
+        // Only the path to changed leaf is copied
+        let path_len = tx.depth().unwrap() + 1;
+        assert!(tx.nodes.saved[0].1.len() <= path_len, "{} of {dirty_nodes}", tx.nodes.saved[0].1.len());
+
+        tx.rollback_to(savepoint).unwrap();
+        assert_eq!(tx.nodes.len(), dirty_nodes);
+        assert!(tx.nodes.saved[0].1.is_empty());
+        assert_eq!(tx.get(&key(0)).unwrap(), Some(value(0)));
+        tx.commit().unwrap();
+        assert!(db.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rebalances_after_random_updates() {
+        for seed in 1..=20 {
+            let path = TempPath::new();
+            let db = open_database(&path);
+            let mut rng = Rng::new(seed);
+            let mut expected = BTreeMap::new();
+            for _ in 0..5 {
+                let mut tx = db.begin_write();
+                for _ in 0..300 {
+                    let i = rng.below(500) as usize;
+                    if rng.below(3) == 0 {
+                        tx.remove(&key(i)).unwrap();
+                        expected.remove(&key(i));
+                    } else {
+                        let value = vec![b'v'; rng.below(100) as usize];
+                        tx.put(&key(i), &value).unwrap();
+                        expected.insert(key(i), value);
+                    }
+                    if rng.below(50) == 0 {
+                        tx.rebalance().unwrap();
+                    }
+                }
+                tx.rebalance().unwrap();
+                tx.rebalance().unwrap();
+                assert_eq!(collect(&mut tx.cursor().unwrap()), Vec::from_iter(expected.clone()));
+                tx.commit().unwrap();
+                assert_eq!(db.verify().unwrap(), Vec::new(), "seed {seed}");
+            }
+            let tx = db.begin_read();
+            assert_eq!(collect(&mut tx.cursor().unwrap()), Vec::from_iter(expected));
         }
     }
 }
@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::{
+    free_list::FreeList,
+    node::{Address, BranchInternalNode, InternalNodes, MetaNode, Node, NodeHeader, NodeId, NodeReader},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub page_address: Address,
+    pub description: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "page {}: {}", self.page_address, self.description)
+    }
+}
+
+/// Checks tree rooted at `meta_node.root_node`, which must be written,
+/// so it can be verified before meta node is published.
+pub(crate) fn verify<R: NodeReader>(
+    tx: &R,
+    meta_node: &MetaNode,
+    free_list: &FreeList,
+) -> Result<Vec<Violation>> {
+    let mut verifier = Verifier {
+        tx,
+        page_size: meta_node.page_size as u64,
+        end_address: meta_node.end_address,
+        pages: HashSet::new(),
+        leaf_depth: None,
+        violations: Vec::new(),
+    };
+    let entries = verifier.verify_node(NodeId::Address(meta_node.root_node), None, None, 0);
+    if let Some(entries) = entries.filter(|entries| *entries != meta_node.entries) {
+        verifier.report(
+            meta_node.root_node,
+            format!("tree has {entries} entries, meta node records {}", meta_node.entries),
+        );
+    }
+    let mut pages = verifier.pages.iter().copied().collect::<Vec<_>>();
+    pages.sort_unstable();
+    for address in free_list.overlaps_any(&pages) {
+        verifier.report(address, "page is referenced by the tree and is in the free list".to_string());
+    }
+    Ok(verifier.violations)
+}
+
+struct Verifier<'a, R> {
+    tx: &'a R,
+    page_size: u64,
+    end_address: Address,
+    pages: HashSet<Address>,
+    leaf_depth: Option<usize>,
+    violations: Vec<Violation>,
+}
+
+impl<R: NodeReader> Verifier<'_, R> {
+    /// Returns number of entries in node subtree or `None` if node is unreadable.
+    fn verify_node(
+        &mut self,
+        node_id: NodeId,
+        lower_bound: Option<&[u8]>,
+        upper_bound: Option<&[u8]>,
+        depth: usize,
+    ) -> Option<u64> {
+        let page_address = node_id.node_address();
+        let node = match self.tx.read_node(node_id) {
+            Ok(Node::ReadOnly(node)) => node,
+            Ok(Node::Dirty(_)) => unreachable!("verified tree must not have dirty nodes"),
+            Err(e) => {
+                self.report(page_address, format!("unreadable node: {e}"));
+                return None;
+            }
+        };
+
+        let (header, node) = node.as_ref();
+        self.verify_pages(page_address, header);
+        self.verify_keys(page_address, node, lower_bound, upper_bound);
+
+        let is_root = depth == 0;
+        if node.is_empty() && !is_root {
+            self.report(page_address, "non-root node is empty".to_string());
+        }
+
+        match node {
+            InternalNodes::Branch(nodes) => {
+                let mut total = Some(0);
+                if nodes.is_empty() {
+                    self.report(page_address, "branch node has no children".to_string());
+                }
+                for (index, child) in nodes.iter().enumerate() {
+                    let child_upper_bound = nodes
+                        .get(index + 1)
+                        .map(|next| next.key.as_slice())
+                        .or(upper_bound);
+                    let count = self.verify_node(
+                        child.node_id,
+                        Some(child.key.as_slice()),
+                        child_upper_bound,
+                        depth + 1,
+                    );
+                    if let Some(count) = count.filter(|count| *count != child.count) {
+                        self.report(
+                            page_address,
+                            format!("child at index {index} has {count} entries, expected {}", child.count),
+                        );
+                    }
+                    if count.is_some() {
+                        self.verify_separator(page_address, index, child);
+                    }
+                    total = total.zip(count).map(|(total, count)| total + count);
+                }
+                total
+            }
+            InternalNodes::Leaf(nodes) => {
+                match self.leaf_depth {
+                    None => self.leaf_depth = Some(depth),
+                    Some(leaf_depth) if leaf_depth != depth => {
+                        self.report(
+                            page_address,
+                            format!("leaf at depth {depth}, expected depth {leaf_depth}"),
+                        );
+                    }
+                    Some(_) => {}
+                }
+                Some(nodes.len() as u64)
+            }
+        }
+    }
+
+    /// Separator must equal first key of child, otherwise seek to keys between
+    /// them descends to the previous child.
+    fn verify_separator(&mut self, page_address: Address, index: usize, child: &BranchInternalNode) {
+        let Ok(node) = self.tx.read_node(child.node_id) else {
+            return;
+        };
+        let node = node.as_ref();
+        if node.is_empty() {
+            return;
+        }
+        let first_key = node.key_at(0);
+        if first_key != child.key.as_slice() {
+            self.report(
+                page_address,
+                format!(
+                    "separator {:?} at index {index} differs from first key {:?} of child",
+                    String::from_utf8_lossy(&child.key),
+                    String::from_utf8_lossy(first_key),
+                ),
+            );
+        }
+    }
+
+    fn verify_pages(&mut self, page_address: Address, header: &NodeHeader) {
+        if !page_address.is_multiple_of(self.page_size) {
+            self.report(page_address, "page address is not aligned".to_string());
+        }
+        let pages = header.overflow_len + 1;
+        let node_end_address = page_address + pages * self.page_size;
+        if node_end_address > self.end_address {
+            self.report(
+                page_address,
+                format!(
+                    "node ends at {node_end_address} past end address {}",
+                    self.end_address,
+                ),
+            );
+        }
+        for i in 0..pages {
+            let address = page_address + i * self.page_size;
+            if !self.pages.insert(address) {
+                self.report(page_address, format!("page {address} is referenced more than once"));
+            }
+        }
+    }
+
+    fn verify_keys(
+        &mut self,
+        page_address: Address,
+        node: &InternalNodes,
+        lower_bound: Option<&[u8]>,
+        upper_bound: Option<&[u8]>,
+    ) {
+        for index in 0..node.len() {
+            let key = node.key_at(index);
+            if index > 0 && node.key_at(index - 1) >= key {
+                self.report(
+                    page_address,
+                    format!("key {:?} at index {index} is out of order", String::from_utf8_lossy(key)),
+                );
+            }
+            if lower_bound.is_some_and(|lower_bound| key < lower_bound)
+                || upper_bound.is_some_and(|upper_bound| key >= upper_bound)
+            {
+                self.report(
+                    page_address,
+                    format!("key {:?} at index {index} is out of parent bounds", String::from_utf8_lossy(key)),
+                );
+            }
+        }
+    }
+
+    fn report(&mut self, page_address: Address, description: String) {
+        self.violations.push(Violation {
+            page_address,
+            description,
+        });
+    }
+}
+
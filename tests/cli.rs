@@ -0,0 +1,108 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+use bonsai_db::{Database, Options};
+
+struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> Self {
+        let file_name = format!("bonsai-db-cli-{}-{name}.db", std::process::id());
+        let path = std::env::temp_dir().join(file_name);
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn build_database(path: &TempPath) {
+    let db = Database::create(
+        &path.0,
+        Options {
+            page_size: 1 << 10,
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    let mut tx = db.begin_write();
+    for i in 0..2000 {
+        tx.put(format!("key{i:04}").as_bytes(), format!("value{i}").as_bytes())
+            .unwrap();
+    }
+    tx.commit().unwrap();
+    let mut tx = db.begin_write();
+    for i in 0..1750 {
+        tx.remove(format!("key{i:04}").as_bytes()).unwrap();
+    }
+    tx.commit().unwrap();
+}
+
+fn run(command: &str, path: &TempPath) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bonsai-db"))
+        .arg(command)
+        .arg(&path.0)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn stats_reports_keys_and_page_size() {
+    let path = TempPath::new("stats");
+    build_database(&path);
+    let output = run("stats", &path);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("page_size: 1024"), "{stdout}");
+    assert!(stdout.contains("keys: 250"), "{stdout}");
+}
+
+#[test]
+fn verify_reports_clean_database() {
+    let path = TempPath::new("verify");
+    build_database(&path);
+    let output = run("verify", &path);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "ok\n");
+}
+
+#[test]
+fn dump_prints_all_entries() {
+    let path = TempPath::new("dump");
+    build_database(&path);
+    let output = run("dump", &path);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 250);
+    assert_eq!(lines[0], "\"key1750\" = \"value1750\"");
+    assert_eq!(lines[249], "\"key1999\" = \"value1999\"");
+}
+
+#[test]
+fn compact_shrinks_database() {
+    let path = TempPath::new("compact");
+    build_database(&path);
+    let size_before = std::fs::metadata(&path.0).unwrap().len();
+    let output = run("compact", &path);
+    assert!(output.status.success());
+    let size_after = std::fs::metadata(&path.0).unwrap().len();
+    assert!(size_after < size_before, "{size_after} >= {size_before}");
+
+    let output = run("dump", &path);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 250);
+    assert!(run("verify", &path).status.success());
+}
+
+#[test]
+fn fails_on_missing_database() {
+    let path = TempPath::new("missing");
+    let output = run("stats", &path);
+    assert!(!output.status.success());
+    assert!(!path.0.exists());
+}